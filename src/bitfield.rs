@@ -0,0 +1,92 @@
+/// Set of pieces, laid out as in the `bitfield` peer message: the high bit of the
+/// first byte is piece 0 and spare bits at the end are zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// empty bitfield for `len` pieces
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0u8; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// bitfield with all `len` pieces set
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for i in 0..len {
+            bitfield.set(i as u32);
+        }
+        bitfield
+    }
+
+    /// parse the payload of a `bitfield` message for a torrent with `len` pieces
+    pub fn from_bytes(bytes: &[u8], len: usize) -> anyhow::Result<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(anyhow::Error::msg(format!(
+                "bitfield of {} bytes for {} pieces",
+                bytes.len(),
+                len
+            )));
+        }
+        let bitfield = Self {
+            bits: bytes.to_vec(),
+            len,
+        };
+        if (len..bytes.len() * 8).any(|i| bitfield.bit(i)) {
+            return Err(anyhow::Error::msg("bitfield has spare bits set"));
+        }
+        Ok(bitfield)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// number of pieces the bitfield covers
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        (index as usize) < self.len && self.bit(index as usize)
+    }
+
+    pub fn set(&mut self, index: u32) {
+        let i = index as usize;
+        assert!(i < self.len, "piece index {} out of range", index);
+        self.bits[i / 8] |= 0x80 >> (i % 8);
+    }
+
+    pub fn unset(&mut self, index: u32) {
+        let i = index as usize;
+        assert!(i < self.len, "piece index {} out of range", index);
+        self.bits[i / 8] &= !(0x80 >> (i % 8));
+    }
+
+    /// number of pieces set
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// indices of the pieces set
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len as u32).filter(|i| self.bit(*i as usize))
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        self.bits[i / 8] & (0x80 >> (i % 8)) != 0
+    }
+}
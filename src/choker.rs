@@ -0,0 +1,275 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use crate::peer::PeerState;
+use crate::rng::Rng;
+
+#[derive(Debug, Clone)]
+pub struct ChokerConfig {
+    /// peers unchoked for their transfer rate
+    pub unchoke_slots: usize,
+    /// peers unchoked at random to discover better partners
+    pub optimistic_slots: usize,
+    /// how often the regular slots are recomputed
+    pub rechoke_interval: Duration,
+    /// how often the optimistic slots move to other peers
+    pub optimistic_interval: Duration,
+    /// a peer that sends us nothing for this long while we're interested is
+    /// snubbing us, only checked while downloading
+    pub snub_timeout: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            unchoke_slots: 4,
+            optimistic_slots: 1,
+            rechoke_interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+            snub_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Tit-for-tat choker with optimistic unchoking and anti-snubbing.
+///
+/// Peers are identified by `K`; the choker only keeps the optimistic unchoke
+/// between rounds and derives everything else from the `PeerState`s it is given.
+#[derive(Debug)]
+pub struct Choker<K> {
+    pub config: ChokerConfig,
+    optimistic: Vec<K>,
+    last_optimistic: Option<Instant>,
+    rng: Rng,
+}
+
+impl<K: Clone + Eq + Hash> Choker<K> {
+    pub fn new(config: ChokerConfig) -> Self {
+        Self::with_rng(config, Rng::new())
+    }
+
+    pub fn with_rng(config: ChokerConfig, rng: Rng) -> Self {
+        Self {
+            config,
+            optimistic: Vec::new(),
+            last_optimistic: None,
+            rng,
+        }
+    }
+
+    /// Pick the peers to unchoke.
+    ///
+    /// Regular slots go to the interested peers we download fastest from, or
+    /// upload fastest to when `seeding`. While downloading, peers snubbing us
+    /// only get an optimistic slot; a seed wants nothing from its peers, so
+    /// none of them can snub it.
+    pub fn rechoke<'a>(
+        &mut self,
+        now: Instant,
+        seeding: bool,
+        peers: impl IntoIterator<Item = (K, &'a PeerState)>,
+    ) -> HashSet<K> {
+        let interested: Vec<(K, &PeerState)> = peers
+            .into_iter()
+            .filter(|(_, state)| state.peer_interested)
+            .collect();
+
+        let mut ranked: Vec<(&K, f64)> = interested
+            .iter()
+            .filter(|(_, state)| seeding || !state.is_snubbed(now, self.config.snub_timeout))
+            .map(|(key, state)| {
+                let rate = if seeding {
+                    state.upload_rate.rate(now)
+                } else {
+                    state.download_rate.rate(now)
+                };
+                (key, rate)
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let mut unchoked: HashSet<K> = ranked
+            .into_iter()
+            .take(self.config.unchoke_slots)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let rotate = match self.last_optimistic {
+            Some(at) => now.saturating_duration_since(at) >= self.config.optimistic_interval,
+            None => true,
+        };
+        if rotate {
+            self.optimistic.clear();
+            self.last_optimistic = Some(now);
+        } else {
+            // keep the current optimistic peers while they are still around and eligible
+            self.optimistic
+                .retain(|key| !unchoked.contains(key) && interested.iter().any(|(k, _)| k == key));
+        }
+
+        // newly connected peers have nothing to reciprocate with yet, give them a better chance
+        let mut candidates: Vec<(&K, usize)> = interested
+            .iter()
+            .filter(|(key, _)| !unchoked.contains(key) && !self.optimistic.contains(key))
+            .map(|(key, state)| {
+                let new = now.saturating_duration_since(state.connected_at)
+                    < self.config.optimistic_interval;
+                (key, if new { 3 } else { 1 })
+            })
+            .collect();
+        while self.optimistic.len() < self.config.optimistic_slots && !candidates.is_empty() {
+            let total: usize = candidates.iter().map(|(_, weight)| weight).sum();
+            let mut pick = self.rng.below(total);
+            let index = candidates
+                .iter()
+                .position(|(_, weight)| {
+                    if pick < *weight {
+                        return true;
+                    }
+                    pick -= weight;
+                    false
+                })
+                .unwrap();
+            let (key, _) = candidates.swap_remove(index);
+            self.optimistic.push(key.clone());
+        }
+
+        unchoked.extend(self.optimistic.iter().cloned());
+        unchoked
+    }
+
+    /// peers currently holding an optimistic slot
+    pub fn optimistic(&self) -> &[K] {
+        &self.optimistic
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(unchoke_slots: usize, optimistic_slots: usize) -> ChokerConfig {
+        ChokerConfig {
+            unchoke_slots,
+            optimistic_slots,
+            ..ChokerConfig::default()
+        }
+    }
+
+    fn interested(connected_at: Instant) -> PeerState {
+        let mut state = PeerState::new(connected_at);
+        state.peer_interested = true;
+        state
+    }
+
+    #[test]
+    fn unchokes_fastest_interested_peers() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(5);
+        let mut peers = Vec::new();
+        for (key, bytes) in [(0, 100), (1, 400), (2, 300), (3, 200)] {
+            let mut state = interested(start);
+            state.record_download(now, bytes);
+            peers.push((key, state));
+        }
+        // fastest of all, but not interested
+        peers[0].1.peer_interested = false;
+        peers[0].1.record_download(now, 1000);
+
+        let mut choker = Choker::with_rng(config(2, 0), Rng::with_seed(1));
+        let unchoked = choker.rechoke(now, false, peers.iter().map(|(k, s)| (*k, s)));
+        assert_eq!(unchoked, HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn ranks_by_upload_rate_when_seeding() {
+        let start = Instant::now();
+        let mut slow = interested(start);
+        slow.record_download(start, 1000);
+        let mut fast = interested(start);
+        fast.record_upload(start, 1000);
+
+        let mut choker = Choker::with_rng(config(1, 0), Rng::with_seed(1));
+        let unchoked = choker.rechoke(start, true, [(0, &slow), (1, &fast)]);
+        assert_eq!(unchoked, HashSet::from([1]));
+    }
+
+    #[test]
+    fn snubbed_peers_get_no_regular_slot() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(120);
+        let mut snubbing = interested(start);
+        snubbing.am_interested = true;
+        let mut quiet = interested(start);
+        quiet.am_interested = true;
+        quiet.record_download(now - Duration::from_secs(30), 10);
+        assert!(snubbing.is_snubbed(now, Duration::from_secs(60)));
+        assert!(!quiet.is_snubbed(now, Duration::from_secs(60)));
+
+        let mut choker = Choker::with_rng(config(4, 0), Rng::with_seed(1));
+        let unchoked = choker.rechoke(now, false, [(0, &snubbing), (1, &quiet)]);
+        assert_eq!(unchoked, HashSet::from([1]));
+
+        // but it may still be unchoked optimistically
+        let mut choker = Choker::with_rng(config(4, 1), Rng::with_seed(1));
+        let unchoked = choker.rechoke(now, false, [(0, &snubbing), (1, &quiet)]);
+        assert_eq!(unchoked, HashSet::from([0, 1]));
+        assert_eq!(choker.optimistic(), &[0]);
+    }
+
+    #[test]
+    fn seeding_ignores_snubbing() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(120);
+        // a seed is never interested, but the choker doesn't rely on that
+        let mut peer = interested(start);
+        peer.am_interested = true;
+        peer.record_upload(now, 10);
+        assert!(peer.is_snubbed(now, Duration::from_secs(60)));
+
+        let mut choker = Choker::with_rng(config(1, 0), Rng::with_seed(1));
+        let unchoked = choker.rechoke(now, true, [(0, &peer)]);
+        assert_eq!(unchoked, HashSet::from([0]));
+        let unchoked = choker.rechoke(now, false, [(0, &peer)]);
+        assert!(unchoked.is_empty());
+    }
+
+    #[test]
+    fn optimistic_slot_rotates_every_interval() {
+        let start = Instant::now();
+        let peers: Vec<(usize, PeerState)> = (0..4).map(|key| (key, interested(start))).collect();
+        let mut choker = Choker::with_rng(config(0, 1), Rng::with_seed(7));
+        let interval = choker.config.optimistic_interval;
+
+        let mut seen = HashSet::new();
+        for round in 0..40 {
+            let now = start + interval * round;
+            let unchoked = choker.rechoke(now, false, peers.iter().map(|(k, s)| (*k, s)));
+            assert_eq!(unchoked.len(), 1);
+            let optimistic = choker.optimistic()[0];
+            seen.insert(optimistic);
+
+            // the slot stays with the same peer until the interval is over
+            let later = now + interval / 2;
+            let unchoked = choker.rechoke(later, false, peers.iter().map(|(k, s)| (*k, s)));
+            assert_eq!(unchoked, HashSet::from([optimistic]));
+        }
+        assert_eq!(seen.len(), peers.len());
+    }
+
+    #[test]
+    fn optimistic_slot_moves_when_its_peer_loses_interest() {
+        let start = Instant::now();
+        let mut peers: Vec<(usize, PeerState)> =
+            (0..2).map(|key| (key, interested(start))).collect();
+        let mut choker = Choker::with_rng(config(0, 1), Rng::with_seed(3));
+        choker.rechoke(start, false, peers.iter().map(|(k, s)| (*k, s)));
+        let first = choker.optimistic()[0];
+
+        peers[first].1.peer_interested = false;
+        let now = start + Duration::from_secs(1);
+        let unchoked = choker.rechoke(now, false, peers.iter().map(|(k, s)| (*k, s)));
+        assert_eq!(unchoked, HashSet::from([1 - first]));
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Error};
+use sha1::{Digest, Sha1};
+
+use crate::storage::Storage;
+use crate::torrent::*;
+
+const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+/// number of pieces aimed for when choosing the piece length automatically
+const TARGET_PIECES: u64 = 1500;
+
+/// Options for authoring a new torrent with [`create_torrent`]
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// tiers of tracker urls, the first url of the first tier becomes `announce`
+    pub trackers: Vec<Vec<String>>,
+    /// piece length in bytes, chosen from the content size if None
+    pub piece_length: Option<u32>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// set `creation date` to the current time
+    pub creation_date: bool,
+    pub private: bool,
+    pub source: Option<String>,
+    pub web_seeds: Vec<String>,
+    /// insert padding files (BEP 47) so every file starts on a piece boundary
+    pub pad: bool,
+    /// number of threads used for hashing pieces, available parallelism if None
+    pub threads: Option<usize>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            trackers: Vec::new(),
+            piece_length: None,
+            comment: None,
+            created_by: Some(format!(
+                "{} {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )),
+            creation_date: true,
+            private: false,
+            source: None,
+            web_seeds: Vec::new(),
+            pad: false,
+            threads: None,
+        }
+    }
+}
+
+/// Create a torrent for the file or directory at `path`.
+pub fn create_torrent(path: impl AsRef<Path>, opts: &CreateOptions) -> anyhow::Result<Torrent> {
+    let path = path.as_ref();
+    let name = path
+        .canonicalize()
+        .with_context(|| format!("resolve {}", path.display()))?
+        .file_name()
+        .context("path has no file name")?
+        .to_str()
+        .context("file name must be UTF-8 encoded")?
+        .to_string();
+
+    let metadata = fs::metadata(path).with_context(|| format!("stat {}", path.display()))?;
+    let (length, files) = if metadata.is_dir() {
+        let files = collect_files(path)?;
+        if files.is_empty() {
+            return Err(Error::msg("directory does not contain any files"));
+        }
+        (files.iter().map(|f| f.length).sum(), Some(files))
+    } else {
+        (metadata.len(), None)
+    };
+    if length == 0 {
+        return Err(Error::msg("cannot create a torrent of empty content"));
+    }
+
+    let piece_length = match opts.piece_length {
+        Some(len) if !len.is_power_of_two() || len < MIN_PIECE_LENGTH => {
+            return Err(Error::msg(format!(
+                "piece length must be a power of two of at least {} bytes",
+                MIN_PIECE_LENGTH
+            )))
+        }
+        Some(len) => len,
+        None => piece_length_for(length),
+    };
+    let (length, files) = match files {
+        Some(files) if opts.pad => {
+            let files = pad_files(files, piece_length);
+            (files.iter().map(|f| f.length).sum(), Some(files))
+        }
+        files => (length, files),
+    };
+
+    let mut info = Info {
+        length,
+        name,
+        piece_length,
+        pieces: Vec::new(),
+        files,
+        private: opts.private,
+        source: opts.source.clone(),
+        meta_version: None,
+        file_tree: Vec::new(),
+        raw: None,
+    };
+
+    let storage = Storage::new(path, &info);
+    let threads = opts
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    info.pieces = hash_pieces(&storage, threads)?;
+
+    let trackers: Vec<Vec<String>> = opts
+        .trackers
+        .iter()
+        .filter(|tier| !tier.is_empty())
+        .cloned()
+        .collect();
+    let announce = trackers
+        .first()
+        .map(|tier| tier[0].as_bytes().to_vec())
+        .unwrap_or_default();
+    // announce-list is only needed when there is more than the single announce url
+    let announce_list = if trackers.iter().map(|tier| tier.len()).sum::<usize>() > 1 {
+        trackers
+    } else {
+        Vec::new()
+    };
+
+    let creation_date = if opts.creation_date {
+        Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+    } else {
+        None
+    };
+
+    Ok(Torrent {
+        announce,
+        announce_list,
+        comment: opts.comment.clone(),
+        created_by: opts.created_by.clone(),
+        creation_date,
+        url_list: opts.web_seeds.clone(),
+        info,
+        piece_layers: BTreeMap::new(),
+    })
+}
+
+/// pick a power of two piece length giving roughly TARGET_PIECES pieces
+pub fn piece_length_for(length: u64) -> u32 {
+    let ideal = (length / TARGET_PIECES).max(1);
+    ideal
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH as u64, MAX_PIECE_LENGTH as u64) as u32
+}
+
+/// SHA-1 every piece of `storage`, spreading the pieces over `threads` threads
+fn hash_pieces(storage: &Storage, threads: usize) -> anyhow::Result<Vec<[u8; 20]>> {
+    let npieces = storage.length.div_ceil(storage.piece_length) as u32;
+    let threads = threads.clamp(1, npieces.max(1) as usize) as u32;
+
+    let results = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                s.spawn(move || -> anyhow::Result<Vec<(u32, [u8; 20])>> {
+                    let mut hashes = Vec::new();
+                    for index in (t..npieces).step_by(threads as usize) {
+                        let piece = storage.read_piece(index)?;
+                        let mut hasher = Sha1::new();
+                        hasher.update(&piece);
+                        hashes.push((index, hasher.finalize().into()));
+                    }
+                    Ok(hashes)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("hashing thread panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    let mut pieces = vec![[0u8; 20]; npieces as usize];
+    for result in results {
+        for (index, hash) in result? {
+            pieces[index as usize] = hash;
+        }
+    }
+    Ok(pieces)
+}
+
+/// recursively list the regular files under `root`, sorted by path
+fn collect_files(root: &Path) -> anyhow::Result<Vec<FileInfo>> {
+    let mut paths = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).with_context(|| format!("read {}", dir.display()))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                paths.push(entry.path());
+            }
+        }
+    }
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|p: PathBuf| {
+            let metadata = fs::metadata(&p)?;
+            let path = p
+                .strip_prefix(root)?
+                .components()
+                .map(|c| {
+                    c.as_os_str()
+                        .to_str()
+                        .map(str::to_string)
+                        .context("file path must be UTF-8 encoded")
+                })
+                .collect::<anyhow::Result<Vec<String>>>()?;
+            Ok(FileInfo {
+                length: metadata.len(),
+                path,
+                attr: is_executable(&metadata).then(|| "x".to_string()),
+                symlink_path: None,
+            })
+        })
+        .collect()
+}
+
+/// Follow every file but the last that doesn't end on a piece boundary with a
+/// padding file up to the next one, named `.pad/<length>` like other tools do.
+fn pad_files(files: Vec<FileInfo>, piece_length: u32) -> Vec<FileInfo> {
+    let count = files.len();
+    let mut padded = Vec::with_capacity(count * 2);
+    for (i, file) in files.into_iter().enumerate() {
+        let rem = file.length % piece_length as u64;
+        padded.push(file);
+        if rem > 0 && i + 1 < count {
+            let length = piece_length as u64 - rem;
+            padded.push(FileInfo {
+                length,
+                path: vec![".pad".to_string(), length.to_string()],
+                attr: Some("p".to_string()),
+                symlink_path: None,
+            });
+        }
+    }
+    padded
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::*;
+
+    fn write(root: &Path, path: &str, length: usize) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let content: Vec<u8> = (0..length).map(|i| (i * 31 % 253) as u8).collect();
+        fs::write(path, content).unwrap();
+    }
+
+    fn options() -> CreateOptions {
+        CreateOptions {
+            piece_length: Some(MIN_PIECE_LENGTH),
+            creation_date: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn picks_piece_lengths() {
+        assert_eq!(piece_length_for(0), MIN_PIECE_LENGTH);
+        assert_eq!(
+            piece_length_for(MIN_PIECE_LENGTH as u64 * TARGET_PIECES),
+            MIN_PIECE_LENGTH
+        );
+        assert_eq!(
+            piece_length_for(MIN_PIECE_LENGTH as u64 * TARGET_PIECES + TARGET_PIECES),
+            2 * MIN_PIECE_LENGTH
+        );
+        assert_eq!(piece_length_for(1 << 30), 1 << 20);
+        assert_eq!(
+            piece_length_for(MAX_PIECE_LENGTH as u64 * TARGET_PIECES),
+            MAX_PIECE_LENGTH
+        );
+        assert_eq!(piece_length_for(u64::MAX), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn pads_all_files_but_the_last() {
+        let file = |name: &str, length| FileInfo {
+            length,
+            path: vec![name.to_string()],
+            attr: None,
+            symlink_path: None,
+        };
+        let padded = pad_files(vec![file("a", 100), file("b", 16384), file("c", 5)], 16384);
+        let layout: Vec<(String, u64, bool)> = padded
+            .iter()
+            .map(|f| (f.path.join("/"), f.length, f.is_padding()))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                ("a".to_string(), 100, false),
+                (".pad/16284".to_string(), 16284, true),
+                ("b".to_string(), 16384, false),
+                ("c".to_string(), 5, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn creates_multi_file_torrents() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        write(&root, "b.txt", 20_000);
+        write(&root, "a/z.txt", 100);
+        write(&root, "a/b/c.txt", 3);
+
+        let torrent = create_torrent(&root, &options()).unwrap();
+        let info = &torrent.info;
+        assert_eq!(info.name, "content");
+        let files = info.files.as_ref().unwrap();
+        let paths: Vec<String> = files.iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(paths, ["a/b/c.txt", "a/z.txt", "b.txt"]);
+        assert_eq!(info.length, 20_103);
+        assert_eq!(info.pieces.len(), 2);
+        assert!(torrent.verify_piece(1, &Storage::new(&root, info).read_piece(1).unwrap()));
+
+        let padded = create_torrent(
+            &root,
+            &CreateOptions {
+                pad: true,
+                ..options()
+            },
+        )
+        .unwrap();
+        let files = padded.info.files.unwrap();
+        assert_eq!(files.len(), 5);
+        assert_eq!(padded.info.length, 2 * 16384 + 20_000);
+    }
+
+    #[test]
+    fn splits_announce_and_announce_list() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "file.bin", 1000);
+        let path = dir.path().join("file.bin");
+        let create = |trackers: &[&[&str]]| {
+            let trackers = trackers
+                .iter()
+                .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+                .collect();
+            create_torrent(
+                &path,
+                &CreateOptions {
+                    trackers,
+                    ..options()
+                },
+            )
+            .unwrap()
+        };
+
+        let torrent = create(&[]);
+        assert!(torrent.announce.is_empty());
+        assert!(torrent.announce_list.is_empty());
+
+        let torrent = create(&[&[], &["http://a/announce"]]);
+        assert_eq!(torrent.announce, b"http://a/announce");
+        assert!(torrent.announce_list.is_empty());
+
+        let torrent = create(&[&["http://a/announce", "http://b/announce"], &["udp://c:80"]]);
+        assert_eq!(torrent.announce, b"http://a/announce");
+        assert_eq!(
+            torrent.announce_list,
+            [
+                vec!["http://a/announce", "http://b/announce"],
+                vec!["udp://c:80"]
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_through_bencode() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "file.bin", 40_000);
+        let opts = CreateOptions {
+            trackers: vec![vec!["http://a/announce".to_string()]],
+            comment: Some("test".to_string()),
+            private: true,
+            source: Some("SRC".to_string()),
+            web_seeds: vec!["http://seed/".to_string()],
+            ..options()
+        };
+        let torrent = create_torrent(dir.path().join("file.bin"), &opts).unwrap();
+
+        let encoded = torrent.to_value().encode();
+        let value = Value::decode(&encoded).unwrap().0;
+        // canonical: decoding and encoding again gives the same bytes, keys sorted
+        assert_eq!(value.encode(), encoded);
+        let raw = raw_dict_value(&encoded, b"info").unwrap().unwrap();
+        assert_eq!(raw, torrent.info.encoded());
+
+        let mut parsed = Torrent::from_value(&value).unwrap();
+        parsed.info.raw = Some(raw.to_vec());
+        assert_eq!(parsed.info.info_hash(), torrent.info.info_hash());
+        assert!(parsed.info.private);
+        assert_eq!(parsed.info.source.as_deref(), Some("SRC"));
+        assert_eq!(parsed.url_list, ["http://seed/"]);
+        assert_eq!(parsed.info.pieces, torrent.info.pieces);
+    }
+
+    #[test]
+    fn hashes_the_same_on_any_number_of_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "file.bin", 10 * 16384 + 77);
+        let path = dir.path().join("file.bin");
+        let create = |threads| {
+            let opts = CreateOptions {
+                threads: Some(threads),
+                ..options()
+            };
+            create_torrent(&path, &opts).unwrap().info.pieces
+        };
+        let pieces = create(1);
+        assert_eq!(pieces.len(), 11);
+        for threads in [2, 3, 8, 64] {
+            assert_eq!(create(threads), pieces, "{} threads", threads);
+        }
+    }
+}
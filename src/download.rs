@@ -0,0 +1,1049 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error};
+use bytes::{BufMut, Bytes};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinSet;
+
+use crate::bitfield::Bitfield;
+use crate::extension::*;
+use crate::metadata::UtMetadata;
+use crate::peer::*;
+use crate::pex::*;
+use crate::picker::PiecePicker;
+use crate::pipeline::*;
+use crate::priority::FilePriority;
+use crate::storage::Storage;
+use crate::torrent::*;
+use crate::webseed::*;
+
+/// size of the blocks pieces are requested in
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+/// peers downloaded from at the same time
+pub const MAX_PEERS: usize = 8;
+/// piece suggestions remembered per peer, older ones are forgotten
+pub const MAX_SUGGESTED: usize = 16;
+
+/// identifies one peer connection of a download
+pub type WorkerId = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockState {
+    Missing,
+    /// requested from these workers, more than one only in endgame
+    Requested(Vec<WorkerId>),
+    Received(Bytes),
+}
+
+/// The blocks of a piece being downloaded
+#[derive(Debug)]
+pub struct PartialPiece {
+    pub index: u32,
+    pub length: u32,
+    blocks: Vec<BlockState>,
+}
+
+impl PartialPiece {
+    pub fn new(index: u32, length: u32) -> Self {
+        Self {
+            index,
+            length,
+            blocks: vec![BlockState::Missing; length.div_ceil(BLOCK_SIZE) as usize],
+        }
+    }
+
+    /// length in bytes of `block`, the last block may be shorter
+    pub fn block_len(&self, block: u32) -> u32 {
+        BLOCK_SIZE.min(self.length - block * BLOCK_SIZE)
+    }
+
+    /// blocks not received yet
+    pub fn missing(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.blocks.len() as u32)
+            .filter(|b| !matches!(self.blocks[*b as usize], BlockState::Received(_)))
+    }
+
+    /// some block is neither received nor requested from anyone
+    pub fn has_unrequested(&self) -> bool {
+        self.blocks.contains(&BlockState::Missing)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.blocks
+            .iter()
+            .all(|b| matches!(b, BlockState::Received(_)))
+    }
+
+    /// Mark up to `max` blocks as requested by `worker` and return them. In
+    /// `endgame` blocks already requested from other workers are handed out too.
+    pub fn claim(&mut self, worker: WorkerId, max: usize, endgame: bool) -> Vec<u32> {
+        let mut claimed = Vec::new();
+        for (block, state) in self.blocks.iter_mut().enumerate() {
+            if claimed.len() == max {
+                break;
+            }
+            match state {
+                BlockState::Missing => *state = BlockState::Requested(vec![worker]),
+                BlockState::Requested(workers) if endgame && !workers.contains(&worker) => {
+                    workers.push(worker)
+                }
+                _ => continue,
+            }
+            claimed.push(block as u32);
+        }
+        claimed
+    }
+
+    /// `worker` will not deliver `block` after all
+    pub fn release(&mut self, worker: WorkerId, block: u32) {
+        let state = &mut self.blocks[block as usize];
+        if let BlockState::Requested(workers) = state {
+            workers.retain(|w| *w != worker);
+            if workers.is_empty() {
+                *state = BlockState::Missing;
+            }
+        }
+    }
+
+    /// release every block requested from `worker`
+    pub fn release_all(&mut self, worker: WorkerId) {
+        for block in 0..self.blocks.len() as u32 {
+            self.release(worker, block);
+        }
+    }
+
+    /// Store a block `worker` received in a `piece` message. Returns the other
+    /// workers it was requested from, or None if we already had the block.
+    pub fn add_block(
+        &mut self,
+        worker: WorkerId,
+        begin: u32,
+        data: Bytes,
+    ) -> anyhow::Result<Option<Vec<WorkerId>>> {
+        let block = self.block_at(begin, data.len())?;
+        let state = &mut self.blocks[block as usize];
+        let others = match state {
+            BlockState::Received(_) => return Ok(None),
+            BlockState::Requested(workers) => {
+                workers.iter().copied().filter(|w| *w != worker).collect()
+            }
+            BlockState::Missing => Vec::new(),
+        };
+        *state = BlockState::Received(data);
+        Ok(Some(others))
+    }
+
+    /// the block starting at `begin`, checking that it is `len` bytes long
+    pub fn block_at(&self, begin: u32, len: usize) -> anyhow::Result<u32> {
+        let block = begin / BLOCK_SIZE;
+        if begin != block * BLOCK_SIZE
+            || block as usize >= self.blocks.len()
+            || len as u32 != self.block_len(block)
+        {
+            return Err(Error::msg(format!(
+                "unexpected block at {} of {} bytes for piece {}",
+                begin, len, self.index
+            )));
+        }
+        Ok(block)
+    }
+
+    /// the piece's bytes, only meaningful once complete
+    pub fn data(&self) -> Vec<u8> {
+        let mut piece = Vec::with_capacity(self.length as usize);
+        for block in &self.blocks {
+            if let BlockState::Received(data) = block {
+                piece.put_slice(data);
+            }
+        }
+        piece
+    }
+}
+
+/// Removes a worker from its download when dropped, so its blocks go back to
+/// the picker even if the worker's task panics or is aborted.
+struct WorkerGuard<'a> {
+    download: &'a Download,
+    worker: WorkerId,
+    /// the pieces the worker's peer has
+    peer_has: Bitfield,
+}
+
+impl Drop for WorkerGuard<'_> {
+    fn drop(&mut self) {
+        // a panic while holding the lock leaves nothing consistent to clean up
+        if !self.download.state.is_poisoned() {
+            self.download.remove_worker(self.worker, &self.peer_has);
+        }
+    }
+}
+
+/// a block requested from a peer
+struct Outstanding {
+    requested_at: Instant,
+    length: u32,
+}
+
+struct DownloadState {
+    picker: PiecePicker,
+    /// pieces started but not verified yet, shared by all peers
+    pieces: HashMap<u32, PartialPiece>,
+    /// tells a worker that a block it requested arrived from another peer
+    cancels: HashMap<WorkerId, mpsc::UnboundedSender<(u32, u32)>>,
+}
+
+/// Downloads a torrent from several peers at once into `storage`
+pub struct Download {
+    pub torrent: Torrent,
+    pub info_hash: [u8; 20],
+    /// the bencoded info dictionary, served to peers that joined by magnet link
+    pub metadata: Bytes,
+    pub storage: Storage,
+    peer_id: [u8; 20],
+    state: Mutex<DownloadState>,
+    /// signalled whenever a piece completes or blocks go back to the picker
+    progress: Notify,
+    next_worker: AtomicUsize,
+    /// peers to connect to and the ones we are connected to
+    pub swarm: Arc<PeerSwarm>,
+    pub pipeline_config: PipelineConfig,
+    pub connection_config: ConnectionConfig,
+}
+
+impl Download {
+    pub fn new(torrent: Torrent, storage: Storage, peer_id: [u8; 20]) -> Self {
+        let npieces = torrent.info.piece_count();
+        Self {
+            info_hash: torrent.info.info_hash(),
+            metadata: torrent.info.encoded().into(),
+            torrent,
+            storage,
+            peer_id,
+            state: Mutex::new(DownloadState {
+                picker: PiecePicker::new(npieces),
+                pieces: HashMap::new(),
+                cancels: HashMap::new(),
+            }),
+            progress: Notify::new(),
+            next_worker: AtomicUsize::new(0),
+            swarm: Arc::new(PeerSwarm::default()),
+            pipeline_config: PipelineConfig::default(),
+            connection_config: ConnectionConfig::default(),
+        }
+    }
+
+    /// every piece of the wanted files has been verified
+    pub fn is_complete(&self) -> bool {
+        self.state.lock().unwrap().picker.is_complete()
+    }
+
+    /// Download only the files whose priority isn't Skip, one priority per file
+    /// of the torrent, pieces of higher priority files first.
+    pub fn set_file_priorities(&mut self, priorities: &[FilePriority]) -> anyhow::Result<()> {
+        if !priorities.iter().any(|p| *p != FilePriority::Skip) {
+            return Err(Error::msg("every file of the torrent is skipped"));
+        }
+        self.storage.set_priorities(priorities)?;
+        let pieces = self.storage.piece_priorities(priorities);
+        self.state.get_mut().unwrap().picker.set_priorities(pieces);
+        Ok(())
+    }
+
+    /// Download from `peers`, the peers they tell us about and the torrent's
+    /// web seeds, keeping up to MAX_PEERS connections, until every piece is
+    /// verified.
+    pub async fn run(self: Arc<Self>, peers: Vec<SocketAddrV4>) -> anyhow::Result<()> {
+        self.storage.create_files()?;
+        self.swarm.add(peers.into_iter().map(SocketAddr::V4));
+
+        let mut workers = JoinSet::new();
+        for url in &self.torrent.url_list {
+            workers.spawn(self.clone().run_web_seed(url.clone()));
+        }
+        loop {
+            while workers.len() < MAX_PEERS {
+                let Some(addr) = self.swarm.next() else {
+                    break;
+                };
+                workers.spawn(self.clone().run_peer(addr));
+            }
+            if workers.is_empty() {
+                break;
+            }
+
+            tokio::select! {
+                Some(result) = workers.join_next() => {
+                    if self.is_complete() {
+                        workers.abort_all();
+                        return Ok(());
+                    }
+                    match result {
+                        Ok(Err(e)) => eprintln!("{:#}", e),
+                        // one misbehaving peer must not take the download down with it
+                        Err(e) if e.is_panic() => eprintln!("{}", e),
+                        _ => {}
+                    }
+                }
+                // peers learned through PEX
+                _ = self.swarm.added.notified() => {}
+            }
+        }
+
+        if self.is_complete() {
+            Ok(())
+        } else {
+            Err(Error::msg("ran out of peers before the download completed"))
+        }
+    }
+
+    async fn run_peer(self: Arc<Self>, addr: SocketAddr) -> anyhow::Result<()> {
+        let result = async {
+            let (handshake_msg, stream) = connect_peer(
+                addr,
+                &self.info_hash,
+                &self.peer_id,
+                &self.connection_config,
+            )
+            .await?;
+            let capabilities = handshake_msg
+                .capabilities()
+                .intersection(&self.connection_config.capabilities);
+            self.swarm.connected(addr, PEX_OUTGOING);
+            let result = self.download_over(stream, addr, capabilities).await;
+            self.swarm.disconnected(addr);
+            result
+        }
+        .await;
+        result.with_context(|| format!("peer {}", addr))
+    }
+
+    /// Download from the peer at the other end of `stream`, once handshakes
+    /// have been exchanged, until the torrent is complete or the connection
+    /// fails. `addr` is the peer's address and `capabilities` are the ones both
+    /// sides announced. Any transport works, not just TCP.
+    pub async fn download_over<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+        addr: SocketAddr,
+        capabilities: Capabilities,
+    ) -> anyhow::Result<()> {
+        let npieces = self.torrent.info.piece_count();
+        let worker = self.next_worker.fetch_add(1, Ordering::Relaxed);
+        let (cancels_tx, mut cancels) = mpsc::unbounded_channel();
+        self.state
+            .lock()
+            .unwrap()
+            .cancels
+            .insert(worker, cancels_tx);
+        let mut guard = WorkerGuard {
+            download: self,
+            worker,
+            peer_has: Bitfield::new(npieces),
+        };
+
+        let mut stream = BufReader::new(stream);
+        self.download_from(
+            worker,
+            addr,
+            capabilities,
+            &mut stream,
+            &mut guard.peer_has,
+            &mut cancels,
+        )
+        .await
+    }
+
+    /// the extensions spoken with the peer at `addr`
+    fn extensions(&self, addr: SocketAddr) -> Extensions {
+        let mut extensions = Extensions::new();
+        // peers that joined by magnet link may fetch the metadata from us
+        extensions.register(Box::new(UtMetadata::serving(self.metadata.clone())));
+        // private torrents (BEP 27) take their peers from the trackers only
+        if !self.torrent.info.private {
+            extensions.register(Box::new(UtPex::new(self.swarm.clone(), addr)));
+        }
+        extensions
+    }
+
+    /// forget `worker`, whose peer had `peer_has`
+    fn remove_worker(&self, worker: WorkerId, peer_has: &Bitfield) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.picker.remove_bitfield(peer_has);
+            state.cancels.remove(&worker);
+            // let the other peers fetch the blocks this one never delivered
+            let DownloadState { picker, pieces, .. } = &mut *state;
+            for piece in pieces.values_mut() {
+                piece.release_all(worker);
+                picker.set_requested(piece.index, !piece.has_unrequested());
+            }
+        }
+        self.progress.notify_waiters();
+    }
+
+    async fn run_web_seed(self: Arc<Self>, url: String) -> anyhow::Result<()> {
+        let result = async {
+            let seed = WebSeed::new(&url, &self.torrent.info)?;
+            let npieces = self.torrent.info.piece_count();
+            // requests can't be cancelled, blocks another peer delivers first
+            // are simply fetched twice
+            let worker = self.next_worker.fetch_add(1, Ordering::Relaxed);
+            // a web seed has every piece
+            let guard = WorkerGuard {
+                download: &self,
+                worker,
+                peer_has: Bitfield::full(npieces),
+            };
+            self.state
+                .lock()
+                .unwrap()
+                .picker
+                .add_bitfield(&guard.peer_has);
+
+            self.download_from_web_seed(worker, &seed, &guard.peer_has)
+                .await
+        }
+        .await;
+        result.with_context(|| format!("web seed {}", url))
+    }
+
+    /// Download a piece at a time from `seed`, picked like for any other peer.
+    /// Failed requests are retried after a growing backoff, the seed is given
+    /// up after MAX_WEB_SEED_FAILURES of them in a row.
+    async fn download_from_web_seed(
+        &self,
+        worker: WorkerId,
+        seed: &WebSeed,
+        seed_has: &Bitfield,
+    ) -> anyhow::Result<()> {
+        let info = &self.torrent.info;
+        let blocks_per_piece = info.piece_length.div_ceil(BLOCK_SIZE) as usize;
+        let mut active = Vec::new();
+        let mut failures = 0;
+        let mut backoff = WEB_SEED_BACKOFF;
+        loop {
+            if self.is_complete() {
+                return Ok(());
+            }
+
+            let claimed = self.claim_blocks(worker, seed_has, &[], &mut active, blocks_per_piece);
+            if claimed.is_empty() {
+                // everything left is requested from peers, wait for it to arrive or
+                // be given up
+                tokio::select! {
+                    _ = self.progress.notified() => {}
+                    _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+                }
+                continue;
+            }
+
+            // one request for each run of consecutive blocks
+            let mut runs: Vec<Vec<(u32, u32, u32)>> = Vec::new();
+            for block in claimed {
+                match runs.last_mut() {
+                    Some(run)
+                        if run
+                            .last()
+                            .is_some_and(|(index, b, _)| *index == block.0 && b + 1 == block.1) =>
+                    {
+                        run.push(block)
+                    }
+                    _ => runs.push(vec![block]),
+                }
+            }
+
+            for (i, run) in runs.iter().enumerate() {
+                let (index, first, _) = run[0];
+                let offset = index as u64 * info.piece_length as u64 + (first * BLOCK_SIZE) as u64;
+                let length: u64 = run.iter().map(|(_, _, len)| *len as u64).sum();
+                match seed.fetch(offset, length).await {
+                    Ok(data) => {
+                        failures = 0;
+                        backoff = WEB_SEED_BACKOFF;
+                        let mut begin = 0;
+                        for (index, block, len) in run {
+                            let block_data = data.slice(begin..begin + *len as usize);
+                            self.receive_block(worker, *index, block * BLOCK_SIZE, block_data)?;
+                            begin += *len as usize;
+                        }
+                    }
+                    Err(e) => {
+                        self.release_blocks(
+                            worker,
+                            runs[i..]
+                                .iter()
+                                .flatten()
+                                .map(|(index, block, _)| (*index, *block)),
+                        );
+                        failures += 1;
+                        if failures >= MAX_WEB_SEED_FAILURES {
+                            return Err(e);
+                        }
+                        eprintln!("web seed {}: {:#}, retrying in {:?}", seed.url, e, backoff);
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_WEB_SEED_BACKOFF);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn download_from<S: AsyncBufRead + AsyncWrite + Unpin>(
+        &self,
+        worker: WorkerId,
+        addr: SocketAddr,
+        capabilities: Capabilities,
+        stream: &mut S,
+        peer_has: &mut Bitfield,
+        cancels: &mut mpsc::UnboundedReceiver<(u32, u32)>,
+    ) -> anyhow::Result<()> {
+        let mut peer = PeerState::new(Instant::now());
+        peer.capabilities = capabilities;
+        let mut liveness = Liveness::new(Instant::now());
+        let config = &self.connection_config;
+        let mut pipeline = Pipeline::new(self.pipeline_config.clone());
+        // blocks requested from this peer, they may arrive in any order
+        let mut outstanding: HashMap<(u32, u32), Outstanding> = HashMap::new();
+        // pieces this peer is fetching blocks of, oldest first
+        let mut active: Vec<u32> = Vec::new();
+        // pieces the peer serves while choking us, and pieces it recommends
+        let mut allowed_fast: Vec<u32> = Vec::new();
+        let mut suggested: Vec<u32> = Vec::new();
+        let mut extensions = self.extensions(addr);
+        let mut next_tick = Instant::now() + EXTENSION_TICK;
+        // with the fast extension the first message must say what we have, and
+        // as we don't upload that's nothing
+        if peer.supports(Capability::Fast) {
+            PeerMessage::HaveNone.write(stream).await?;
+            liveness.sent(Instant::now());
+        }
+        if peer.supports(Capability::Extended) {
+            extensions.handshake_message().write(stream).await?;
+            liveness.sent(Instant::now());
+        }
+        loop {
+            if self.is_complete() {
+                return Ok(());
+            }
+
+            // only ask to be unchoked once the peer has something we want
+            if !peer.am_interested && self.wants_any(peer_has) {
+                peer.am_interested = true;
+                PeerMessage::Interested.write(stream).await?;
+                liveness.sent(Instant::now());
+            }
+
+            // a peer snubbing us gets one request at a time so it can't sit on many blocks
+            let depth = if peer.is_snubbed(Instant::now(), config.request_timeout) {
+                1
+            } else {
+                pipeline.depth()
+            };
+            let wanted = depth.saturating_sub(outstanding.len());
+            let may_request = !peer.peer_choking || !allowed_fast.is_empty();
+            if peer.am_interested && may_request && wanted > 0 {
+                // while choked only the allowed-fast pieces may be requested
+                let mut fast_has;
+                let requestable = if peer.peer_choking {
+                    fast_has = Bitfield::new(peer_has.len());
+                    for index in allowed_fast.iter().filter(|index| peer_has.has(**index)) {
+                        fast_has.set(*index);
+                    }
+                    &fast_has
+                } else {
+                    &*peer_has
+                };
+                let now = Instant::now();
+                for (index, block, length) in
+                    self.claim_blocks(worker, requestable, &suggested, &mut active, wanted)
+                {
+                    PeerMessage::Request {
+                        index,
+                        begin: block * BLOCK_SIZE,
+                        length,
+                    }
+                    .write(stream)
+                    .await?;
+                    liveness.sent(now);
+                    outstanding.insert(
+                        (index, block),
+                        Outstanding {
+                            requested_at: now,
+                            length,
+                        },
+                    );
+                }
+                if !peer.peer_choking && outstanding.is_empty() && !self.wants_any(peer_has) {
+                    peer.am_interested = false;
+                    PeerMessage::NotInterested.write(stream).await?;
+                    liveness.sent(Instant::now());
+                }
+            }
+            let idle = outstanding.is_empty();
+            let oldest_request = outstanding.values().map(|r| r.requested_at).min();
+
+            tokio::select! {
+                biased;
+                Some((index, block)) = cancels.recv() => {
+                    // another peer delivered a block we also asked this one for
+                    if let Some(request) = outstanding.remove(&(index, block)) {
+                        PeerMessage::Cancel {
+                            index,
+                            begin: block * BLOCK_SIZE,
+                            length: request.length,
+                        }
+                        .write(stream)
+                        .await?;
+                        liveness.sent(Instant::now());
+                    }
+                    continue;
+                }
+                // wait for a message without reading part of it if another branch wins
+                _ = stream.fill_buf() => {}
+                _ = tokio::time::sleep_until(liveness.deadline(config).into()) => {
+                    let now = Instant::now();
+                    if liveness.check(now, config)? {
+                        PeerMessage::KeepAlive.write(stream).await?;
+                        liveness.sent(now);
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep_until(
+                    (oldest_request.unwrap_or(next_tick) + config.request_timeout).into()
+                ), if oldest_request.is_some() => {
+                    // the peer sits on these requests, let whoever gets them first have them
+                    let now = Instant::now();
+                    let expired: Vec<(u32, u32)> = outstanding
+                        .iter()
+                        .filter(|(_, r)| now.saturating_duration_since(r.requested_at) >= config.request_timeout)
+                        .map(|(block, _)| *block)
+                        .collect();
+                    for (index, block) in &expired {
+                        let request = outstanding.remove(&(*index, *block)).unwrap();
+                        PeerMessage::Cancel {
+                            index: *index,
+                            begin: block * BLOCK_SIZE,
+                            length: request.length,
+                        }
+                        .write(stream)
+                        .await?;
+                        liveness.sent(now);
+                    }
+                    self.release_blocks(worker, expired.into_iter());
+                    pipeline.pause();
+                    continue;
+                }
+                _ = tokio::time::sleep_until(next_tick.into()) => {
+                    let now = Instant::now();
+                    for msg in extensions.poll(now) {
+                        msg.write(stream).await?;
+                        liveness.sent(now);
+                    }
+                    next_tick = now + EXTENSION_TICK;
+                    continue;
+                }
+                // every block this peer could give us is requested elsewhere, wait
+                // for pieces to complete or be given up
+                _ = self.progress.notified(), if idle => continue,
+                _ = tokio::time::sleep(Duration::from_secs(1)), if idle => continue,
+            }
+
+            let msg = PeerMessage::read_limited(stream, config.max_frame_len)
+                .await
+                .context("read message")?;
+            liveness.received(Instant::now());
+            if msg.is_fast() && !peer.supports(Capability::Fast) {
+                return Err(Error::msg("fast extension message without negotiating it"));
+            }
+            match msg {
+                PeerMessage::KeepAlive | PeerMessage::Port(_) => {}
+                PeerMessage::Choke => {
+                    // the peer drops our pending requests, hand them to whoever gets them
+                    // first. With the fast extension requests for allowed-fast pieces
+                    // stay queued and the rest are rejected explicitly, but we don't
+                    // wait for the rejects.
+                    peer.peer_choking = true;
+                    let dropped: Vec<(u32, u32)> = outstanding
+                        .keys()
+                        .copied()
+                        .filter(|(index, _)| {
+                            !peer.supports(Capability::Fast) || !allowed_fast.contains(index)
+                        })
+                        .collect();
+                    for block in &dropped {
+                        outstanding.remove(block);
+                    }
+                    self.release_blocks(worker, dropped.into_iter());
+                    pipeline.pause();
+                }
+                PeerMessage::Unchoke => peer.peer_choking = false,
+                PeerMessage::Interested => peer.peer_interested = true,
+                PeerMessage::NotInterested => peer.peer_interested = false,
+                PeerMessage::Have { index } => {
+                    self.peer_have(peer_has, index);
+                    if peer_has.is_full() {
+                        self.swarm.set_flags(addr, PEX_SEED);
+                    }
+                }
+                PeerMessage::Bitfield(_) | PeerMessage::HaveAll | PeerMessage::HaveNone => {
+                    let bitfield = match msg {
+                        PeerMessage::Bitfield(bits) => Bitfield::from_bytes(&bits, peer_has.len())?,
+                        PeerMessage::HaveAll => Bitfield::full(peer_has.len()),
+                        _ => Bitfield::new(peer_has.len()),
+                    };
+                    let mut state = self.state.lock().unwrap();
+                    state.picker.remove_bitfield(peer_has);
+                    state.picker.add_bitfield(&bitfield);
+                    *peer_has = bitfield;
+                    if peer_has.is_full() {
+                        self.swarm.set_flags(addr, PEX_SEED);
+                    }
+                }
+                // we keep the peer choked, downloads don't upload
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    if peer.supports(Capability::Fast) {
+                        PeerMessage::RejectRequest {
+                            index,
+                            begin,
+                            length,
+                        }
+                        .write(stream)
+                        .await?;
+                        liveness.sent(Instant::now());
+                    }
+                }
+                PeerMessage::Cancel { .. } => {}
+                PeerMessage::RejectRequest { index, begin, .. } => {
+                    // hand the block to whoever gets it first instead of waiting for it
+                    if outstanding.remove(&(index, begin / BLOCK_SIZE)).is_some() {
+                        self.release_blocks(worker, std::iter::once((index, begin / BLOCK_SIZE)));
+                    }
+                    // don't ask again until unchoked if the peer withdrew the piece
+                    if peer.peer_choking {
+                        allowed_fast.retain(|i| *i != index);
+                    }
+                }
+                PeerMessage::AllowedFast { index } => {
+                    if (index as usize) < peer_has.len() && !allowed_fast.contains(&index) {
+                        allowed_fast.push(index);
+                    }
+                }
+                PeerMessage::SuggestPiece { index } => {
+                    if (index as usize) < peer_has.len() && !suggested.contains(&index) {
+                        if suggested.len() == MAX_SUGGESTED {
+                            suggested.remove(0);
+                        }
+                        suggested.push(index);
+                    }
+                }
+                PeerMessage::Piece { index, begin, data } => {
+                    // unrequested blocks and blocks we cancelled are dropped
+                    if let Some(request) = outstanding.remove(&(index, begin / BLOCK_SIZE)) {
+                        let now = Instant::now();
+                        pipeline.on_block(request.requested_at, now, data.len() as u64);
+                        peer.record_download(now, data.len() as u64);
+                        self.receive_block(worker, index, begin, data)?;
+                    }
+                }
+                PeerMessage::Extended { id, payload } => {
+                    for reply in extensions.dispatch(id, &payload)? {
+                        reply.write(stream).await?;
+                        liveness.sent(Instant::now());
+                    }
+                    if id == 0 {
+                        if let Some(reqq) = extensions.peer_handshake().and_then(|h| h.reqq) {
+                            pipeline.set_peer_limit(reqq);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// give blocks `worker` requested back to the picker
+    fn release_blocks(&self, worker: WorkerId, blocks: impl Iterator<Item = (u32, u32)>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let DownloadState { picker, pieces, .. } = &mut *state;
+            for (index, block) in blocks {
+                if let Some(piece) = pieces.get_mut(&index) {
+                    piece.release(worker, block);
+                    picker.set_requested(index, !piece.has_unrequested());
+                }
+            }
+        }
+        self.progress.notify_waiters();
+    }
+
+    /// Claim up to `max` blocks for `worker` to request, finishing the pieces in
+    /// `active` before starting new ones the peer has, `suggested` ones first.
+    /// Active pieces not in `peer_has` are left alone.
+    fn claim_blocks(
+        &self,
+        worker: WorkerId,
+        peer_has: &Bitfield,
+        suggested: &[u32],
+        active: &mut Vec<u32>,
+        max: usize,
+    ) -> Vec<(u32, u32, u32)> {
+        let mut state = self.state.lock().unwrap();
+        let endgame = state.picker.is_endgame();
+        let DownloadState { picker, pieces, .. } = &mut *state;
+        active.retain(|index| pieces.contains_key(index));
+
+        let mut claimed = Vec::new();
+        let mut i = 0;
+        while claimed.len() < max {
+            if i == active.len() {
+                // every active piece has been claimed as far as we can, pieces picked
+                // again in endgame included
+                let picked = picker
+                    .pick_suggested(peer_has, suggested)
+                    .or_else(|| picker.pick(peer_has))
+                    .or_else(|| picker.pick_endgame(peer_has));
+                let Some(index) = picked else {
+                    break;
+                };
+                if active.contains(&index) {
+                    break;
+                }
+                let length = self.torrent.info.piece_len(index);
+                pieces
+                    .entry(index)
+                    .or_insert_with(|| PartialPiece::new(index, length));
+                active.push(index);
+            }
+
+            let index = active[i];
+            if !peer_has.has(index) {
+                i += 1;
+                continue;
+            }
+            let piece = pieces.get_mut(&index).unwrap();
+            for block in piece.claim(worker, max - claimed.len(), endgame) {
+                claimed.push((index, block, piece.block_len(block)));
+            }
+            picker.set_requested(index, !piece.has_unrequested());
+            i += 1;
+        }
+        claimed
+    }
+
+    /// Store a block from `worker`, cancel it at the other peers it was requested
+    /// from and verify and write the piece once it is complete.
+    fn receive_block(
+        &self,
+        worker: WorkerId,
+        index: u32,
+        begin: u32,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        let piece = {
+            let mut state = self.state.lock().unwrap();
+            let Some(piece) = state.pieces.get_mut(&index) else {
+                return Ok(());
+            };
+            let Some(others) = piece.add_block(worker, begin, data)? else {
+                return Ok(());
+            };
+            let complete = piece.is_complete();
+            for other in others {
+                if let Some(cancels) = state.cancels.get(&other) {
+                    // a closed channel means that worker is gone anyway
+                    _ = cancels.send((index, begin / BLOCK_SIZE));
+                }
+            }
+            if !complete {
+                return Ok(());
+            }
+            state.pieces.remove(&index).unwrap()
+        };
+
+        let data = piece.data();
+        let result = if self.torrent.verify_piece(index, &data) {
+            let offset = index as u64 * self.torrent.info.piece_length as u64;
+            self.storage.write(offset, &data)
+        } else {
+            Err(Error::msg(format!("piece {} failed hash check", index)))
+        };
+
+        {
+            let mut state = self.state.lock().unwrap();
+            match result {
+                Ok(()) => state.picker.complete(index),
+                Err(ref e) => {
+                    eprintln!("{:#}", e);
+                    state.picker.abort(index);
+                }
+            }
+        }
+        self.progress.notify_waiters();
+        Ok(())
+    }
+
+    /// the peer announced it now has piece `index`
+    fn peer_have(&self, peer_has: &mut Bitfield, index: u32) {
+        if (index as usize) < peer_has.len() && !peer_has.has(index) {
+            peer_has.set(index);
+            self.state.lock().unwrap().picker.add_have(index);
+        }
+    }
+
+    /// the peer has a piece we want and don't have
+    fn wants_any(&self, peer_has: &Bitfield) -> bool {
+        let state = self.state.lock().unwrap();
+        peer_has.iter().any(|index| state.picker.wants(index))
+    }
+}
+
+/// Request the missing blocks of `piece` from the peer, keeping as many
+/// requests outstanding as `Pipeline` suggests, and store them as they
+/// arrive. Requests the peer drops by choking us are sent again once it
+/// unchokes. `on_have` is told about pieces the peer announces in the meantime.
+pub async fn download_piece<S: AsyncRead + AsyncWrite + Unpin>(
+    piece: &mut PartialPiece,
+    peer_stream: &mut S,
+    mut on_have: impl FnMut(u32),
+) -> anyhow::Result<()> {
+    let mut pipeline = Pipeline::new(PipelineConfig::default());
+    let mut outstanding: HashMap<u32, Instant> = HashMap::new();
+    let mut peer_choking = false;
+    loop {
+        if !peer_choking {
+            let wanted = pipeline.depth().saturating_sub(outstanding.len());
+            let now = Instant::now();
+            for block in piece.claim(0, wanted, false) {
+                PeerMessage::Request {
+                    index: piece.index,
+                    begin: block * BLOCK_SIZE,
+                    length: piece.block_len(block),
+                }
+                .write(peer_stream)
+                .await?;
+                outstanding.insert(block, now);
+            }
+        }
+        if piece.is_complete() {
+            return Ok(());
+        }
+
+        let msg = PeerMessage::read(peer_stream)
+            .await
+            .context("read message")?;
+        match msg {
+            PeerMessage::Piece { index, begin, data } => {
+                // blocks of other pieces or that we didn't ask for are dropped
+                if index != piece.index {
+                    continue;
+                }
+                if let Some(requested_at) = outstanding.remove(&(begin / BLOCK_SIZE)) {
+                    pipeline.on_block(requested_at, Instant::now(), data.len() as u64);
+                    piece.add_block(0, begin, data)?;
+                }
+            }
+            PeerMessage::Choke => {
+                // the peer dropped our requests, ask again once unchoked
+                peer_choking = true;
+                for (block, _) in outstanding.drain() {
+                    piece.release(0, block);
+                }
+                pipeline.pause();
+            }
+            PeerMessage::RejectRequest { index, begin, .. } => {
+                // ask again rather than wait for a block that won't come
+                if index == piece.index && outstanding.remove(&(begin / BLOCK_SIZE)).is_some() {
+                    piece.release(0, begin / BLOCK_SIZE);
+                }
+            }
+            PeerMessage::Unchoke => peer_choking = false,
+            PeerMessage::Have { index } => on_have(index),
+            PeerMessage::Extended { id: 0, payload } => {
+                let handshake = ExtensionHandshake::decode(&payload)?;
+                if let Some(reqq) = handshake.reqq {
+                    pipeline.set_peer_limit(reqq);
+                }
+            }
+            PeerMessage::KeepAlive
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested
+            | PeerMessage::Bitfield(_)
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone
+            | PeerMessage::Request { .. }
+            | PeerMessage::Cancel { .. }
+            | PeerMessage::Port(_)
+            | PeerMessage::Extended { .. }
+            | PeerMessage::SuggestPiece { .. }
+            | PeerMessage::AllowedFast { .. } => {}
+        }
+    }
+}
+
+/// Tell the peer we are interested and wait until it unchokes us. Returns the
+/// pieces the peer announced meanwhile.
+pub async fn request_unchoke<S: AsyncRead + AsyncWrite + Unpin>(
+    peer_stream: &mut S,
+    npieces: usize,
+) -> anyhow::Result<Bitfield> {
+    PeerMessage::Interested.write(peer_stream).await?;
+
+    let mut peer_has = Bitfield::new(npieces);
+    loop {
+        let msg = PeerMessage::read(peer_stream)
+            .await
+            .context("read message")?;
+        match msg {
+            PeerMessage::Unchoke => return Ok(peer_has),
+            PeerMessage::Bitfield(bits) => peer_has = Bitfield::from_bytes(&bits, npieces)?,
+            PeerMessage::HaveAll => peer_has = Bitfield::full(npieces),
+            PeerMessage::HaveNone => peer_has = Bitfield::new(npieces),
+            PeerMessage::Have { index } if (index as usize) < npieces => peer_has.set(index),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::*;
+
+    fn download(private: bool) -> (tempfile::TempDir, Download) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        std::fs::write(&path, [7; 1000]).unwrap();
+        let opts = CreateOptions {
+            private,
+            creation_date: false,
+            ..Default::default()
+        };
+        let torrent = create_torrent(&path, &opts).unwrap();
+        let storage = Storage::new(dir.path().join("out.bin"), &torrent.info);
+        (dir, Download::new(torrent, storage, [2; 20]))
+    }
+
+    #[test]
+    fn keeps_private_torrents_off_pex() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let (_dir, public) = download(false);
+        let extensions = public.extensions(addr);
+        assert!(extensions.local_id("ut_metadata").is_some());
+        assert!(extensions.local_id("ut_pex").is_some());
+
+        let (_dir, private) = download(true);
+        let extensions = private.extensions(addr);
+        assert!(extensions.local_id("ut_metadata").is_some());
+        assert_eq!(extensions.local_id("ut_pex"), None);
+        assert!(!extensions.our_handshake().m.contains_key("ut_pex"));
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error};
+use bytes::Bytes;
+
+use crate::peer::PeerMessage;
+use crate::value::Value;
+
+/// our client name and version, sent as `v`
+pub const CLIENT_VERSION: &str = concat!("bittorrent-starter-rust/", env!("CARGO_PKG_VERSION"));
+
+/// how often extensions get to send messages on their own
+pub const EXTENSION_TICK: Duration = Duration::from_secs(1);
+
+/// The extension handshake of BEP 10, the payload of extended message 0
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionHandshake {
+    /// extension names mapped to the id the sender wants their messages sent with
+    pub m: BTreeMap<String, u8>,
+    /// client name and version
+    pub v: Option<String>,
+    /// port the sender listens on
+    pub p: Option<u16>,
+    /// outstanding requests the sender queues at most
+    pub reqq: Option<usize>,
+    /// size of the info dictionary, announced by `ut_metadata`
+    pub metadata_size: Option<usize>,
+    /// our address as the sender sees it
+    pub yourip: Option<IpAddr>,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl ExtensionHandshake {
+    /// id the sender assigned to extension `name`, None if it doesn't support it
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != 0)
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut map = BTreeMap::new();
+        let m = self
+            .m
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), Value::Integer(*id as isize)))
+            .collect();
+        map.insert(b"m"[..].to_vec(), Value::Dict(m));
+        if let Some(v) = &self.v {
+            map.insert(b"v"[..].to_vec(), Value::String(v.as_bytes().to_vec()));
+        }
+        if let Some(p) = self.p {
+            map.insert(b"p"[..].to_vec(), Value::Integer(p as isize));
+        }
+        if let Some(reqq) = self.reqq {
+            map.insert(b"reqq"[..].to_vec(), Value::Integer(reqq as isize));
+        }
+        if let Some(metadata_size) = self.metadata_size {
+            map.insert(
+                b"metadata_size"[..].to_vec(),
+                Value::Integer(metadata_size as isize),
+            );
+        }
+        if let Some(yourip) = self.yourip {
+            let bytes = match yourip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            map.insert(b"yourip"[..].to_vec(), Value::String(bytes));
+        }
+        if let Some(ipv4) = self.ipv4 {
+            map.insert(b"ipv4"[..].to_vec(), Value::String(ipv4.octets().to_vec()));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            map.insert(b"ipv6"[..].to_vec(), Value::String(ipv6.octets().to_vec()));
+        }
+        Value::Dict(map)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_value().encode()
+    }
+
+    /// Read a handshake. Fields of an unexpected type or size are ignored, as
+    /// peers fill them in loosely, but the payload has to be a dictionary.
+    pub fn from_value(value: &Value) -> anyhow::Result<Self> {
+        let Value::Dict(dict) = value else {
+            return Err(Error::msg("extension handshake must be a dictionary"));
+        };
+        let integer = |key: &[u8]| match dict.get(key) {
+            Some(Value::Integer(i)) if *i >= 0 => Some(*i as usize),
+            _ => None,
+        };
+        let bytes = |key: &[u8]| match dict.get(key) {
+            Some(Value::String(s)) => Some(s.as_slice()),
+            _ => None,
+        };
+
+        let mut m = BTreeMap::new();
+        if let Some(Value::Dict(extensions)) = dict.get(&b"m"[..]) {
+            for (name, id) in extensions {
+                let (Ok(name), Value::Integer(id)) = (String::from_utf8(name.clone()), id) else {
+                    continue;
+                };
+                if let Ok(id) = u8::try_from(*id) {
+                    m.insert(name, id);
+                }
+            }
+        }
+
+        Ok(Self {
+            m,
+            v: bytes(b"v").map(|v| String::from_utf8_lossy(v).into_owned()),
+            p: integer(b"p").and_then(|p| u16::try_from(p).ok()),
+            reqq: integer(b"reqq"),
+            metadata_size: integer(b"metadata_size"),
+            yourip: bytes(b"yourip").and_then(|ip| match ip.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).unwrap())),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).unwrap())),
+                _ => None,
+            }),
+            ipv4: bytes(b"ipv4").and_then(|ip| <[u8; 4]>::try_from(ip).ok().map(Ipv4Addr::from)),
+            ipv6: bytes(b"ipv6").and_then(|ip| <[u8; 16]>::try_from(ip).ok().map(Ipv6Addr::from)),
+        })
+    }
+
+    pub fn decode(payload: &[u8]) -> anyhow::Result<Self> {
+        let (value, _) = Value::decode(payload).context("decode extension handshake")?;
+        Self::from_value(&value)
+    }
+}
+
+/// An extension of the extension protocol, e.g. `ut_metadata`
+pub trait Extension: Send {
+    /// the name the extension is announced with in `m`
+    fn name(&self) -> &'static str;
+
+    /// fill in the fields of our handshake that belong to this extension
+    fn extend_handshake(&self, _handshake: &mut ExtensionHandshake) {}
+
+    /// The peer's handshake arrived. `id` is the id it wants our messages for
+    /// this extension sent with, None if it doesn't support it.
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake, _id: Option<u8>) {}
+
+    /// A message for this extension arrived. Returns the payloads to answer
+    /// with, sent with the peer's id for the extension.
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Bytes>>;
+
+    /// Called every EXTENSION_TICK. Returns payloads to send on the
+    /// extension's own initiative.
+    fn poll(&mut self, _now: Instant) -> Vec<Bytes> {
+        Vec::new()
+    }
+}
+
+/// The extensions spoken on one connection.
+///
+/// Each registered extension gets the id it is registered with in our
+/// handshake; messages the peer sends are dispatched by that id. Messages we
+/// send use the ids the peer assigned in its handshake.
+#[derive(Default)]
+pub struct Extensions {
+    extensions: Vec<Box<dyn Extension>>,
+    /// fields of our handshake not owned by an extension, `m` is filled in
+    pub handshake: ExtensionHandshake,
+    peer_handshake: Option<ExtensionHandshake>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self {
+            handshake: ExtensionHandshake {
+                v: Some(CLIENT_VERSION.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// add an extension, returning the id the peer sends its messages with
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> u8 {
+        assert!(
+            self.extensions.len() < u8::MAX as usize,
+            "too many extensions"
+        );
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    /// id we assigned to extension `name`
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        let i = self.extensions.iter().position(|e| e.name() == name)?;
+        Some(i as u8 + 1)
+    }
+
+    /// id the peer assigned to extension `name`, None until its handshake arrived
+    /// or if it doesn't support the extension
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.peer_handshake.as_ref()?.id(name)
+    }
+
+    pub fn peer_handshake(&self) -> Option<&ExtensionHandshake> {
+        self.peer_handshake.as_ref()
+    }
+
+    /// our handshake, announcing every registered extension
+    pub fn our_handshake(&self) -> ExtensionHandshake {
+        let mut handshake = self.handshake.clone();
+        for (i, extension) in self.extensions.iter().enumerate() {
+            handshake
+                .m
+                .insert(extension.name().to_string(), i as u8 + 1);
+            extension.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    /// our handshake as the message to send
+    pub fn handshake_message(&self) -> PeerMessage {
+        PeerMessage::Extended {
+            id: 0,
+            payload: self.our_handshake().encode().into(),
+        }
+    }
+
+    /// Handle the extended message with `id`, returning the messages to answer
+    /// with. Messages for ids we didn't assign are ignored.
+    pub fn dispatch(&mut self, id: u8, payload: &[u8]) -> anyhow::Result<Vec<PeerMessage>> {
+        if id == 0 {
+            let handshake = ExtensionHandshake::decode(payload)?;
+            for extension in &mut self.extensions {
+                let id = handshake.id(extension.name());
+                extension.on_handshake(&handshake, id);
+            }
+            self.peer_handshake = Some(handshake);
+            return Ok(Vec::new());
+        }
+
+        let Some(extension) = self.extensions.get_mut(id as usize - 1) else {
+            return Ok(Vec::new());
+        };
+        let name = extension.name();
+        let replies = extension
+            .on_message(payload)
+            .with_context(|| format!("extension {}", name))?;
+        if replies.is_empty() {
+            return Ok(Vec::new());
+        }
+        let remote_id = self
+            .remote_id(name)
+            .with_context(|| format!("peer doesn't support {}", name))?;
+        Ok(replies
+            .into_iter()
+            .map(|payload| PeerMessage::Extended {
+                id: remote_id,
+                payload,
+            })
+            .collect())
+    }
+
+    /// Messages the extensions send on their own, for extensions the peer supports.
+    pub fn poll(&mut self, now: Instant) -> Vec<PeerMessage> {
+        let mut messages = Vec::new();
+        for extension in &mut self.extensions {
+            let Some(id) = self
+                .peer_handshake
+                .as_ref()
+                .and_then(|h| h.id(extension.name()))
+            else {
+                continue;
+            };
+            for payload in extension.poll(now) {
+                messages.push(PeerMessage::Extended { id, payload });
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// answers every message with its payload reversed, and sends `tick` when polled
+    struct Echo {
+        name: &'static str,
+    }
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn extend_handshake(&self, handshake: &mut ExtensionHandshake) {
+            handshake.metadata_size = Some(1234);
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Bytes>> {
+            if payload.is_empty() {
+                return Err(Error::msg("empty"));
+            }
+            Ok(vec![payload.iter().rev().copied().collect()])
+        }
+
+        fn poll(&mut self, _now: Instant) -> Vec<Bytes> {
+            vec![Bytes::from_static(b"tick")]
+        }
+    }
+
+    fn echo(name: &'static str) -> Box<dyn Extension> {
+        Box::new(Echo { name })
+    }
+
+    #[test]
+    fn round_trips_handshakes() {
+        let handshake = ExtensionHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), 3), ("ut_pex".to_string(), 1)]),
+            v: Some("test 1.0".to_string()),
+            p: Some(6881),
+            reqq: Some(250),
+            metadata_size: Some(31_235),
+            yourip: Some("2001:db8::2".parse().unwrap()),
+            ipv4: Some("1.2.3.4".parse().unwrap()),
+            ipv6: Some("2001:db8::1".parse().unwrap()),
+        };
+        let encoded = handshake.encode();
+        assert!(encoded.starts_with(b"d4:ipv44:\x01\x02\x03\x04"));
+        assert_eq!(ExtensionHandshake::decode(&encoded).unwrap(), handshake);
+        assert_eq!(handshake.id("ut_pex"), Some(1));
+        assert_eq!(handshake.id("lt_donthave"), None);
+
+        let ipv4 = ExtensionHandshake {
+            yourip: Some("10.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(ExtensionHandshake::decode(&ipv4.encode()).unwrap(), ipv4);
+    }
+
+    #[test]
+    fn ignores_malformed_handshake_fields() {
+        let handshake = ExtensionHandshake::decode(
+            b"d1:md6:ut_pexi0e5:ut_xxi300e3:badi-1e11:ut_metadatai2ee\
+              4:reqqi-5e6:yourip3:abc4:ipv41:x1:pi70000e13:metadata_size1:xe",
+        )
+        .unwrap();
+        assert_eq!(
+            handshake.m,
+            BTreeMap::from([("ut_metadata".to_string(), 2), ("ut_pex".to_string(), 0)])
+        );
+        // an id of 0 disables the extension
+        assert_eq!(handshake.id("ut_pex"), None);
+        assert_eq!(handshake.reqq, None);
+        assert_eq!(handshake.yourip, None);
+        assert_eq!(handshake.ipv4, None);
+        assert_eq!(handshake.p, None);
+        assert_eq!(handshake.metadata_size, None);
+
+        assert!(ExtensionHandshake::decode(b"li1ee").is_err());
+        assert!(ExtensionHandshake::decode(b"d1:m").is_err());
+    }
+
+    #[test]
+    fn assigns_local_ids_in_registration_order() {
+        let mut extensions = Extensions::new();
+        assert_eq!(extensions.register(echo("ut_metadata")), 1);
+        assert_eq!(extensions.register(echo("ut_pex")), 2);
+        assert_eq!(extensions.local_id("ut_pex"), Some(2));
+        assert_eq!(extensions.local_id("lt_donthave"), None);
+
+        let ours = extensions.our_handshake();
+        assert_eq!(
+            ours.m,
+            BTreeMap::from([("ut_metadata".to_string(), 1), ("ut_pex".to_string(), 2)])
+        );
+        assert_eq!(ours.v.as_deref(), Some(CLIENT_VERSION));
+        assert_eq!(ours.metadata_size, Some(1234));
+        let PeerMessage::Extended { id: 0, payload } = extensions.handshake_message() else {
+            panic!("the handshake is extended message 0");
+        };
+        assert_eq!(ExtensionHandshake::decode(&payload).unwrap(), ours);
+    }
+
+    #[test]
+    fn dispatches_by_local_id_and_replies_with_remote_ids() {
+        let mut extensions = Extensions::new();
+        extensions.register(echo("ut_metadata"));
+        extensions.register(echo("ut_pex"));
+        let peer = ExtensionHandshake {
+            m: BTreeMap::from([("ut_pex".to_string(), 7), ("ut_metadata".to_string(), 0)]),
+            ..Default::default()
+        };
+        assert!(extensions.dispatch(0, &peer.encode()).unwrap().is_empty());
+        assert_eq!(extensions.peer_handshake(), Some(&peer));
+        assert_eq!(extensions.remote_id("ut_pex"), Some(7));
+        assert_eq!(extensions.remote_id("ut_metadata"), None);
+
+        assert_eq!(
+            extensions.dispatch(2, b"abc").unwrap(),
+            [PeerMessage::Extended {
+                id: 7,
+                payload: Bytes::from_static(b"cba"),
+            }]
+        );
+        // ids we never assigned are ignored
+        assert!(extensions.dispatch(3, b"abc").unwrap().is_empty());
+        assert!(extensions.dispatch(255, b"abc").unwrap().is_empty());
+        // errors carry the extension's name
+        let err = extensions.dispatch(2, b"").unwrap_err();
+        assert!(format!("{:#}", err).contains("ut_pex"));
+        // a reply to an extension the peer disabled can't be sent
+        assert!(extensions.dispatch(1, b"abc").is_err());
+        assert!(extensions.dispatch(0, b"i1e").is_err());
+    }
+
+    #[test]
+    fn polls_extensions_the_peer_supports() {
+        let mut extensions = Extensions::new();
+        extensions.register(echo("ut_metadata"));
+        extensions.register(echo("ut_pex"));
+        let now = Instant::now();
+        // nothing is sent before the peer's handshake
+        assert!(extensions.poll(now).is_empty());
+
+        let peer = ExtensionHandshake {
+            m: BTreeMap::from([("ut_pex".to_string(), 9)]),
+            ..Default::default()
+        };
+        extensions.dispatch(0, &peer.encode()).unwrap();
+        assert_eq!(
+            extensions.poll(now),
+            [PeerMessage::Extended {
+                id: 9,
+                payload: Bytes::from_static(b"tick"),
+            }]
+        );
+    }
+}
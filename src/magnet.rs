@@ -1,62 +1,464 @@
-use std::collections::HashMap;
+use std::ops::RangeInclusive;
 
-use anyhow::Context;
+use anyhow::{Context, Error};
 use reqwest::Url;
 
-use crate::torrent::TorrentInfo;
+use crate::torrent::{Torrent, TorrentInfo};
 
+/// A magnet link (BEP 9), identifying a torrent by its info hash
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Magnet {
-    pub tracker_url: String,
-    /// The name of the file to be downloaded
-    pub name: String,
-    /// 40-char hex-encoded info hash
-    pub info_hash: [u8; 20],
+    /// v1 info hash from `xt=urn:btih:`, hex or base32 encoded in the link
+    pub info_hash_v1: Option<[u8; 20]>,
+    /// v2 info hash from `xt=urn:btmh:`, a SHA-256 multihash
+    pub info_hash_v2: Option<[u8; 32]>,
+    /// tracker urls in the order they appear, `tr`
+    pub trackers: Vec<String>,
+    /// The name of the file to be downloaded, `dn`
+    pub name: Option<String>,
+    /// peers to connect to without asking a tracker, `x.pe`, as host:port
+    pub peers: Vec<String>,
+    /// web seed urls (BEP 19), `ws`
+    pub web_seeds: Vec<String>,
+    /// exact length of the torrent in bytes, `xl`
+    pub length: Option<u64>,
+    /// indices of the files to download, `so`, empty for all of them
+    pub select_only: Vec<RangeInclusive<usize>>,
 }
 
 impl TorrentInfo for Magnet {
     fn announce(&self) -> String {
-        self.tracker_url.to_owned()
+        self.trackers.first().cloned().unwrap_or_default()
     }
 
+    /// The v1 info hash, or for v2-only links the v2 hash truncated to 20
+    /// bytes as used by trackers and the peer handshake.
     fn info_hash(&self) -> [u8; 20] {
-        self.info_hash
+        match (self.info_hash_v1, self.info_hash_v2) {
+            (Some(hash), _) => hash,
+            (None, Some(hash)) => std::array::from_fn(|i| hash[i]),
+            (None, None) => unreachable!("parse requires an info hash"),
+        }
     }
 
-    fn length(&self) -> u32 {
-        1
+    fn length(&self) -> u64 {
+        self.length.unwrap_or(1)
     }
 }
 
 impl Magnet {
     pub fn parse(uri: &str) -> anyhow::Result<Magnet> {
-        let url = Url::parse(&uri).unwrap();
-        let pairs = url.query_pairs().fold(HashMap::new(), |mut acc, q| {
-            acc.insert(q.0, q.1);
-            acc
-        });
-
-        let tracker_url = pairs
-            .get("tr")
-            .context("magnet-link doesn't have tracker url")?
-            .to_string();
-        let name = pairs
-            .get("dn")
-            .context("magnet-link doesn't have name")?
-            .to_string();
-        let info_hash = pairs
-            .get("xt")
-            .context("magnet-link doesn't have info hash")?
-            .split(':')
-            .last()
-            .unwrap()
-            .to_string();
-        let info_hash = hex::decode(info_hash)?;
-        let info_hash: [u8; 20] = std::array::from_fn(|i| info_hash[i]);
-
-        Ok(Magnet {
-            tracker_url,
-            name,
-            info_hash,
-        })
+        let url = Url::parse(uri).context("parse magnet link")?;
+        if url.scheme() != "magnet" {
+            return Err(Error::msg(format!(
+                "expected a magnet link, got scheme {}",
+                url.scheme()
+            )));
+        }
+
+        let mut magnet = Magnet::default();
+        for (key, value) in url.query_pairs() {
+            // parameters may be numbered to repeat them, e.g. `tr.1`, `xt.2`
+            let key = match key.rsplit_once('.') {
+                Some((base, n)) if n.bytes().all(|b| b.is_ascii_digit()) => base,
+                _ => &key,
+            };
+            match key {
+                "xt" => magnet.parse_exact_topic(&value)?,
+                "tr" => push_unique(&mut magnet.trackers, &value),
+                "dn" => magnet.name = Some(value.into_owned()),
+                "x.pe" => {
+                    let (host, port) = value
+                        .rsplit_once(':')
+                        .with_context(|| format!("peer address {} has no port", value))?;
+                    if host.is_empty() {
+                        return Err(Error::msg(format!("peer address {} has no host", value)));
+                    }
+                    port.parse::<u16>()
+                        .with_context(|| format!("invalid port in peer address {}", value))?;
+                    push_unique(&mut magnet.peers, &value);
+                }
+                "ws" => push_unique(&mut magnet.web_seeds, &value),
+                "xl" => {
+                    let length = value
+                        .parse()
+                        .with_context(|| format!("invalid exact length {}", value))?;
+                    magnet.length = Some(length);
+                }
+                "so" => magnet.select_only = parse_select_only(&value)?,
+                // other parameters, e.g. `kt` or `as`, are not used
+                _ => {}
+            }
+        }
+
+        if magnet.info_hash_v1.is_none() && magnet.info_hash_v2.is_none() {
+            return Err(Error::msg(
+                "magnet link has no urn:btih or urn:btmh info hash",
+            ));
+        }
+        Ok(magnet)
+    }
+
+    /// Magnet link of `torrent`, with all of its trackers and web seeds and
+    /// the info hashes of the versions it has.
+    pub fn from_torrent(torrent: &Torrent) -> Magnet {
+        let mut trackers = Vec::new();
+        if !torrent.announce.is_empty() {
+            push_unique(&mut trackers, &String::from_utf8_lossy(&torrent.announce));
+        }
+        for url in torrent.announce_list.iter().flatten() {
+            push_unique(&mut trackers, url);
+        }
+        let info = &torrent.info;
+        Magnet {
+            // v2-only torrents have no v1 info hash, hybrids have both
+            info_hash_v1: info.has_v1().then(|| info.hash()),
+            info_hash_v2: info.hash_v2(),
+            trackers,
+            name: Some(torrent.info.name.clone()),
+            web_seeds: torrent.url_list.clone(),
+            length: Some(torrent.info.length),
+            ..Default::default()
+        }
+    }
+
+    /// Check that the torrent of the link can be fetched. v2-only torrents need
+    /// their piece layers, which peers would have to send in BEP 52 hash
+    /// messages we don't support, so they are refused before contacting anyone.
+    pub fn check_fetchable(&self) -> anyhow::Result<()> {
+        if self.info_hash_v1.is_none() {
+            return Err(Error::msg(
+                "v2-only magnet links are unsupported, their piece layers can't be fetched",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The link as a `magnet:` URI, parameters percent-encoded.
+    pub fn to_uri(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(hash) = self.info_hash_v1 {
+            params.push(format!("xt=urn:btih:{}", hex::encode(hash)));
+        }
+        if let Some(hash) = self.info_hash_v2 {
+            params.push(format!("xt=urn:btmh:1220{}", hex::encode(hash)));
+        }
+        if let Some(name) = &self.name {
+            params.push(format!("dn={}", percent_encode(name)));
+        }
+        if let Some(length) = self.length {
+            params.push(format!("xl={}", length));
+        }
+        for tracker in &self.trackers {
+            params.push(format!("tr={}", percent_encode(tracker)));
+        }
+        for url in &self.web_seeds {
+            params.push(format!("ws={}", percent_encode(url)));
+        }
+        for peer in &self.peers {
+            params.push(format!("x.pe={}", percent_encode(peer)));
+        }
+        if !self.select_only.is_empty() {
+            params.push(format!("so={}", self.select_only_list()));
+        }
+        format!("magnet:?{}", params.join("&"))
+    }
+
+    /// the `so` file indices as they appear in a link, e.g. `0,2,4-6`
+    pub fn select_only_list(&self) -> String {
+        let items: Vec<String> = self
+            .select_only
+            .iter()
+            .map(|r| {
+                if r.start() == r.end() {
+                    r.start().to_string()
+                } else {
+                    format!("{}-{}", r.start(), r.end())
+                }
+            })
+            .collect();
+        items.join(",")
+    }
+
+    /// whether file `index` is to be downloaded according to `so`
+    pub fn is_selected(&self, index: usize) -> bool {
+        self.select_only.is_empty() || self.select_only.iter().any(|r| r.contains(&index))
+    }
+
+    /// Read an `xt` value. Topics other than BitTorrent info hashes are
+    /// ignored, a second different hash of the same kind is an error.
+    fn parse_exact_topic(&mut self, topic: &str) -> anyhow::Result<()> {
+        if let Some(hash) = topic.strip_prefix("urn:btih:") {
+            let hash = match hash.len() {
+                40 => hex::decode(hash).ok(),
+                32 => base32_decode(hash),
+                _ => None,
+            }
+            .and_then(|h| <[u8; 20]>::try_from(h).ok())
+            .with_context(|| format!("invalid btih info hash {}", hash))?;
+            if self.info_hash_v1.is_some_and(|h| h != hash) {
+                return Err(Error::msg("magnet link has more than one btih info hash"));
+            }
+            self.info_hash_v1 = Some(hash);
+        } else if let Some(multihash) = topic.strip_prefix("urn:btmh:") {
+            // multihash: 0x12 for SHA-256, 0x20 for its length, then the digest
+            let hash = hex::decode(multihash)
+                .ok()
+                .and_then(|m| m.strip_prefix(&[0x12, 0x20]).map(<[u8]>::to_vec))
+                .and_then(|h| <[u8; 32]>::try_from(h).ok())
+                .with_context(|| format!("invalid btmh info hash {}", multihash))?;
+            if self.info_hash_v2.is_some_and(|h| h != hash) {
+                return Err(Error::msg("magnet link has more than one btmh info hash"));
+            }
+            self.info_hash_v2 = Some(hash);
+        }
+        Ok(())
+    }
+}
+
+fn push_unique(list: &mut Vec<String>, value: &str) {
+    if !list.iter().any(|v| v == value) {
+        list.push(value.to_string());
+    }
+}
+
+/// escape everything but RFC 3986 unreserved characters
+pub fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// parse a `so` list of file indices and ranges, e.g. `0,2,4-6`
+fn parse_select_only(value: &str) -> anyhow::Result<Vec<RangeInclusive<usize>>> {
+    value.split(',').map(parse_index_range).collect()
+}
+
+/// parse a file index or an inclusive range of them, e.g. `2` or `4-6`
+pub fn parse_index_range(item: &str) -> anyhow::Result<RangeInclusive<usize>> {
+    let (start, end) = item.split_once('-').unwrap_or((item, item));
+    match (start.parse().ok(), end.parse().ok()) {
+        (Some(start), Some(end)) if start <= end => Ok(start..=end),
+        _ => Err(Error::msg(format!("invalid file index or range {}", item))),
+    }
+}
+
+/// decode unpadded RFC 4648 base32, case insensitive
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::torrent::Info;
+    use crate::value::{raw_dict_value, Value};
+
+    const HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+    #[test]
+    fn parses_every_parameter() {
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=sample%20file.txt&xl=92063\
+             &tr=http%3A%2F%2Fa.example%2Fannounce&tr.1=udp%3A%2F%2Fb.example%3A80\
+             &tr.2=http%3A%2F%2Fa.example%2Fannounce&x.pe=10.0.0.1%3A6881&x.pe=%5B%3A%3A1%5D%3A51413\
+             &ws=http%3A%2F%2Fseed.example%2Ffile&so=0,2,4-6&kt=ignored",
+            HASH
+        ))
+        .unwrap();
+        assert_eq!(
+            magnet.info_hash_v1,
+            Some(hex::decode(HASH).unwrap().try_into().unwrap())
+        );
+        assert_eq!(magnet.info_hash_v2, None);
+        assert_eq!(magnet.name.as_deref(), Some("sample file.txt"));
+        assert_eq!(magnet.length, Some(92063));
+        assert_eq!(
+            magnet.trackers,
+            ["http://a.example/announce", "udp://b.example:80"]
+        );
+        assert_eq!(magnet.peers, ["10.0.0.1:6881", "[::1]:51413"]);
+        assert_eq!(magnet.web_seeds, ["http://seed.example/file"]);
+        assert_eq!(magnet.select_only, [0..=0, 2..=2, 4..=6]);
+        assert!(magnet.is_selected(5));
+        assert!(!magnet.is_selected(3));
+        assert_eq!(magnet.announce(), "http://a.example/announce");
+    }
+
+    #[test]
+    fn decodes_base32_info_hashes() {
+        let hex = Magnet::parse(&format!("magnet:?xt=urn:btih:{}", HASH)).unwrap();
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap();
+        assert_eq!(base32.info_hash_v1, hex.info_hash_v1);
+        let lower = Magnet::parse("magnet:?xt=urn:btih:22pzdzvsvzgfijdi2edtu4ou5ijypgt7").unwrap();
+        assert_eq!(lower.info_hash_v1, hex.info_hash_v1);
+    }
+
+    #[test]
+    fn parses_v2_and_hybrid_links() {
+        let v2 = "1220fafc154bee37109f272d11849d2ade68d3898556112372d1f8b1f98d3e176790";
+        let magnet = Magnet::parse(&format!("magnet:?xt=urn:btmh:{}", v2)).unwrap();
+        assert_eq!(magnet.info_hash_v1, None);
+        assert_eq!(magnet.info_hash_v2.map(hex::encode).unwrap(), v2[4..]);
+        assert_eq!(magnet.info_hash(), magnet.info_hash_v2.unwrap()[..20]);
+        assert!(magnet.check_fetchable().is_err());
+
+        let hybrid =
+            Magnet::parse(&format!("magnet:?xt=urn:btih:{}&xt=urn:btmh:{}", HASH, v2)).unwrap();
+        assert_eq!(hex::encode(hybrid.info_hash()), HASH);
+        assert!(hybrid.info_hash_v2.is_some());
+        assert!(hybrid.check_fetchable().is_ok());
+    }
+
+    #[test]
+    fn round_trips_through_uri() {
+        let magnet = Magnet {
+            info_hash_v1: Some([0xab; 20]),
+            info_hash_v2: Some([0xcd; 32]),
+            trackers: vec![
+                "http://tracker.example/announce?key=a&b".to_string(),
+                "udp://other.example:6969".to_string(),
+            ],
+            name: Some("name with spaces & ünïcode".to_string()),
+            peers: vec!["127.0.0.1:6881".to_string()],
+            web_seeds: vec!["https://seed.example/dir/".to_string()],
+            length: Some(12345),
+            select_only: vec![1..=1, 3..=7],
+        };
+        let uri = magnet.to_uri();
+        assert!(uri.starts_with("magnet:?xt=urn:btih:abab"), "{}", uri);
+        assert!(uri.contains("&so=1,3-7"), "{}", uri);
+        assert_eq!(Magnet::parse(&uri).unwrap(), magnet);
+
+        let minimal = Magnet {
+            info_hash_v1: Some([1; 20]),
+            ..Default::default()
+        };
+        assert_eq!(Magnet::parse(&minimal.to_uri()).unwrap(), minimal);
+    }
+
+    #[test]
+    fn rejects_invalid_links() {
+        for uri in [
+            "magnet:?dn=no-hash",
+            "http://example.com/?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?xt=urn:btih:d69f91e6",
+            "magnet:?xt=urn:btih:zz9f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f\
+             &xt=urn:btih:0000000000000000000000000000000000000000",
+            "magnet:?xt=urn:btmh:1114d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&x.pe=10.0.0.1",
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&x.pe=:6881",
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&xl=-1",
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&so=3-1",
+        ] {
+            assert!(Magnet::parse(uri).is_err(), "{}", uri);
+        }
+    }
+
+    fn sample_torrent() -> Torrent {
+        let file = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.torrent")).unwrap();
+        let mut torrent = Torrent::from_value(&Value::decode(&file).unwrap().0).unwrap();
+        torrent.info.raw = raw_dict_value(&file, b"info").unwrap().map(<[u8]>::to_vec);
+        torrent
+    }
+
+    #[test]
+    fn builds_links_from_torrents() {
+        let mut torrent = sample_torrent();
+        torrent.announce_list = vec![
+            vec![torrent.announce()],
+            vec!["udp://backup.example:6969".to_string()],
+        ];
+        torrent.url_list = vec!["http://seed.example/sample.txt".to_string()];
+
+        let magnet = Magnet::from_torrent(&torrent);
+        assert_eq!(magnet.info_hash_v1.map(hex::encode).as_deref(), Some(HASH));
+        assert_eq!(magnet.info_hash_v2, None);
+        assert_eq!(
+            magnet.trackers,
+            [
+                "http://bittorrent-test-tracker.codecrafters.io/announce",
+                "udp://backup.example:6969"
+            ]
+        );
+        assert_eq!(magnet.name.as_deref(), Some("sample.txt"));
+        assert_eq!(magnet.length, Some(92063));
+        assert_eq!(magnet.web_seeds, torrent.url_list);
+        assert_eq!(
+            magnet.to_uri(),
+            format!(
+                "magnet:?xt=urn:btih:{}&dn=sample.txt&xl=92063\
+                 &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+                 &tr=udp%3A%2F%2Fbackup.example%3A6969\
+                 &ws=http%3A%2F%2Fseed.example%2Fsample.txt",
+                HASH
+            )
+        );
+        assert_eq!(Magnet::parse(&magnet.to_uri()).unwrap(), magnet);
+    }
+
+    #[test]
+    fn links_v2_torrents_by_their_v2_hash() {
+        let mut torrent = sample_torrent();
+        let file_tree = Value::Dict(BTreeMap::from([(
+            b"sample.txt".to_vec(),
+            Value::Dict(BTreeMap::from([(
+                Vec::new(),
+                Value::Dict(BTreeMap::from([
+                    (b"length".to_vec(), Value::Integer(900)),
+                    (b"pieces root".to_vec(), Value::String(vec![1; 32])),
+                ])),
+            )])),
+        )]));
+        let Value::Dict(mut info) = torrent.info.to_value() else {
+            unreachable!("to_value returns a dictionary")
+        };
+        info.remove(&b"pieces"[..]);
+        info.remove(&b"length"[..]);
+        info.insert(b"file tree".to_vec(), file_tree);
+        info.insert(b"meta version".to_vec(), Value::Integer(2));
+        info.insert(b"piece length".to_vec(), Value::Integer(16384));
+        torrent.info = Info::from_value(&Value::Dict(info)).unwrap();
+
+        let magnet = Magnet::from_torrent(&torrent);
+        assert_eq!(magnet.info_hash_v1, None);
+        assert_eq!(magnet.info_hash_v2, torrent.info.hash_v2());
+        assert_eq!(magnet.info_hash(), torrent.info.info_hash());
+        let uri = magnet.to_uri();
+        assert!(!uri.contains("urn:btih:"), "{}", uri);
+        assert!(
+            uri.contains(&format!(
+                "xt=urn:btmh:1220{}",
+                hex::encode(torrent.info.hash_v2().unwrap())
+            )),
+            "{}",
+            uri
+        );
+        assert_eq!(Magnet::parse(&uri).unwrap(), magnet);
     }
 }
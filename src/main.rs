@@ -1,26 +1,56 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 use anyhow::Context;
-use bytes::BufMut;
-use serde_json::json;
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
 use std::fs;
-use std::net::SocketAddrV4;
-use tokio::net::TcpStream;
-
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::{Arc, OnceLock};
+use tokio::net::{TcpListener, TcpStream};
+
+mod bitfield;
+mod choker;
+mod create;
+mod download;
+mod extension;
 mod magnet;
+mod merkle;
+mod metadata;
 mod peer;
+mod pex;
+mod picker;
+mod pipeline;
+mod priority;
+mod rng;
+mod seed;
+mod sha256;
+mod storage;
 mod torrent;
 mod tracker;
 mod value;
+mod webseed;
 
+use choker::*;
+use create::*;
+use download::*;
+use extension::*;
 use magnet::*;
+use metadata::*;
 use peer::*;
+use pipeline::*;
+use priority::*;
+use seed::*;
+use storage::*;
 use torrent::*;
 use tracker::*;
 use value::*;
 
-const PEER_ID: [u8; 20] = *b"code5craf5ters5code5";
+/// our peer id, random per process so two instances never mistake each other for a self-connection
+fn peer_id() -> [u8; 20] {
+    static PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
+    *PEER_ID.get_or_init(generate_peer_id)
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -29,13 +59,13 @@ async fn main() -> anyhow::Result<()> {
     match args.next().expect("command").as_str() {
         "decode" => {
             let encoded_value = args.next().expect("encoded value");
-            let decoded_value = Value::decode(&encoded_value.as_bytes())?;
+            let decoded_value = Value::decode(encoded_value.as_bytes())?;
             println!("{}", decoded_value.0.to_json());
         }
         "info" => {
             let file_path = args.next().expect("path to torrent file");
             let torrent = parse_torrent_file(&file_path)?;
-            let info_hash = torrent.info.hash();
+            let info_hash = torrent.info_hash();
             let piece_hashes = torrent.piece_hashes();
 
             println!(
@@ -54,6 +84,9 @@ async fn main() -> anyhow::Result<()> {
                     acc
                 })
             );
+            if let Some(info_hash) = torrent.info.hash_v2() {
+                println!("Info Hash v2: {}", hex::encode(info_hash));
+            }
             println!("Piece Length: {}", torrent.info.piece_length);
             println!("Piece Hashes:");
             for hash in piece_hashes {
@@ -66,7 +99,7 @@ async fn main() -> anyhow::Result<()> {
         "peers" => {
             let file_path = args.next().expect("path to torrent file");
             let torrent = parse_torrent_file(&file_path)?;
-            let peers = get_peers(&torrent, &PEER_ID).await?;
+            let peers = get_peers(&torrent, &peer_id()).await?;
             for peer in peers {
                 println!("{}:{}", peer.ip(), peer.port());
             }
@@ -76,13 +109,13 @@ async fn main() -> anyhow::Result<()> {
             let peer_add = args.next().expect("peer address");
             let torrent = parse_torrent_file(&file_path)?;
 
-            let info_hash = torrent.info.hash();
+            let info_hash = torrent.info_hash();
             let peer_address = peer_add.parse::<SocketAddrV4>().unwrap();
 
             let (handshake_msg, _peer_stream) =
-                handshake_peer(peer_address, &info_hash, &PEER_ID).await?;
+                handshake_peer(peer_address, &info_hash, &peer_id()).await?;
 
-            println!("Peer ID: {}", hex::encode(&handshake_msg.peer_id));
+            println!("Peer ID: {}", hex::encode(handshake_msg.peer_id));
         }
         "download_piece" => {
             let _ = args.next().context("expected -o")?;
@@ -92,158 +125,92 @@ async fn main() -> anyhow::Result<()> {
 
             let torrent = parse_torrent_file(&torrent_path).context("parse torrent file")?;
             let piece_index = piece_index.parse::<u32>().expect("piece index must be u32");
-            let peers = get_peers(&torrent, &PEER_ID).await?;
+            let peers = get_peers(&torrent, &peer_id()).await?;
             let info = &torrent.info;
 
-            let (_handshake_msg, mut peer_stream) =
-                handshake_peer(peers[1], &info.hash(), &PEER_ID).await?;
+            let mut peer_stream = connect_for_piece(&peers, info, piece_index).await?;
 
-            // recieve [bitfield] message
-            let pmf = PeerMsgFrame::read(&mut peer_stream).await?;
-            if pmf.msg_id != MsgID::Bitfield {
-                todo!()
-            }
-            // send [interested] message
-            let pmf = PeerMsgFrame::new(MsgID::Interested, Vec::new());
-            pmf.write(&mut peer_stream).await?;
-            // recieve unchoke message
-            let pmf = PeerMsgFrame::read(&mut peer_stream).await?;
-            if pmf.msg_id != MsgID::Unchoke {
-                todo!()
-            }
-
-            let npieces = info.pieces.len() as u32;
-            let rem = info.length % info.piece_length;
-            let piece_len = if piece_index == npieces - 1 && rem != 0 {
-                rem
-            } else {
-                info.piece_length
-            };
-
-            let piece = download_piece(piece_index, piece_len, &mut peer_stream).await?;
-            fs::write(output_path, piece).expect("write piece to file");
+            let mut piece = PartialPiece::new(piece_index, info.piece_len(piece_index));
+            download_piece(&mut piece, &mut peer_stream, |_| {}).await?;
+            fs::write(output_path, piece.data()).expect("write piece to file");
         }
         "download" => {
-            let _ = args.next().context("expected -o")?;
-            let output_path = args.next().context("get output path")?;
-            let torrent_path = args.next().context("get torrent file path")?;
+            // download -o <output> [--min-requests <n>] [--max-requests <n>]
+            //          [--files <selection>] <torrent>
+            let DownloadArgs {
+                output_path,
+                pipeline_config,
+                selection,
+                source,
+            } = DownloadArgs::parse(args)?;
+            let torrent_path = source.context("get torrent file path")?;
 
             let torrent = parse_torrent_file(&torrent_path).context("parse torrent file")?;
-            let my_peer_id = b"randombyterandombyte";
-            let peers = get_peers(&torrent, &PEER_ID).await?;
-            let info = &torrent.info;
-
-            struct Piece {
-                index: u32,
-                length: u32,
-                data: Vec<u8>,
+            let peers = download_peers(&torrent).await?;
+
+            let storage = Storage::new(&output_path, &torrent.info);
+            let nfiles = storage.files.len();
+            let mut download = Download::new(torrent, storage, peer_id());
+            download.pipeline_config = pipeline_config;
+            if let Some(selection) = selection {
+                download.set_file_priorities(&parse_file_priorities(&selection, nfiles)?)?;
             }
+            Arc::new(download).run(peers).await?;
+        }
+        "magnet_parse" => {
+            let magnet_link = args.next().expect("magnet-link");
+            let magnet = Magnet::parse(&magnet_link)?;
 
-            impl Piece {
-                fn new(index: u32, length: u32) -> Self {
-                    Self {
-                        index,
-                        length,
-                        data: Vec::new(),
-                    }
-                }
+            for tracker in &magnet.trackers {
+                println!("Tracker URL: {}", tracker);
             }
-
-            let npieces = info.pieces.len() as u32;
-            let mut pieces = Vec::new();
-            for i in 0..npieces {
-                // calculate piece length in bytes
-                let rem = info.length % info.piece_length;
-                let len = if i == npieces - 1 && rem != 0 {
-                    rem
-                } else {
-                    info.piece_length
-                };
-
-                pieces.push(Piece::new(i, len as u32));
+            if let Some(info_hash) = magnet.info_hash_v1 {
+                println!("Info Hash: {}", hex::encode(info_hash));
             }
-
-            let (_handshake_msg, mut peer_stream) =
-                handshake_peer(peers[1], &info.hash(), &PEER_ID).await?;
-
-            // recieve [bitfield] message
-            let pmf = PeerMsgFrame::read(&mut peer_stream).await?;
-            if pmf.msg_id != MsgID::Bitfield {
-                todo!()
+            if let Some(info_hash) = magnet.info_hash_v2 {
+                println!("Info Hash v2: {}", hex::encode(info_hash));
             }
-            // send [interested] message
-            let pmf = PeerMsgFrame::new(MsgID::Interested, Vec::new());
-            pmf.write(&mut peer_stream).await?;
-            // recieve unchoke message
-            let pmf = PeerMsgFrame::read(&mut peer_stream).await?;
-            if pmf.msg_id != MsgID::Unchoke {
-                todo!()
+            if let Some(name) = &magnet.name {
+                println!("Name: {}", name);
             }
-
-            for piece in &mut pieces {
-                let mut data = download_piece(piece.index, piece.length, &mut peer_stream).await?;
-                piece.data.append(&mut data);
+            if let Some(length) = magnet.length {
+                println!("Length: {}", length);
+            }
+            for peer in &magnet.peers {
+                println!("Peer: {}", peer);
+            }
+            for url in &magnet.web_seeds {
+                println!("Web Seed: {}", url);
+            }
+            if !magnet.select_only.is_empty() {
+                println!("Select Only: {}", magnet.select_only_list());
             }
-
-            pieces.sort_by(|a, b| a.index.cmp(&b.index));
-            let file = pieces.iter_mut().fold(Vec::new(), |mut acc, p| {
-                acc.append(&mut p.data);
-                acc
-            });
-            fs::write(output_path, file).expect("write downloaded pieces to file");
         }
-        "magnet_parse" => {
-            let magnet_link = args.next().expect("magnet-link");
-            let magnet = Magnet::parse(&magnet_link)?;
-
-            println!("Tracker URL: {}", magnet.tracker_url);
-            println!("Info Hash: {}", hex::encode(magnet.info_hash));
+        "magnet_link" => {
+            let file_path = args.next().context("path to torrent file")?;
+            let torrent = parse_torrent_file(&file_path)?;
+            println!("{}", Magnet::from_torrent(&torrent).to_uri());
         }
         "magnet_handshake" => {
             let magnet_link = args.next().expect("magnet-link");
             let magnet = Magnet::parse(&magnet_link)?;
-            let peers = get_peers(&magnet, &PEER_ID).await?;
+            let peers = get_peers(&magnet, &peer_id()).await?;
 
             // 1. Establish a TCP connection with a peer
             // 2. Send the base handshake message
             // 3. Receive the base handshake message
             let (handshake_msg, mut peer_stream) =
-                handshake_peer(peers[0], &magnet.info_hash, &PEER_ID).await?;
+                handshake_peer(peers[0], &magnet.info_hash(), &peer_id()).await?;
             let peer_id = handshake_msg.peer_id;
 
-            // 4. Receive the bitfield message
-            let pmf = PeerMsgFrame::read(&mut peer_stream).await?;
-            if pmf.msg_id != MsgID::Bitfield {
-                return Err(anyhow::Error::msg("did not recived bitfield msg"));
-            }
-
             if !handshake_msg.is_supporting_extention() {
                 return Err(anyhow::Error::msg("Peer does not support extension"));
             }
 
-            let extension_json = json!({
-                "m": {
-                    "ut_metadata": 1,
-                    "ut_pex": 2,
-                },
-            });
-
-            let extension_value = Value::from_json(&extension_json)?;
-            let mut extension_payload = Vec::new();
-            extension_payload.push(0);
-            extension_payload.append(&mut extension_value.encode());
-
-            // 5. Send Extension Handshake Msg
-            let pmf = PeerMsgFrame::new(MsgID::Extended, extension_payload);
-            pmf.write(&mut peer_stream).await?;
-
-            // 6. Receive Extension Handshake Msg
-            let pmf = PeerMsgFrame::read(&mut peer_stream).await?;
-            let extension_msg = Value::decode(&pmf.payload[1..])?;
-            let ut_metadata_id = extension_msg.0.to_json()["m"]["ut_metadata"]
-                .clone()
-                .as_i64()
-                .unwrap() as u32;
+            let extension_handshake = extension_handshake(&mut peer_stream).await?;
+            let ut_metadata_id = extension_handshake
+                .id("ut_metadata")
+                .context("peer does not support ut_metadata")?;
 
             println!("Peer ID: {}", hex::encode(peer_id));
             println!("Peer Metadata Extension ID: {}", ut_metadata_id);
@@ -272,323 +239,441 @@ async fn main() -> anyhow::Result<()> {
 
             let piece_index = piece_index.parse::<u32>().expect("piece index must be u32");
             let torrent = get_torrent_using_magnet(&magnet_link).await?;
-            let peers = get_peers(&torrent, &PEER_ID).await?;
+            let peers = get_peers(&torrent, &peer_id()).await?;
             let info = &torrent.info;
 
-            let mut peer_stream = None;
-            for i in 0..peers.len() {
-                let (_handshake_msg, mut stream) =
-                    handshake_peer(peers[i], &info.hash(), &PEER_ID).await?;
-
-                // recieve [bitfield] message
-                let pmf = PeerMsgFrame::read(&mut stream).await?;
-                if pmf.msg_id != MsgID::Bitfield {
-                    eprintln!("Didn't recieve unchocke msgid, got {:?}", pmf.msg_id);
-                    continue;
-                }
-
-                // send [interested] message
-                let pmf = PeerMsgFrame::new(MsgID::Interested, Vec::new());
-                pmf.write(&mut stream).await?;
-
-                // TODO: peer replie with unchoke message after this Extended Msg read. find out why?
-                let pmf = PeerMsgFrame::read(&mut stream).await?;
-
-                // recieve unchoke message
-                let pmf = PeerMsgFrame::read(&mut stream).await?;
-                if pmf.msg_id != MsgID::Unchoke {
-                    eprintln!("Didn't recieve unchocke msgid, got {:?}", pmf.msg_id);
-                    continue;
-                }
-
-                peer_stream = Some(stream);
-                break;
-            }
-
-            let mut peer_stream = if let Some(stream) = peer_stream {
-                stream
-            } else {
-                return Err(anyhow::Error::msg("Could not connect to any peer"));
-            };
-
-            let npieces = info.pieces.len() as u32;
-            let rem = info.length % info.piece_length;
-            let piece_len = if piece_index == npieces - 1 && rem != 0 {
-                rem
-            } else {
-                info.piece_length
-            };
+            let mut peer_stream = connect_for_piece(&peers, info, piece_index).await?;
 
-            let piece = download_piece(piece_index, piece_len, &mut peer_stream).await?;
-            fs::write(output_path, piece).expect("write piece to file");
+            let mut piece = PartialPiece::new(piece_index, info.piece_len(piece_index));
+            download_piece(&mut piece, &mut peer_stream, |_| {}).await?;
+            fs::write(output_path, piece.data()).expect("write piece to file");
         }
         "magnet_download" => {
-            let _ = args.next().context("expected -o")?;
-            let output_path = args.next().context("get output path")?;
-            let magnet_link = args.next().context("get torrent file path")?;
+            // magnet_download -o <output> [--min-requests <n>] [--max-requests <n>]
+            //                 [--files <selection>] <magnet link>
+            let DownloadArgs {
+                output_path,
+                pipeline_config,
+                selection,
+                source,
+            } = DownloadArgs::parse(args)?;
+            let magnet_link = source.context("get magnet link")?;
 
-            let torrent = get_torrent_using_magnet(&magnet_link).await?;
-            let peers = get_peers(&torrent, &PEER_ID).await?;
-            let info = &torrent.info;
-
-            // connect to a peer
-            let mut peer_stream = None;
-            for i in 0..peers.len() {
-                let (_handshake_msg, mut stream) =
-                    handshake_peer(peers[i], &info.hash(), &PEER_ID).await?;
-
-                // recieve [bitfield] message
-                let pmf = PeerMsgFrame::read(&mut stream).await?;
-                if pmf.msg_id != MsgID::Bitfield {
-                    eprintln!("Didn't recieve unchocke msgid, got {:?}", pmf.msg_id);
-                    continue;
+            let magnet = Magnet::parse(&magnet_link)?;
+            let metadata = fetch_metadata(&magnet).await?;
+            let torrent = magnet_torrent(&magnet, &metadata)?;
+            let peers = magnet_download_peers(&magnet, &torrent).await?;
+
+            let storage = Storage::new(&output_path, &torrent.info);
+            let nfiles = storage.files.len();
+            // --files takes precedence over the link's `so`
+            let priorities = match selection {
+                Some(selection) => Some(parse_file_priorities(&selection, nfiles)?),
+                None if !magnet.select_only.is_empty() => Some(
+                    (0..nfiles)
+                        .map(|i| {
+                            if magnet.is_selected(i) {
+                                FilePriority::Normal
+                            } else {
+                                FilePriority::Skip
+                            }
+                        })
+                        .collect(),
+                ),
+                None => None,
+            };
+            let mut download = Download::new(torrent, storage, peer_id());
+            download.pipeline_config = pipeline_config;
+            if let Some(priorities) = priorities {
+                download.set_file_priorities(&priorities)?;
+            }
+            Arc::new(download).run(peers).await?;
+        }
+        "magnet_to_torrent" => {
+            // magnet_to_torrent -o <output> <magnet link>
+            let mut output_path = None;
+            let mut source = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-o" => output_path = Some(args.next().context("get output path")?),
+                    _ => source = Some(arg),
                 }
+            }
+            let output_path = output_path.context("expected -o")?;
+            let magnet = Magnet::parse(&source.context("get magnet link")?)?;
 
-                // send [interested] message
-                let pmf = PeerMsgFrame::new(MsgID::Interested, Vec::new());
-                pmf.write(&mut stream).await?;
-
-                // TODO: peer replie with unchoke message after this Extended Msg read. find out why?
-                let pmf = PeerMsgFrame::read(&mut stream).await?;
+            let metadata = fetch_metadata(&magnet).await?;
+            let torrent = magnet_torrent(&magnet, &metadata)?;
+            fs::write(&output_path, torrent.encode_with_info(&metadata))
+                .context("write torrent file")?;
 
-                // recieve unchoke message
-                let pmf = PeerMsgFrame::read(&mut stream).await?;
-                if pmf.msg_id != MsgID::Unchoke {
-                    eprintln!("Didn't recieve unchocke msgid, got {:?}", pmf.msg_id);
-                    continue;
+            println!("Info Hash: {}", hex::encode(magnet.info_hash()));
+        }
+        "create" => {
+            // create [-o <output>] [-a <url>[,<url>...]]... [-w <web seed>]... [-l <piece length>]
+            //        [-c <comment>] [-s <source>] [--private] [--pad] [--no-date] <path>
+            let mut output_path = None;
+            let mut opts = CreateOptions::default();
+            let mut path = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-o" => output_path = Some(args.next().context("get output path")?),
+                    "-a" => {
+                        let tier = args.next().context("get announce url")?;
+                        opts.trackers
+                            .push(tier.split(',').map(str::to_string).collect());
+                    }
+                    "-w" => opts
+                        .web_seeds
+                        .push(args.next().context("get web seed url")?),
+                    "-l" => {
+                        let piece_length = args.next().context("get piece length")?;
+                        opts.piece_length =
+                            Some(piece_length.parse().context("piece length must be u32")?);
+                    }
+                    "-c" => opts.comment = Some(args.next().context("get comment")?),
+                    "-s" => opts.source = Some(args.next().context("get source tag")?),
+                    "--private" => opts.private = true,
+                    "--pad" => opts.pad = true,
+                    "--no-date" => opts.creation_date = false,
+                    _ => path = Some(arg),
                 }
-
-                peer_stream = Some(stream);
-                break;
             }
+            let path = path.context("get path of file or directory")?;
 
-            let mut peer_stream = if let Some(stream) = peer_stream {
-                stream
-            } else {
-                return Err(anyhow::Error::msg("Could not connect to any peer"));
-            };
-
-            // calculate peices
-            struct Piece {
-                index: u32,
-                length: u32,
-                data: Vec<u8>,
-            }
+            let torrent = create_torrent(&path, &opts)?;
+            let output_path =
+                output_path.unwrap_or_else(|| format!("{}.torrent", torrent.info.name));
+            fs::write(&output_path, torrent.to_value().encode()).context("write torrent file")?;
 
-            impl Piece {
-                fn new(index: u32, length: u32) -> Self {
-                    Self {
-                        index,
-                        length,
-                        data: Vec::new(),
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()));
+        }
+        "seed" => {
+            // seed [--port <port>] [--upload-slots <n>] [--optimistic-slots <n>] <torrent> <path>
+            let mut port = DEFAULT_PORT;
+            let mut choker_config = ChokerConfig::default();
+            let mut positional = Vec::new();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--port" => {
+                        let p = args.next().context("get port")?;
+                        port = p.parse().context("port must be u16")?;
+                    }
+                    "--upload-slots" => {
+                        let n = args.next().context("get upload slots")?;
+                        choker_config.unchoke_slots = n.parse().context("slots must be usize")?;
                     }
+                    "--optimistic-slots" => {
+                        let n = args.next().context("get optimistic slots")?;
+                        choker_config.optimistic_slots =
+                            n.parse().context("slots must be usize")?;
+                    }
+                    _ => positional.push(arg),
                 }
             }
+            let mut positional = positional.into_iter();
+            let torrent_path = positional.next().context("get torrent file path")?;
+            let data_path = positional.next().context("get path of torrent data")?;
 
-            let npieces = info.pieces.len() as u32;
-            let mut pieces = Vec::new();
-            for i in 0..npieces {
-                // calculate piece length in bytes
-                let rem = info.length % info.piece_length;
-                let len = if i == npieces - 1 && rem != 0 {
-                    rem
-                } else {
-                    info.piece_length
-                };
-
-                pieces.push(Piece::new(i, len as u32));
-            }
-
-            // dowload peices from peer
-            for piece in &mut pieces {
-                let mut data = download_piece(piece.index, piece.length, &mut peer_stream).await?;
-                piece.data.append(&mut data);
-            }
+            let torrent = parse_torrent_file(&torrent_path).context("parse torrent file")?;
+            let seeder = Arc::new(Seeder::new(peer_id(), choker_config));
+            let seed_torrent = seeder.add(torrent, data_path);
+            println!(
+                "Verified {}/{} pieces",
+                seed_torrent.have.count(),
+                seed_torrent.have.len()
+            );
+            let listener = TcpListener::bind(listen_address(port))
+                .await
+                .context("bind listener")?;
+            println!("Listening on port {}", port);
+            let listening = tokio::spawn(seeder.listen(listener));
 
-            // sort peices and save to file
-            pieces.sort_by(|a, b| a.index.cmp(&b.index));
-            let file = pieces.iter_mut().fold(Vec::new(), |mut acc, p| {
-                acc.append(&mut p.data);
-                acc
-            });
-            fs::write(output_path, file).expect("write downloaded pieces to file");
+            let stop = async {
+                _ = tokio::signal::ctrl_c().await;
+            };
+            seed_torrent
+                .announce_periodically(peer_id(), port, stop)
+                .await?;
+            listening.abort();
         }
         _ => {}
     }
     Ok(())
 }
 
-async fn get_torrent_using_magnet(magnet_link: &str) -> anyhow::Result<Torrent> {
-    let magnet = Magnet::parse(&magnet_link)?;
-    let peers = get_peers(&magnet, &PEER_ID).await?;
-
-    // 1. Establish a TCP connection with a peer
-    // 2. Send the base handshake message
-    // 3. Receive the base handshake message
-    let (handshake_msg, mut peer_stream) =
-        handshake_peer(peers[0], &magnet.info_hash, &PEER_ID).await?;
-    let peer_id = handshake_msg.peer_id;
-
-    // 4. Receive the bitfield message
-    let pmf = PeerMsgFrame::read(&mut peer_stream).await?;
-    if pmf.msg_id != MsgID::Bitfield {
-        return Err(anyhow::Error::msg("did not recived bitfield msg"));
-    }
+/// Options of `download` and `magnet_download`.
+struct DownloadArgs {
+    output_path: String,
+    pipeline_config: PipelineConfig,
+    // e.g. `0,2,4-6:high,7:low`, files left out are skipped
+    selection: Option<String>,
+    // torrent file path or magnet link
+    source: Option<String>,
+}
 
-    if !handshake_msg.is_supporting_extention() {
-        return Err(anyhow::Error::msg("Peer does not support extension"));
+impl DownloadArgs {
+    fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut output_path = None;
+        let mut pipeline_config = PipelineConfig::default();
+        let mut selection = None;
+        let mut source = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" => output_path = Some(args.next().context("get output path")?),
+                "--files" | "--select" => {
+                    selection = Some(args.next().context("get file selection")?)
+                }
+                "--min-requests" => {
+                    let n = args.next().context("get minimum outstanding requests")?;
+                    pipeline_config.min_requests = n.parse().context("requests must be usize")?;
+                }
+                "--max-requests" => {
+                    let n = args.next().context("get maximum outstanding requests")?;
+                    pipeline_config.max_requests = n.parse().context("requests must be usize")?;
+                }
+                _ => source = Some(arg),
+            }
+        }
+        Ok(Self {
+            output_path: output_path.context("expected -o")?,
+            pipeline_config,
+            selection,
+            source,
+        })
     }
+}
 
-    let extension_json = json!({
-        "m": {
-            "ut_metadata": 1,
-            "ut_pex": 2,
-        },
-    });
-
-    let extension_value = Value::from_json(&extension_json)?;
-    let mut extension_payload = Vec::new();
-    extension_payload.push(0);
-    extension_payload.append(&mut extension_value.encode());
-
-    // 5. Send Extension Handshake Msg
-    let pmf = PeerMsgFrame::new(MsgID::Extended, extension_payload);
-    pmf.write(&mut peer_stream).await?;
-
-    // 6. Receive Extension Handshake Msg
-    let pmf = PeerMsgFrame::read(&mut peer_stream).await?;
-    let extension_msg = Value::decode(&pmf.payload[1..])?;
-    let ut_metadata_id: u8 = extension_msg.0.to_json()["m"]["ut_metadata"]
-        .clone()
-        .as_i64()
-        .unwrap() as u8;
-
-    // 7. request info using Metadata extension Messages
-    // {  msg_type will be 0 since this is a request message
-    //    piece is the zero-based piece index of the metadata being requested
-    //    Since we're only requesting one piece in this challenge, this will always be 0
-    // }
-    let metadata_request_msg = json!({
-        "msg_type": 0,
-        "piece": 0,
-    });
-
-    let value = Value::from_json(&metadata_request_msg)?;
-    let mut payload = Vec::new();
-    payload.push(ut_metadata_id);
-    payload.append(&mut value.encode());
+async fn get_torrent_using_magnet(magnet_link: &str) -> anyhow::Result<Torrent> {
+    let magnet = Magnet::parse(magnet_link)?;
+    let metadata = fetch_metadata(&magnet).await?;
+    magnet_torrent(&magnet, &metadata)
+}
 
-    // 8. Request MetaInfo using Metadata Extension Msg
-    let pmf = PeerMsgFrame::new(MsgID::Extended, payload);
-    pmf.write(&mut peer_stream).await?;
+/// Fetch the info dictionary of `magnet` from the swarm, checked against the info hash.
+async fn fetch_metadata(magnet: &Magnet) -> anyhow::Result<Bytes> {
+    magnet.check_fetchable()?;
+    let peers = magnet_peers(magnet).await?;
+    let fetch = Arc::new(MetadataFetch::new(magnet.info_hash(), peer_id()));
+    fetch.run(peers).await
+}
 
-    // 9. Read MetaInfo
-    let pmf = PeerMsgFrame::read(&mut peer_stream).await?;
-    let (msg, rest) = Value::decode(&pmf.payload[1..])?;
-    let metadata_size: usize = msg.to_json()["total_size"].take().as_u64().unwrap() as usize;
-    let (metadata, _rest) = Value::decode(rest)?;
-    let meta_info = Info::from_value(&metadata)?;
+/// The torrent of `magnet` given its bencoded info dictionary.
+fn magnet_torrent(magnet: &Magnet, metadata: &[u8]) -> anyhow::Result<Torrent> {
+    let (value, _rest) = Value::decode(metadata)?;
+    let mut meta_info = Info::from_value(&value)?;
+    meta_info.raw = Some(metadata.to_vec());
 
     let torrent = Torrent {
         announce: magnet.announce().as_bytes().to_vec(),
+        // every tracker of the link in a single tier, tried in order
+        announce_list: if magnet.trackers.len() > 1 {
+            vec![magnet.trackers.clone()]
+        } else {
+            Vec::new()
+        },
+        comment: None,
+        created_by: None,
+        creation_date: None,
+        url_list: magnet.web_seeds.clone(),
         info: meta_info,
+        // magnet links carry no piece layers, peers would have to send them
+        piece_layers: BTreeMap::new(),
     };
+    torrent.info.check_piece_layers(&torrent.piece_layers)?;
 
     Ok(torrent)
 }
 
-async fn download_piece(
-    piece_index: u32,
-    piece_len: u32,
-    peer_stream: &mut TcpStream,
-) -> anyhow::Result<Vec<u8>> {
-    const SIXTEEN_KB: u32 = 16 * 1024;
-
-    struct Block {
-        index: u32,
-        begin: u32,
-        length: u32,
-        data: Vec<u8>,
+/// Peers to download `torrent` from. Torrents with web seeds can do without
+/// a tracker, so a failing one is only reported.
+async fn download_peers(torrent: &Torrent) -> anyhow::Result<Vec<SocketAddrV4>> {
+    if torrent.url_list.is_empty() {
+        return get_peers(torrent, &peer_id()).await;
     }
-
-    impl Block {
-        fn new(index: u32, begin: u32, length: u32) -> Self {
-            Self {
-                index,
-                begin,
-                length,
-                data: Vec::new(),
-            }
+    if torrent.announce.is_empty() {
+        return Ok(Vec::new());
+    }
+    match get_peers(torrent, &peer_id()).await {
+        Ok(peers) => Ok(peers),
+        Err(e) => {
+            eprintln!("tracker {}: {:#}", torrent.announce(), e);
+            Ok(Vec::new())
         }
     }
+}
 
-    let nblocks = if piece_len % SIXTEEN_KB == 0 {
-        piece_len / SIXTEEN_KB
-    } else {
-        piece_len / SIXTEEN_KB + 1
-    };
+/// Peers of a magnet link: those of every tracker and the `x.pe` hints.
+async fn magnet_peers(magnet: &Magnet) -> anyhow::Result<Vec<SocketAddrV4>> {
+    let mut peers = Vec::new();
+    for tracker in &magnet.trackers {
+        let request = TrackerRequest {
+            info_hash: magnet.info_hash(),
+            port: DEFAULT_PORT,
+            peer_id: peer_id(),
+            uploaded: 0,
+            downloaded: 0,
+            left: magnet.length(),
+            compact: 1,
+            event: None,
+        };
+        match announce(tracker, &request).await {
+            Ok(response) => peers.extend(response.peer_addresses()),
+            Err(e) => eprintln!("tracker {}: {:#}", tracker, e),
+        }
+    }
+    peers.extend(hint_peers(magnet).await);
+    let mut seen = HashSet::new();
+    peers.retain(|peer| seen.insert(*peer));
+    if peers.is_empty() {
+        return Err(anyhow::Error::msg("magnet link yields no peers"));
+    }
+    Ok(peers)
+}
 
-    let mut blocks = Vec::<Block>::new();
+/// the IPv4 addresses of the `x.pe` peer hints of `magnet`
+async fn hint_peers(magnet: &Magnet) -> Vec<SocketAddrV4> {
+    let mut peers = Vec::new();
+    for hint in &magnet.peers {
+        match tokio::net::lookup_host(hint.as_str()).await {
+            Ok(addrs) => peers.extend(addrs.filter_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })),
+            Err(e) => eprintln!("peer {}: {:#}", hint, e),
+        }
+    }
+    peers
+}
 
-    for index in 0..nblocks {
-        let begin = SIXTEEN_KB * index;
-        let rem = piece_len % SIXTEEN_KB;
-        let length = if index == nblocks - 1 && rem != 0 {
-            rem
-        } else {
-            SIXTEEN_KB
-        };
-        blocks.push(Block::new(index, begin, length));
+/// Peers to download the torrent of `magnet` from: those of its trackers and
+/// web seeds, and the link's peer hints unless the torrent is private (BEP 27).
+async fn magnet_download_peers(
+    magnet: &Magnet,
+    torrent: &Torrent,
+) -> anyhow::Result<Vec<SocketAddrV4>> {
+    if torrent.info.private || magnet.peers.is_empty() {
+        return download_peers(torrent).await;
     }
+    // the hints may be all there is
+    let mut peers = download_peers(torrent).await.unwrap_or_else(|e| {
+        eprintln!("{:#}", e);
+        Vec::new()
+    });
+    peers.extend(hint_peers(magnet).await);
+    let mut seen = HashSet::new();
+    peers.retain(|peer| seen.insert(*peer));
+    Ok(peers)
+}
 
-    for block_chunk in blocks.chunks_mut(5) {
-        for block in block_chunk.iter() {
-            let mut payload = Vec::new();
-            payload.put_slice(&(piece_index as u32).to_be_bytes());
-            payload.put_slice(&(block.begin as u32).to_be_bytes());
-            payload.put_slice(&(block.length as u32).to_be_bytes());
-            assert_eq!(
-                block.length as u32,
-                u32::from_be_bytes([payload[8], payload[9], payload[10], payload[11]])
-            );
+/// Send our extension handshake announcing `ut_metadata` and read the peer's.
+async fn extension_handshake(peer_stream: &mut TcpStream) -> anyhow::Result<ExtensionHandshake> {
+    let mut extensions = Extensions::new();
+    extensions.register(Box::new(UtMetadata::new()));
+    extensions.handshake_message().write(peer_stream).await?;
 
-            let pmf = PeerMsgFrame::new(MsgID::Request, payload);
-            pmf.write(peer_stream).await?;
-        }
+    // the bitfield and others may come first
+    let payload = read_extended(peer_stream, 0).await?;
+    ExtensionHandshake::decode(&payload)
+}
 
-        for block in block_chunk.iter_mut() {
-            let pmf = PeerMsgFrame::read(peer_stream)
-                .await
-                .context("read message")?;
-
-            let index = u32::from_be_bytes([
-                pmf.payload[0],
-                pmf.payload[1],
-                pmf.payload[2],
-                pmf.payload[3],
-            ]);
-            assert_eq!(index, piece_index);
-            let begin = u32::from_be_bytes([
-                pmf.payload[4],
-                pmf.payload[5],
-                pmf.payload[6],
-                pmf.payload[7],
-            ]);
-            let data = &pmf.payload[8..];
-            assert_eq!(data.len() as u32, block.length);
-            block.data.put_slice(data);
-        }
+/// Connect to the first of `peers` that has piece `piece_index` and unchokes us.
+async fn connect_for_piece(
+    peers: &[SocketAddrV4],
+    info: &Info,
+    piece_index: u32,
+) -> anyhow::Result<TcpStream> {
+    if piece_index as usize >= info.piece_count() {
+        return Err(anyhow::Error::msg(format!(
+            "torrent has only {} pieces",
+            info.piece_count()
+        )));
     }
-    let mut piece = Vec::new();
-    for Block { data, .. } in blocks {
-        piece.put_slice(&data);
+    for peer in peers {
+        let result = async {
+            let (_handshake_msg, mut stream) =
+                handshake_peer(*peer, &info.info_hash(), &peer_id()).await?;
+            let peer_has = request_unchoke(&mut stream, info.piece_count()).await?;
+            if !peer_has.has(piece_index) {
+                return Err(anyhow::Error::msg("peer does not have the piece"));
+            }
+            Ok(stream)
+        }
+        .await;
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => eprintln!("peer {}: {:#}", peer, e),
+        }
     }
-    Ok(piece)
+    Err(anyhow::Error::msg("Could not connect to any peer"))
 }
 
 fn parse_torrent_file(file_path: &str) -> anyhow::Result<Torrent> {
     let file = fs::read(file_path).context("read torrent file")?;
     let decoded_value = Value::decode(&file).context("decode bencode value")?.0;
-    let torrent = Torrent::from_value(&decoded_value).context("parse MetaInfo from value")?;
+    let mut torrent = Torrent::from_value(&decoded_value).context("parse MetaInfo from value")?;
+    // hash the info dictionary as written, not as we would encode it
+    torrent.info.raw = raw_dict_value(&file, b"info")?.map(<[u8]>::to_vec);
     Ok(torrent)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+
+    /// a private torrent whose info dictionary has a key we don't know about
+    const TORRENT: &[u8] = b"d8:announce17:http://t/announce4:infod6:lengthi5e\
+        4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa\
+        7:privatei1e7:unknown3:xyzee";
+
+    #[test]
+    fn hashes_info_dictionaries_as_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.torrent");
+        fs::write(&path, TORRENT).unwrap();
+        let torrent = parse_torrent_file(path.to_str().unwrap()).unwrap();
+
+        let start = TORRENT.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        let raw = &TORRENT[start..TORRENT.len() - 1];
+        assert_eq!(
+            torrent.info.info_hash(),
+            <[u8; 20]>::from(Sha1::digest(raw))
+        );
+        // re-encoding would drop the unknown key and change the hash
+        assert_ne!(torrent.info.to_value().encode(), raw);
+        assert_eq!(torrent.info.encoded(), raw);
+        assert_eq!(torrent.encode_with_info(raw), TORRENT);
+    }
+
+    #[test]
+    fn keeps_the_private_flag() {
+        let (value, _) = Value::decode(TORRENT).unwrap();
+        let torrent = Torrent::from_value(&value).unwrap();
+        assert!(torrent.info.private);
+        let encoded = torrent.info.to_value().encode();
+        let reparsed = Info::from_value(&Value::decode(&encoded).unwrap().0).unwrap();
+        assert!(reparsed.private);
+        assert!(encoded.windows(12).any(|w| w == b"7:privatei1e"));
+    }
+
+    #[tokio::test]
+    async fn skips_peer_hints_for_private_torrents() {
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{}&x.pe=127.0.0.1:51413",
+            "ab".repeat(20)
+        ))
+        .unwrap();
+        let (value, _) = Value::decode(TORRENT).unwrap();
+        let mut torrent = Torrent::from_value(&value).unwrap();
+        // no tracker to ask, only the hint is left
+        torrent.announce.clear();
+
+        let hint: SocketAddrV4 = "127.0.0.1:51413".parse().unwrap();
+        torrent.info.private = false;
+        let peers = magnet_download_peers(&magnet, &torrent).await.unwrap();
+        assert_eq!(peers, [hint]);
+        torrent.info.private = true;
+        assert!(magnet_download_peers(&magnet, &torrent).await.is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::sha256::{sha256, Sha256};
+
+/// bytes hashed into each leaf of a v2 file's merkle tree
+pub const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
+
+/// parent of two nodes of a merkle tree
+pub fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize()
+}
+
+/// Root of the tree over `nodes`, filled up with `pad` to `width` nodes, a
+/// power of two.
+pub fn merkle_root(nodes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    debug_assert!(width.is_power_of_two() && nodes.len() <= width);
+    let mut layer = nodes.to_vec();
+    layer.resize(width, pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+/// root of a subtree of `leaves` zero leaves, what the piece layer is padded with
+pub fn pad_hash(leaves: usize) -> [u8; 32] {
+    merkle_root(&[], leaves, [0; 32])
+}
+
+/// leaf hashes of `data`, one per 16 KiB block, the last block may be shorter
+pub fn leaf_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(MERKLE_BLOCK_SIZE).map(sha256).collect()
+}
+
+/// Hash of a piece of a file larger than one piece, as found in its piece
+/// layer. Leaves past the end of the file are zero.
+pub fn piece_hash(data: &[u8], piece_length: u32) -> [u8; 32] {
+    let width = piece_length as usize / MERKLE_BLOCK_SIZE;
+    merkle_root(&leaf_hashes(data), width, [0; 32])
+}
+
+/// `pieces root` of a file no larger than one piece, given its contents
+pub fn small_file_root(data: &[u8]) -> [u8; 32] {
+    let leaves = leaf_hashes(data);
+    merkle_root(&leaves, leaves.len().next_power_of_two(), [0; 32])
+}
+
+/// `pieces root` of a file larger than one piece, given its piece layer
+pub fn layer_root(layer: &[[u8; 32]], piece_length: u32) -> [u8; 32] {
+    let pad = pad_hash(piece_length as usize / MERKLE_BLOCK_SIZE);
+    merkle_root(layer, layer.len().next_power_of_two(), pad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex32(s: &str) -> [u8; 32] {
+        hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    /// content of the 100000 byte file of the test torrent in torrent.rs
+    fn big_file() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn pads_with_zero_subtrees() {
+        assert_eq!(pad_hash(1), [0; 32]);
+        assert_eq!(pad_hash(2), sha256(&[0; 64]));
+        assert_eq!(pad_hash(4), hash_pair(&pad_hash(2), &pad_hash(2)));
+    }
+
+    #[test]
+    fn roots_of_small_files() {
+        let data = b"hello v2\n".repeat(100);
+        assert_eq!(small_file_root(&data), sha256(&data));
+        assert_eq!(
+            small_file_root(&data),
+            hex32("7f76d7d4d2be22143bfcedbe16b6e06f91c20ba17178edeb5c3a02d31734dcf3")
+        );
+        // three leaves, the fourth is zero
+        assert_eq!(
+            small_file_root(&[0; 40_000]),
+            hex32("c222145b40178f84605e5c7bf86515e2c69ce4d02ef587e4b35d5060542f27b3")
+        );
+    }
+
+    #[test]
+    fn piece_layer_and_root_of_a_large_file() {
+        let data = big_file();
+        let layer: Vec<[u8; 32]> = data
+            .chunks(32768)
+            .map(|piece| piece_hash(piece, 32768))
+            .collect();
+        assert_eq!(
+            layer,
+            [
+                hex32("96a0dd5f00f7441893e63bf62c03d8d9449214aaacd9ce3e603b58125984d3e3"),
+                hex32("6c09ee7eb144bbb8e451e1c7d03eb252f1d23f6e8fbd5834f79138b2bb52be0d"),
+                hex32("a3be724d4e89b794a1e396c07410e57475b9387eecab5227f7842cbedfc2a54d"),
+                hex32("b6e7ec1b1f3851f428250f404f1faf111fe1f2a35ae0f0c9f998ff26df802015"),
+            ]
+        );
+        let root = hex32("33e2d18ace9db35babb7c73b9d83d7c5b5d7dc18663225ca17ac3d3e9cb6a306");
+        assert_eq!(layer_root(&layer, 32768), root);
+        // the same tree built from the 16 KiB leaves, padded with zero leaves
+        let leaves = leaf_hashes(&data);
+        assert_eq!(leaves.len(), 7);
+        assert_eq!(merkle_root(&leaves, 8, [0; 32]), root);
+    }
+}
@@ -0,0 +1,774 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error};
+use bytes::{BufMut, Bytes};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+
+use crate::download::MAX_PEERS;
+use crate::extension::*;
+use crate::peer::*;
+use crate::value::Value;
+
+/// metadata is exchanged in pieces of this size, only the last may be shorter
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// info dictionaries larger than this are refused rather than fetched
+pub const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+/// metadata pieces requested from one peer at a time
+pub const METADATA_REQUESTS_PER_PEER: usize = 2;
+/// peers that reject this many requests are given up on
+pub const MAX_METADATA_REJECTS: usize = 3;
+/// times the assembled metadata may fail the hash check before giving up
+pub const MAX_METADATA_ATTEMPTS: usize = 3;
+/// window over which metadata requests we serve are rate limited
+pub const METADATA_SERVE_WINDOW: Duration = Duration::from_secs(60);
+/// give up on a peer that doesn't send anything for this long
+pub const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A message of the metadata extension, BEP 9
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: u32,
+    },
+    Data {
+        piece: u32,
+        total_size: usize,
+        data: Bytes,
+    },
+    /// the sender doesn't have the metadata or won't send it now
+    Reject {
+        piece: u32,
+    },
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Bytes {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request { piece } => (0, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject { piece } => (2, piece),
+        };
+        let mut dict = BTreeMap::new();
+        dict.insert(b"msg_type"[..].to_vec(), Value::Integer(msg_type));
+        dict.insert(b"piece"[..].to_vec(), Value::Integer(*piece as isize));
+        if let MetadataMessage::Data { total_size, .. } = self {
+            dict.insert(
+                b"total_size"[..].to_vec(),
+                Value::Integer(*total_size as isize),
+            );
+        }
+
+        let mut payload = Value::Dict(dict).encode();
+        // the piece data follows the dictionary
+        if let MetadataMessage::Data { data, .. } = self {
+            payload.put_slice(data);
+        }
+        payload.into()
+    }
+
+    pub fn decode(payload: &[u8]) -> anyhow::Result<Self> {
+        let (value, rest) = Value::decode(payload).context("decode metadata message")?;
+        let Value::Dict(dict) = value else {
+            return Err(Error::msg("metadata message must be a dictionary"));
+        };
+        let integer = |key: &str| match dict.get(key.as_bytes()) {
+            Some(Value::Integer(i)) if *i >= 0 => Ok(*i as usize),
+            _ => Err(Error::msg(format!("metadata message without {}", key))),
+        };
+
+        let piece = u32::try_from(integer("piece")?).context("metadata piece out of range")?;
+        match integer("msg_type")? {
+            0 => Ok(MetadataMessage::Request { piece }),
+            1 => Ok(MetadataMessage::Data {
+                piece,
+                total_size: integer("total_size")?,
+                data: Bytes::copy_from_slice(rest),
+            }),
+            2 => Ok(MetadataMessage::Reject { piece }),
+            msg_type => Err(Error::msg(format!(
+                "unknown metadata message type {}",
+                msg_type
+            ))),
+        }
+    }
+}
+
+/// number of metadata pieces an info dictionary of `size` bytes is split into
+pub fn metadata_pieces(size: usize) -> usize {
+    size.div_ceil(METADATA_PIECE_SIZE)
+}
+
+/// identifies one peer connection of a metadata fetch
+pub type FetchWorker = usize;
+
+#[derive(Debug, Clone)]
+enum MetadataPiece {
+    Missing,
+    Requested(FetchWorker),
+    Received(Bytes),
+}
+
+#[derive(Debug, Default)]
+struct FetchState {
+    /// size of the info dictionary, the one announced by most connected peers
+    size: Option<usize>,
+    /// sizes announced by each peer, only peers agreeing with `size` are asked
+    announced: HashMap<FetchWorker, usize>,
+    pieces: Vec<MetadataPiece>,
+    rejects: HashMap<FetchWorker, usize>,
+    /// times the assembled metadata didn't match the info hash
+    failed_attempts: usize,
+    /// sizes whose assembled metadata didn't match the info hash
+    mismatched: HashSet<usize>,
+    /// the verified info dictionary
+    info: Option<Bytes>,
+}
+
+impl FetchState {
+    /// Use the size announced by most peers, preferring sizes that haven't
+    /// failed verification and keeping the current one on ties. The pieces are
+    /// reset when the size changes and dropped once no peer announces it.
+    fn pick_size(&mut self) {
+        let mut counts: HashMap<usize, usize> = HashMap::new();
+        for size in self.announced.values() {
+            *counts.entry(*size).or_default() += 1;
+        }
+        let rank = |size: usize, n: usize| (!self.mismatched.contains(&size), n);
+        let best = counts
+            .iter()
+            .map(|(&size, &n)| (rank(size, n), size))
+            .max_by_key(|&(rank, size)| (rank, std::cmp::Reverse(size)));
+        let current = self
+            .size
+            .and_then(|size| counts.get(&size).map(|&n| rank(size, n)));
+        match best {
+            None => {
+                self.size = None;
+                self.pieces.clear();
+            }
+            Some((best, _)) if current >= Some(best) => {}
+            Some((_, size)) => {
+                self.size = Some(size);
+                self.pieces = vec![MetadataPiece::Missing; metadata_pieces(size)];
+            }
+        }
+    }
+}
+
+/// Hands a worker's pieces to the other peers when dropped, also when its
+/// task panicked.
+struct FetchWorkerGuard<'a> {
+    fetch: &'a MetadataFetch,
+    worker: FetchWorker,
+}
+
+impl Drop for FetchWorkerGuard<'_> {
+    fn drop(&mut self) {
+        if !self.fetch.state.is_poisoned() {
+            self.fetch.release_all(self.worker);
+        }
+    }
+}
+
+/// Fetches the info dictionary of a magnet link from the peers of the swarm.
+///
+/// The metadata is split into METADATA_PIECE_SIZE pieces requested from
+/// several peers at once. Once every piece arrived the assembled dictionary is
+/// checked against the info hash; if it doesn't match because a peer sent
+/// garbage every piece is fetched again, up to MAX_METADATA_ATTEMPTS times.
+pub struct MetadataFetch {
+    pub info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    state: Mutex<FetchState>,
+    /// signalled when pieces are received or given back
+    progress: Notify,
+    next_worker: AtomicUsize,
+    pub connection_config: ConnectionConfig,
+}
+
+impl MetadataFetch {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+            state: Mutex::new(FetchState::default()),
+            progress: Notify::new(),
+            next_worker: AtomicUsize::new(0),
+            connection_config: ConnectionConfig::default(),
+        }
+    }
+
+    /// the verified info dictionary, once fetched
+    pub fn info(&self) -> Option<Bytes> {
+        self.state.lock().unwrap().info.clone()
+    }
+
+    /// Fetch from `peers`, keeping up to MAX_PEERS connections, until the info
+    /// dictionary is verified.
+    pub async fn run(self: Arc<Self>, peers: Vec<SocketAddrV4>) -> anyhow::Result<Bytes> {
+        let mut peers = peers.into_iter();
+        let mut workers = JoinSet::new();
+        for addr in peers.by_ref().take(MAX_PEERS) {
+            workers.spawn(self.clone().run_peer(addr));
+        }
+
+        while let Some(result) = workers.join_next().await {
+            if let Some(info) = self.info() {
+                workers.abort_all();
+                return Ok(info);
+            }
+            if self.state.lock().unwrap().failed_attempts >= MAX_METADATA_ATTEMPTS {
+                workers.abort_all();
+                return Err(Error::msg(
+                    "metadata from peers doesn't match the info hash",
+                ));
+            }
+            match result {
+                Ok(Err(e)) => eprintln!("{:#}", e),
+                // one misbehaving peer must not take the fetch down with it
+                Err(e) if e.is_panic() => eprintln!("{}", e),
+                _ => {}
+            }
+            if let Some(addr) = peers.next() {
+                workers.spawn(self.clone().run_peer(addr));
+            }
+        }
+
+        self.info()
+            .context("ran out of peers before the metadata was fetched")
+    }
+
+    async fn run_peer(self: Arc<Self>, addr: SocketAddrV4) -> anyhow::Result<()> {
+        let worker = self.next_worker.fetch_add(1, Ordering::Relaxed);
+        let _guard = FetchWorkerGuard {
+            fetch: &self,
+            worker,
+        };
+        self.fetch_from(worker, addr)
+            .await
+            .with_context(|| format!("peer {}", addr))
+    }
+
+    async fn fetch_from(
+        self: &Arc<Self>,
+        worker: FetchWorker,
+        addr: SocketAddrV4,
+    ) -> anyhow::Result<()> {
+        let (handshake_msg, stream) = connect_peer(
+            addr.into(),
+            &self.info_hash,
+            &self.peer_id,
+            &self.connection_config,
+        )
+        .await?;
+        if !handshake_msg.is_supporting_extention() {
+            return Err(Error::msg("peer does not support extensions"));
+        }
+
+        let mut stream = BufReader::new(stream);
+        let mut extensions = Extensions::new();
+        extensions.register(Box::new(UtMetadata::fetching(worker, self.clone())));
+        extensions.handshake_message().write(&mut stream).await?;
+
+        let mut last_received = Instant::now();
+        loop {
+            if self.info().is_some()
+                || self.state.lock().unwrap().failed_attempts >= MAX_METADATA_ATTEMPTS
+            {
+                return Ok(());
+            }
+            if self.rejects(worker) >= MAX_METADATA_REJECTS {
+                return Err(Error::msg("peer keeps rejecting metadata requests"));
+            }
+
+            if let Some(id) = extensions.remote_id("ut_metadata") {
+                while self.outstanding(worker) < METADATA_REQUESTS_PER_PEER {
+                    let Some(piece) = self.claim(worker) else {
+                        break;
+                    };
+                    PeerMessage::Extended {
+                        id,
+                        payload: MetadataMessage::Request { piece }.encode(),
+                    }
+                    .write(&mut stream)
+                    .await?;
+                }
+            } else if extensions.peer_handshake().is_some() {
+                return Err(Error::msg("peer does not support ut_metadata"));
+            }
+
+            tokio::select! {
+                biased;
+                // wait for a message without reading part of it if another branch wins
+                _ = stream.fill_buf() => {}
+                // pieces requested elsewhere may be given back
+                _ = self.progress.notified() => continue,
+                _ = tokio::time::sleep_until((last_received + METADATA_TIMEOUT).into()) => {
+                    return Err(Error::msg("peer stopped responding"));
+                }
+            }
+
+            let msg = PeerMessage::read_limited(&mut stream, self.connection_config.max_frame_len)
+                .await
+                .context("read message")?;
+            last_received = Instant::now();
+            if let PeerMessage::Extended { id, payload } = msg {
+                for reply in extensions.dispatch(id, &payload)? {
+                    reply.write(&mut stream).await?;
+                }
+            }
+        }
+    }
+
+    /// `worker` announced the size of the info dictionary. The size most peers
+    /// agree on is used, peers announcing another one aren't asked for pieces.
+    fn set_size(&self, worker: FetchWorker, size: usize) -> anyhow::Result<()> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(Error::msg(format!("invalid metadata size {}", size)));
+        }
+        let mut state = self.state.lock().unwrap();
+        state.announced.insert(worker, size);
+        state.pick_size();
+        drop(state);
+        self.progress.notify_waiters();
+        Ok(())
+    }
+
+    /// claim a missing piece for `worker` to request
+    fn claim(&self, worker: FetchWorker) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        if state.size.is_none() || state.announced.get(&worker) != state.size.as_ref() {
+            return None;
+        }
+        let index = state
+            .pieces
+            .iter()
+            .position(|p| matches!(p, MetadataPiece::Missing))?;
+        state.pieces[index] = MetadataPiece::Requested(worker);
+        Some(index as u32)
+    }
+
+    /// pieces requested by `worker` that haven't arrived yet
+    fn outstanding(&self, worker: FetchWorker) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .pieces
+            .iter()
+            .filter(|p| matches!(p, MetadataPiece::Requested(w) if *w == worker))
+            .count()
+    }
+
+    fn rejects(&self, worker: FetchWorker) -> usize {
+        let state = self.state.lock().unwrap();
+        state.rejects.get(&worker).copied().unwrap_or(0)
+    }
+
+    /// `worker` rejected our request for `piece`, let another peer have it
+    fn reject(&self, worker: FetchWorker, piece: u32) {
+        let mut state = self.state.lock().unwrap();
+        *state.rejects.entry(worker).or_default() += 1;
+        if let Some(p) = state.pieces.get_mut(piece as usize) {
+            if matches!(p, MetadataPiece::Requested(w) if *w == worker) {
+                *p = MetadataPiece::Missing;
+            }
+        }
+        drop(state);
+        self.progress.notify_waiters();
+    }
+
+    /// the connection of `worker` ended, hand its pieces to the other peers
+    fn release_all(&self, worker: FetchWorker) {
+        let mut state = self.state.lock().unwrap();
+        state.announced.remove(&worker);
+        for p in state.pieces.iter_mut() {
+            if matches!(p, MetadataPiece::Requested(w) if *w == worker) {
+                *p = MetadataPiece::Missing;
+            }
+        }
+        state.pick_size();
+        drop(state);
+        self.progress.notify_waiters();
+    }
+
+    /// Store a piece from `worker` and verify the info dictionary once every
+    /// piece arrived.
+    fn receive(
+        &self,
+        worker: FetchWorker,
+        piece: u32,
+        total_size: usize,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(&announced) = state.announced.get(&worker) else {
+            return Err(Error::msg("metadata sent before its size was announced"));
+        };
+        if total_size != announced {
+            return Err(Error::msg(format!(
+                "metadata size {} differs from the announced {}",
+                total_size, announced
+            )));
+        }
+        // answers to requests made before we settled on another size
+        let size = total_size;
+        if state.size != Some(size) {
+            return Ok(());
+        }
+        let index = piece as usize;
+        if index >= state.pieces.len() {
+            return Err(Error::msg(format!("metadata piece {} out of range", piece)));
+        }
+        let expected_len = if index + 1 == state.pieces.len() {
+            size - index * METADATA_PIECE_SIZE
+        } else {
+            METADATA_PIECE_SIZE
+        };
+        if data.len() != expected_len {
+            return Err(Error::msg(format!(
+                "metadata piece {} has {} bytes, expected {}",
+                piece,
+                data.len(),
+                expected_len
+            )));
+        }
+        // pieces we didn't ask this peer for are dropped
+        if !matches!(state.pieces[index], MetadataPiece::Requested(w) if w == worker) {
+            return Ok(());
+        }
+        state.pieces[index] = MetadataPiece::Received(data);
+
+        let mut info = Vec::with_capacity(size);
+        for p in &state.pieces {
+            match p {
+                MetadataPiece::Received(data) => info.extend_from_slice(data),
+                _ => return Ok(()),
+            }
+        }
+        // only v1 info hashes get here, v2-only magnet links aren't fetchable
+        if Sha1::digest(&info)[..] == self.info_hash[..] {
+            state.info = Some(info.into());
+        } else {
+            eprintln!("metadata doesn't match the info hash, fetching it again");
+            state.failed_attempts += 1;
+            state.mismatched.insert(size);
+            state.pieces.fill(MetadataPiece::Missing);
+            state.pick_size();
+        }
+        drop(state);
+        self.progress.notify_waiters();
+        Ok(())
+    }
+}
+
+/// The `ut_metadata` extension, fetching the info dictionary of a magnet link
+/// or serving the one we hold.
+pub struct UtMetadata {
+    fetch: Option<(FetchWorker, Arc<MetadataFetch>)>,
+    /// the info dictionary we give to peers asking for it
+    metadata: Option<Bytes>,
+    /// when we served the peer's recent requests, for rate limiting
+    served: VecDeque<Instant>,
+}
+
+impl UtMetadata {
+    /// announce the extension without fetching or serving metadata
+    pub fn new() -> Self {
+        Self {
+            fetch: None,
+            metadata: None,
+            served: VecDeque::new(),
+        }
+    }
+
+    /// fetch metadata for `fetch` as `worker`
+    pub fn fetching(worker: FetchWorker, fetch: Arc<MetadataFetch>) -> Self {
+        Self {
+            fetch: Some((worker, fetch)),
+            ..Self::new()
+        }
+    }
+
+    /// serve `metadata`, the raw info dictionary, to the peer
+    pub fn serving(metadata: Bytes) -> Self {
+        Self {
+            metadata: Some(metadata),
+            ..Self::new()
+        }
+    }
+
+    /// Answer a request for `piece`. Requests beyond what lets the peer fetch
+    /// the whole metadata twice per METADATA_SERVE_WINDOW are rejected.
+    fn serve(&mut self, piece: u32, now: Instant) -> MetadataMessage {
+        let Some(metadata) = &self.metadata else {
+            return MetadataMessage::Reject { piece };
+        };
+        let npieces = metadata_pieces(metadata.len());
+        let start = piece as usize * METADATA_PIECE_SIZE;
+        if piece as usize >= npieces {
+            return MetadataMessage::Reject { piece };
+        }
+
+        while let Some(at) = self.served.front() {
+            if now.saturating_duration_since(*at) < METADATA_SERVE_WINDOW {
+                break;
+            }
+            self.served.pop_front();
+        }
+        if self.served.len() >= 2 * npieces {
+            return MetadataMessage::Reject { piece };
+        }
+        self.served.push_back(now);
+
+        let end = (start + METADATA_PIECE_SIZE).min(metadata.len());
+        MetadataMessage::Data {
+            piece,
+            total_size: metadata.len(),
+            data: metadata.slice(start..end),
+        }
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtensionHandshake) {
+        if let Some(metadata) = &self.metadata {
+            handshake.metadata_size = Some(metadata.len());
+        }
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtensionHandshake, id: Option<u8>) {
+        if let (Some((worker, fetch)), Some(_), Some(size)) =
+            (&self.fetch, id, handshake.metadata_size)
+        {
+            // a bogus size just means this peer isn't asked
+            if let Err(e) = fetch.set_size(*worker, size) {
+                eprintln!("{:#}", e);
+            }
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Bytes>> {
+        match MetadataMessage::decode(payload)? {
+            MetadataMessage::Request { piece } => {
+                Ok(vec![self.serve(piece, Instant::now()).encode()])
+            }
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                if let Some((worker, fetch)) = &self.fetch {
+                    fetch.receive(*worker, piece, total_size, data)?;
+                }
+                Ok(Vec::new())
+            }
+            MetadataMessage::Reject { piece } => {
+                if let Some((worker, fetch)) = &self.fetch {
+                    fetch.reject(*worker, piece);
+                }
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an info dictionary spanning three metadata pieces
+    fn info() -> Bytes {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"name"[..].to_vec(), Value::String(b"test".to_vec()));
+        dict.insert(b"pieces"[..].to_vec(), Value::String(vec![7; 40_000]));
+        Value::Dict(dict).encode().into()
+    }
+
+    fn fetch_for(info: &[u8]) -> MetadataFetch {
+        MetadataFetch::new(Sha1::digest(info).into(), [0; 20])
+    }
+
+    fn piece(info: &Bytes, index: u32) -> Bytes {
+        let start = index as usize * METADATA_PIECE_SIZE;
+        info.slice(start..(start + METADATA_PIECE_SIZE).min(info.len()))
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for msg in [
+            MetadataMessage::Request { piece: 1 },
+            MetadataMessage::Reject { piece: 2 },
+            MetadataMessage::Data {
+                piece: 3,
+                total_size: 40_000,
+                data: Bytes::from_static(b"data"),
+            },
+        ] {
+            assert_eq!(MetadataMessage::decode(&msg.encode()).unwrap(), msg);
+        }
+        assert!(MetadataMessage::decode(b"d8:msg_typei1e5:piecei0ee").is_err());
+        assert!(MetadataMessage::decode(b"d8:msg_typei7e5:piecei0ee").is_err());
+        assert!(MetadataMessage::decode(b"d8:msg_typei0e5:piecei-1ee").is_err());
+    }
+
+    #[test]
+    fn assembles_pieces_from_several_peers() {
+        let info = info();
+        let fetch = fetch_for(&info);
+        assert_eq!(fetch.claim(0), None);
+        fetch.set_size(0, info.len()).unwrap();
+        fetch.set_size(1, info.len()).unwrap();
+
+        assert_eq!(fetch.claim(0), Some(0));
+        assert_eq!(fetch.claim(1), Some(1));
+        assert_eq!(fetch.claim(0), Some(2));
+        assert_eq!(fetch.claim(1), None);
+        assert_eq!(fetch.outstanding(0), 2);
+
+        // pieces sent by a peer we didn't ask are dropped
+        fetch.receive(0, 1, info.len(), piece(&info, 1)).unwrap();
+        fetch.receive(1, 1, info.len(), piece(&info, 1)).unwrap();
+        fetch.receive(0, 2, info.len(), piece(&info, 2)).unwrap();
+        assert_eq!(fetch.info(), None);
+        fetch.receive(0, 0, info.len(), piece(&info, 0)).unwrap();
+        assert_eq!(fetch.info(), Some(info));
+    }
+
+    #[test]
+    fn fetches_again_after_a_hash_mismatch() {
+        let info = info();
+        let fetch = fetch_for(&info);
+        fetch.set_size(0, info.len()).unwrap();
+        for index in 0..3 {
+            assert_eq!(fetch.claim(0), Some(index));
+            let data = if index == 1 {
+                Bytes::from(vec![0; METADATA_PIECE_SIZE])
+            } else {
+                piece(&info, index)
+            };
+            fetch.receive(0, index, info.len(), data).unwrap();
+        }
+        assert_eq!(fetch.info(), None);
+        assert_eq!(fetch.state.lock().unwrap().failed_attempts, 1);
+
+        for index in 0..3 {
+            assert_eq!(fetch.claim(0), Some(index));
+            fetch
+                .receive(0, index, info.len(), piece(&info, index))
+                .unwrap();
+        }
+        assert_eq!(fetch.info(), Some(info));
+    }
+
+    #[test]
+    fn rejects_pieces_of_the_wrong_size() {
+        let info = info();
+        let fetch = fetch_for(&info);
+        assert!(fetch.receive(0, 0, info.len(), piece(&info, 0)).is_err());
+        assert!(fetch.set_size(0, 0).is_err());
+        assert!(fetch.set_size(0, MAX_METADATA_SIZE + 1).is_err());
+        fetch.set_size(0, info.len()).unwrap();
+        assert_eq!(fetch.claim(0), Some(0));
+
+        assert!(fetch
+            .receive(0, 0, info.len() + 1, piece(&info, 0))
+            .is_err());
+        assert!(fetch.receive(0, 3, info.len(), piece(&info, 2)).is_err());
+        assert!(fetch.receive(0, 0, info.len(), piece(&info, 2)).is_err());
+    }
+
+    #[test]
+    fn uses_the_size_most_peers_announce() {
+        let info = info();
+        let fetch = fetch_for(&info);
+        fetch.set_size(0, info.len() + 100).unwrap();
+        assert_eq!(fetch.claim(0), Some(0));
+        fetch.set_size(1, info.len()).unwrap();
+        fetch.set_size(2, info.len()).unwrap();
+
+        // the lone peer's request is forgotten with its size
+        assert_eq!(fetch.claim(0), None);
+        assert_eq!(fetch.claim(1), Some(0));
+        assert!(fetch.receive(0, 0, info.len(), piece(&info, 0)).is_err());
+        fetch.receive(1, 0, info.len(), piece(&info, 0)).unwrap();
+    }
+
+    #[test]
+    fn drops_the_size_when_its_peers_leave() {
+        let info = info();
+        let fetch = fetch_for(&info);
+        fetch.set_size(0, info.len() + 100).unwrap();
+        fetch.set_size(1, info.len()).unwrap();
+        assert_eq!(fetch.claim(0), Some(0));
+        assert_eq!(fetch.claim(1), None);
+
+        fetch.release_all(0);
+        assert_eq!(fetch.claim(1), Some(0));
+        fetch.release_all(1);
+        assert_eq!(fetch.state.lock().unwrap().size, None);
+    }
+
+    #[test]
+    fn prefers_sizes_that_did_not_fail() {
+        let info = info();
+        let fetch = fetch_for(&info);
+        let wrong = 2 * METADATA_PIECE_SIZE;
+        fetch.set_size(0, wrong).unwrap();
+        fetch.set_size(1, wrong).unwrap();
+        fetch.set_size(2, info.len()).unwrap();
+        for index in 0..2 {
+            assert_eq!(fetch.claim(0), Some(index));
+            fetch.receive(0, index, wrong, piece(&info, index)).unwrap();
+        }
+        assert_eq!(fetch.info(), None);
+        assert_eq!(fetch.claim(0), None);
+        assert_eq!(fetch.claim(2), Some(0));
+    }
+
+    #[test]
+    fn serves_metadata_with_a_rate_limit() {
+        let info = info();
+        let mut ut_metadata = UtMetadata::serving(info.clone());
+        let now = Instant::now();
+        assert_eq!(
+            ut_metadata.serve(2, now),
+            MetadataMessage::Data {
+                piece: 2,
+                total_size: info.len(),
+                data: piece(&info, 2),
+            }
+        );
+        assert_eq!(
+            ut_metadata.serve(3, now),
+            MetadataMessage::Reject { piece: 3 }
+        );
+        for _ in 1..6 {
+            assert!(matches!(
+                ut_metadata.serve(0, now),
+                MetadataMessage::Data { .. }
+            ));
+        }
+        assert_eq!(
+            ut_metadata.serve(0, now),
+            MetadataMessage::Reject { piece: 0 }
+        );
+        assert!(matches!(
+            ut_metadata.serve(0, now + METADATA_SERVE_WINDOW),
+            MetadataMessage::Data { .. }
+        ));
+        assert_eq!(
+            UtMetadata::new().serve(0, now),
+            MetadataMessage::Reject { piece: 0 }
+        );
+    }
+}
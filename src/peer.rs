@@ -1,141 +1,1127 @@
-use bytes::BufMut;
-use std::net::SocketAddrV4;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 
 use anyhow::{Context, Error};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum MsgID {
-    Choke = 0,
-    Unchoke = 1,
-    Interested = 2,
-    NotInterested = 3,
-    Have = 4,
-    Bitfield = 5,
-    Request = 6,
-    Piece = 7,
-    Cancel = 8,
-    Extended = 20, // add support for extension system protocol
-}
-
-#[derive(Debug)]
-pub struct PeerMsgFrame {
-    /// first 4 bytes are length of payload and rest 1 byte is Msg ID
-    pub msg_id: MsgID,
-    /// payload of variable size of length given in prefix
-    pub payload: Vec<u8>,
-}
-
-impl PeerMsgFrame {
-    pub fn new(msg_id: MsgID, payload: Vec<u8>) -> Self {
-        Self { msg_id, payload }
-    }
-
-    pub async fn read(stream: &mut TcpStream) -> anyhow::Result<Self> {
-        let mut buf = vec![0u8; 5];
-        stream.read_exact(&mut buf).await?;
-
-        let msg_id = match buf[4] {
-            0 => MsgID::Choke,
-            1 => MsgID::Unchoke,
-            2 => MsgID::Interested,
-            3 => MsgID::NotInterested,
-            4 => MsgID::Have,
-            5 => MsgID::Bitfield,
-            6 => MsgID::Request,
-            7 => MsgID::Piece,
-            8 => MsgID::Cancel,
-            20 => MsgID::Extended,
-            m => return Err(Error::msg(format!("Unknown message type {}.", m))),
+use crate::rng::Rng;
+
+/// frames longer than this are refused before allocating their payload; room
+/// for a 128 KiB block or the bitfield of a torrent with a million pieces
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+/// send a keep-alive when we haven't sent anything for this long
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+/// drop a connection that hasn't sent anything, keep-alives included, for this long
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+/// give up on peers that don't accept our connection within this time
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// give up on peers that don't complete the handshake within this time
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// hand a requested block to other peers when it hasn't arrived within this time
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Limits and timers of a peer connection
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    pub max_frame_len: usize,
+    pub keep_alive_interval: Duration,
+    pub idle_timeout: Duration,
+    pub connect_timeout: Duration,
+    pub handshake_timeout: Duration,
+    pub request_timeout: Duration,
+    /// capabilities announced in our handshakes
+    pub capabilities: Capabilities,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_len: MAX_FRAME_LEN,
+            keep_alive_interval: KEEP_ALIVE_INTERVAL,
+            idle_timeout: IDLE_TIMEOUT,
+            connect_timeout: CONNECT_TIMEOUT,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            request_timeout: REQUEST_TIMEOUT,
+            capabilities: Capabilities::none()
+                .with(Capability::Extended)
+                .with(Capability::Fast),
+        }
+    }
+}
+
+/// A message of the peer wire protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    /// a frame of length zero, sent to keep an idle connection open
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: u32,
+    },
+    /// the pieces the sender has, checked against the piece count by `Bitfield::from_bytes`
+    Bitfield(Bytes),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        data: Bytes,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// the port the sender's DHT node listens on
+    Port(u16),
+    /// the sender recommends downloading this piece, e.g. because it is in its cache
+    SuggestPiece {
+        index: u32,
+    },
+    /// the sender has every piece, replaces `Bitfield`
+    HaveAll,
+    /// the sender has no pieces, replaces `Bitfield`
+    HaveNone,
+    /// the sender won't serve a request
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// the sender serves requests for this piece even while choking us
+    AllowedFast {
+        index: u32,
+    },
+    /// an extension protocol message, `id` 0 is the extension handshake
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+}
+
+impl PeerMessage {
+    /// the message id on the wire, keep-alives have none
+    pub fn id(&self) -> Option<u8> {
+        let id = match self {
+            PeerMessage::KeepAlive => return None,
+            PeerMessage::Choke => 0,
+            PeerMessage::Unchoke => 1,
+            PeerMessage::Interested => 2,
+            PeerMessage::NotInterested => 3,
+            PeerMessage::Have { .. } => 4,
+            PeerMessage::Bitfield(_) => 5,
+            PeerMessage::Request { .. } => 6,
+            PeerMessage::Piece { .. } => 7,
+            PeerMessage::Cancel { .. } => 8,
+            PeerMessage::Port(_) => 9,
+            PeerMessage::SuggestPiece { .. } => 13,
+            PeerMessage::HaveAll => 14,
+            PeerMessage::HaveNone => 15,
+            PeerMessage::RejectRequest { .. } => 16,
+            PeerMessage::AllowedFast { .. } => 17,
+            PeerMessage::Extended { .. } => 20,
         };
+        Some(id)
+    }
 
-        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
-        // subtract the length of 'message type' which is 1 for a byte
-        let mut payload = vec![0u8; len - 1];
-        if len > 0 {
-            _ = stream.read_exact(&mut payload).await?;
+    /// messages of the fast extension, only allowed if both sides announced it
+    pub fn is_fast(&self) -> bool {
+        matches!(self.id(), Some(13..=17))
+    }
+
+    /// Decode the message with `id` from its payload. Ok(None) if we don't
+    /// know the message type.
+    pub fn decode(id: u8, mut payload: Bytes) -> anyhow::Result<Option<Self>> {
+        let expect_len = |len: usize, payload: &Bytes| {
+            if payload.len() == len {
+                Ok(())
+            } else {
+                Err(Error::msg(format!(
+                    "message {} must have a payload of {} bytes, got {}",
+                    id,
+                    len,
+                    payload.len()
+                )))
+            }
+        };
+
+        let msg = match id {
+            0..=3 | 14 | 15 => {
+                expect_len(0, &payload)?;
+                match id {
+                    0 => PeerMessage::Choke,
+                    1 => PeerMessage::Unchoke,
+                    2 => PeerMessage::Interested,
+                    3 => PeerMessage::NotInterested,
+                    14 => PeerMessage::HaveAll,
+                    _ => PeerMessage::HaveNone,
+                }
+            }
+            4 | 13 | 17 => {
+                expect_len(4, &payload)?;
+                let index = payload.get_u32();
+                match id {
+                    4 => PeerMessage::Have { index },
+                    13 => PeerMessage::SuggestPiece { index },
+                    _ => PeerMessage::AllowedFast { index },
+                }
+            }
+            5 => PeerMessage::Bitfield(payload),
+            6 | 8 | 16 => {
+                expect_len(12, &payload)?;
+                let (index, begin, length) =
+                    (payload.get_u32(), payload.get_u32(), payload.get_u32());
+                match id {
+                    6 => PeerMessage::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    8 => PeerMessage::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => PeerMessage::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            7 => {
+                if payload.len() < 8 {
+                    return Err(Error::msg("piece message too short"));
+                }
+                PeerMessage::Piece {
+                    index: payload.get_u32(),
+                    begin: payload.get_u32(),
+                    data: payload,
+                }
+            }
+            9 => {
+                expect_len(2, &payload)?;
+                PeerMessage::Port(payload.get_u16())
+            }
+            20 => {
+                if payload.is_empty() {
+                    return Err(Error::msg("extension message without extended id"));
+                }
+                PeerMessage::Extended {
+                    id: payload.get_u8(),
+                    payload,
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(msg))
+    }
+
+    /// the message as a frame, length prefix included
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            PeerMessage::KeepAlive => return 0u32.to_be_bytes().to_vec(),
+            PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone => {}
+            PeerMessage::Have { index }
+            | PeerMessage::SuggestPiece { index }
+            | PeerMessage::AllowedFast { index } => payload.put_u32(*index),
+            PeerMessage::Bitfield(bits) => payload.put_slice(bits),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                payload.put_u32(*index);
+                payload.put_u32(*begin);
+                payload.put_u32(*length);
+            }
+            PeerMessage::Piece { index, begin, data } => {
+                payload.put_u32(*index);
+                payload.put_u32(*begin);
+                payload.put_slice(data);
+            }
+            PeerMessage::Port(port) => payload.put_u16(*port),
+            PeerMessage::Extended { id, payload: data } => {
+                payload.put_u8(*id);
+                payload.put_slice(data);
+            }
         }
 
-        Ok(Self { msg_id, payload })
+        let mut buf = Vec::with_capacity(5 + payload.len());
+        // NOTE: + 1 for length of MsgType
+        buf.put_u32(payload.len() as u32 + 1);
+        buf.put_u8(self.id().unwrap());
+        buf.put_slice(&payload);
+        buf
+    }
+
+    /// read the next message, refusing frames longer than MAX_FRAME_LEN
+    pub async fn read<R: AsyncRead + Unpin>(stream: &mut R) -> anyhow::Result<Self> {
+        Self::read_limited(stream, MAX_FRAME_LEN).await
     }
 
-    pub async fn write(&self, stream: &mut TcpStream) -> anyhow::Result<()> {
-        let bytes = self.to_bytes();
-        stream.write_all(&bytes).await?;
+    /// Read the next message, refusing frames longer than `max_len` bytes.
+    /// Messages of types we don't know are skipped.
+    pub async fn read_limited<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        max_len: usize,
+    ) -> anyhow::Result<Self> {
+        loop {
+            let len = stream.read_u32().await? as usize;
+            if len == 0 {
+                return Ok(PeerMessage::KeepAlive);
+            }
+            if len > max_len {
+                return Err(Error::msg(format!(
+                    "frame of {} bytes exceeds the limit of {}",
+                    len, max_len
+                )));
+            }
+
+            let id = stream.read_u8().await?;
+            let mut payload = BytesMut::zeroed(len - 1);
+            stream.read_exact(&mut payload).await?;
+            // extensions we didn't negotiate are skipped
+            if let Some(msg) = Self::decode(id, payload.freeze())? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> anyhow::Result<()> {
+        stream.write_all(&self.encode()).await?;
         stream.flush().await?;
         Ok(())
     }
+}
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        // NOTE: + 1 for length of MsgType
-        buf.put_slice(&(self.payload.len() as u32 + 1u32).to_be_bytes());
-        buf.put_u8(self.msg_id as u8);
-        buf.put(&self.payload[..]);
-        buf
+/// pieces in the allowed-fast set we grant a peer
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// The allowed-fast set of a peer at `ip`: up to `count` pieces it may request
+/// while choked. Derived from the peer's /24 and the info hash as in BEP 6, so
+/// peers sharing a subnet can't collect more pieces than a single one. The
+/// BEP only defines the set for IPv4, IPv6 peers get none.
+pub fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: &[u8; 20],
+    npieces: usize,
+    count: usize,
+) -> Vec<u32> {
+    let IpAddr::V4(ip) = ip else {
+        return Vec::new();
+    };
+    let count = count.min(npieces);
+    let mut set = Vec::with_capacity(count);
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xffff_ff00).to_be_bytes());
+    x.extend_from_slice(info_hash);
+    while set.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() == count {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().unwrap());
+            let index = (y as u64 % npieces as u64) as u32;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+/// window over which transfer rates are averaged
+const RATE_WINDOW: Duration = Duration::from_secs(20);
+
+/// Bytes per second transferred over the last RATE_WINDOW
+#[derive(Debug, Clone, Default)]
+pub struct RateMeter {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateMeter {
+    pub fn record(&mut self, now: Instant, bytes: u64) {
+        while let Some((at, _)) = self.samples.front() {
+            if now.saturating_duration_since(*at) <= RATE_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, bytes));
+    }
+
+    pub fn rate(&self, now: Instant) -> f64 {
+        let bytes: u64 = self
+            .samples
+            .iter()
+            .filter(|(at, _)| now.saturating_duration_since(*at) <= RATE_WINDOW)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        bytes as f64 / RATE_WINDOW.as_secs_f64()
+    }
+}
+
+/// Tracks when a connection last sent and received anything, to send
+/// keep-alives and drop peers that went silent.
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    pub last_sent: Instant,
+    pub last_received: Instant,
+}
+
+impl Liveness {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            last_sent: now,
+            last_received: now,
+        }
+    }
+
+    pub fn sent(&mut self, now: Instant) {
+        self.last_sent = now;
+    }
+
+    pub fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    /// when `check` next has something to do
+    pub fn deadline(&self, config: &ConnectionConfig) -> Instant {
+        (self.last_sent + config.keep_alive_interval).min(self.last_received + config.idle_timeout)
+    }
+
+    /// Err once the peer has been silent for the idle timeout, otherwise
+    /// whether a keep-alive is due.
+    pub fn check(&self, now: Instant, config: &ConnectionConfig) -> anyhow::Result<bool> {
+        let silent = now.saturating_duration_since(self.last_received);
+        if silent >= config.idle_timeout {
+            return Err(Error::msg(format!(
+                "peer sent nothing for {}s",
+                silent.as_secs()
+            )));
+        }
+        Ok(now.saturating_duration_since(self.last_sent) >= config.keep_alive_interval)
+    }
+}
+
+/// Choke and interest state of a connection in both directions, plus its transfer rates
+#[derive(Debug, Clone)]
+pub struct PeerState {
+    /// we refuse to serve the peer's requests
+    pub am_choking: bool,
+    /// we want pieces the peer has
+    pub am_interested: bool,
+    /// the peer refuses to serve our requests
+    pub peer_choking: bool,
+    /// the peer wants pieces we have
+    pub peer_interested: bool,
+    /// piece data received from the peer
+    pub download_rate: RateMeter,
+    /// piece data sent to the peer
+    pub upload_rate: RateMeter,
+    pub connected_at: Instant,
+    /// when the peer last sent us a block
+    pub last_piece_received: Option<Instant>,
+    /// capabilities both sides announced in their handshakes
+    pub capabilities: Capabilities,
+}
+
+impl PeerState {
+    /// state of a fresh connection, both sides start choked and not interested
+    pub fn new(now: Instant) -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            download_rate: RateMeter::default(),
+            upload_rate: RateMeter::default(),
+            connected_at: now,
+            last_piece_received: None,
+            capabilities: Capabilities::none(),
+        }
+    }
+
+    /// both sides announced `capability`, so the connection may use it
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.supports(capability)
+    }
+
+    pub fn record_download(&mut self, now: Instant, bytes: u64) {
+        self.download_rate.record(now, bytes);
+        self.last_piece_received = Some(now);
+    }
+
+    pub fn record_upload(&mut self, now: Instant, bytes: u64) {
+        self.upload_rate.record(now, bytes);
+    }
+
+    /// we want data from the peer but it hasn't sent us any block for `timeout`
+    pub fn is_snubbed(&self, now: Instant, timeout: Duration) -> bool {
+        let last = self.last_piece_received.unwrap_or(self.connected_at);
+        self.am_interested && now.saturating_duration_since(last) >= timeout
+    }
+}
+
+/// the protocol string every handshake starts with
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+/// bytes in a handshake message
+pub const HANDSHAKE_LEN: usize = 68;
+
+/// Why a handshake failed
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("connect to peer")]
+    Connect(#[source] std::io::Error),
+    #[error("connecting to peer timed out after {0:?}")]
+    ConnectTimeout(Duration),
+    #[error("handshake timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("send or receive handshake")]
+    Io(#[from] std::io::Error),
+    #[error("peer does not speak the BitTorrent protocol")]
+    Protocol,
+    #[error("peer answered for info hash {}, expected {}", hex::encode(.got), hex::encode(.expected))]
+    InfoHashMismatch { expected: [u8; 20], got: [u8; 20] },
+    #[error("peer asked for unknown info hash {}", hex::encode(.0))]
+    UnknownInfoHash([u8; 20]),
+    #[error("connected to ourselves")]
+    SelfConnection,
+}
+
+/// An optional protocol feature announced in the reserved bytes of the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// extension protocol, BEP 10
+    Extended,
+    /// DHT, BEP 5
+    Dht,
+    /// fast extension, BEP 6
+    Fast,
+    /// upgrade to BitTorrent v2, BEP 52
+    V2,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 4] = [
+        Capability::Extended,
+        Capability::Dht,
+        Capability::Fast,
+        Capability::V2,
+    ];
+
+    /// byte and mask of the reserved bit announcing this capability
+    fn bit(self) -> (usize, u8) {
+        match self {
+            Capability::Extended => (5, 0x10),
+            Capability::Dht => (7, 0x01),
+            Capability::Fast => (7, 0x04),
+            Capability::V2 => (7, 0x10),
+        }
+    }
+}
+
+/// Set of capabilities, kept as the reserved bytes of a handshake so bits we
+/// don't know about survive a round trip.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities([u8; 8]);
+
+impl Capabilities {
+    pub fn none() -> Self {
+        Self([0; 8])
+    }
+
+    pub fn from_reserved(reserved: [u8; 8]) -> Self {
+        Self(reserved)
+    }
+
+    pub fn reserved(&self) -> [u8; 8] {
+        self.0
+    }
+
+    pub fn with(mut self, capability: Capability) -> Self {
+        self.insert(capability);
+        self
+    }
+
+    pub fn insert(&mut self, capability: Capability) {
+        let (byte, mask) = capability.bit();
+        self.0[byte] |= mask;
+    }
+
+    pub fn remove(&mut self, capability: Capability) {
+        let (byte, mask) = capability.bit();
+        self.0[byte] &= !mask;
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        let (byte, mask) = capability.bit();
+        self.0[byte] & mask == mask
+    }
+
+    /// what both sides support, the features a connection may use
+    pub fn intersection(&self, other: &Capabilities) -> Capabilities {
+        let mut reserved = [0; 8];
+        for (i, byte) in reserved.iter_mut().enumerate() {
+            *byte = self.0[i] & other.0[i];
+        }
+        Self(reserved)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Capability> + '_ {
+        Capability::ALL.into_iter().filter(|c| self.supports(*c))
     }
 }
 
-#[derive(Debug)]
-#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HandshakeMsg {
-    /// length of the protocol string (BitTorrent protocol) which is 19 (1 byte)
-    pub len: u8,
-    /// the string BitTorrent protocol (19 bytes)
-    pub string: [u8; 19],
-    /// eight reserved bytes, which are all set to zero (8 bytes)
+    /// eight reserved bytes, each set bit announces support for an extension
     pub reserved_bytes: [u8; 8],
     /// sha1 infohash (20 bytes) (NOT the hexadecimal representation, which is 40 bytes long)
     pub info_hash: [u8; 20],
-    /// peer id (20 bytes) (generate 20 random byte values)            
+    /// peer id (20 bytes) (generate 20 random byte values)
     pub peer_id: [u8; 20],
 }
 
 impl HandshakeMsg {
+    /// handshake announcing only the extension protocol
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
-        // enable bittorent extention system by setting 20th bit in reserved byte.
-        let reserved_bytes = [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00];
+        Self::with_capabilities(
+            info_hash,
+            peer_id,
+            Capabilities::none().with(Capability::Extended),
+        )
+    }
+
+    pub fn with_capabilities(
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        capabilities: Capabilities,
+    ) -> Self {
         Self {
-            len: 19,
-            string: *b"BitTorrent protocol",
-            reserved_bytes,
+            reserved_bytes: capabilities.reserved(),
             info_hash,
             peer_id,
         }
     }
 
+    /// capabilities announced by the sender
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_reserved(self.reserved_bytes)
+    }
+
     pub fn is_supporting_extention(&self) -> bool {
-        let bit = 1 << 4;
-        self.reserved_bytes[5] & bit == bit
+        self.capabilities().supports(Capability::Extended)
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0u8; HANDSHAKE_LEN];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved_bytes);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    /// parse a handshake, checking its protocol string
+    pub fn from_bytes(bytes: &[u8; HANDSHAKE_LEN]) -> Result<Self, HandshakeError> {
+        if bytes[0] as usize != PROTOCOL.len() || bytes[1..20] != PROTOCOL[..] {
+            return Err(HandshakeError::Protocol);
+        }
+        Ok(Self {
+            reserved_bytes: bytes[20..28].try_into().unwrap(),
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..68].try_into().unwrap(),
+        })
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Self, HandshakeError> {
+        let mut bytes = [0u8; HANDSHAKE_LEN];
+        stream.read_exact(&mut bytes).await?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> Result<(), HandshakeError> {
+        stream.write_all(&self.to_bytes()).await?;
+        stream.flush().await?;
+        Ok(())
     }
 }
 
+/// Random peer id in Azureus style, `-CC0001-` followed by twelve random characters
+pub fn generate_peer_id() -> [u8; 20] {
+    const CHARS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut rng = Rng::new();
+    let mut peer_id = *b"-CC0001-000000000000";
+    for c in &mut peer_id[8..] {
+        *c = CHARS[rng.below(CHARS.len())];
+    }
+    peer_id
+}
+
+/// connect to a peer and exchange handshakes with the default timeouts
 pub async fn handshake_peer(
     peer_address: SocketAddrV4,
     info_hash: &[u8; 20],
     my_peer_id: &[u8; 20],
-) -> anyhow::Result<(HandshakeMsg, TcpStream)> {
-    let mut handshake_msg = HandshakeMsg::new(info_hash.clone(), my_peer_id.clone());
-    let mut peer = tokio::net::TcpStream::connect(peer_address).await.unwrap();
-
-    let handshake_msg_bytes =
-        &mut handshake_msg as *mut HandshakeMsg as *mut [u8; std::mem::size_of::<HandshakeMsg>()];
-    // Safety: Handshake is a POD(Plain Old Data) with repr(c)
-    let handshake_msg_bytes: &mut [u8; std::mem::size_of::<HandshakeMsg>()] =
-        unsafe { &mut *handshake_msg_bytes };
-    peer.write_all(handshake_msg_bytes)
-        .await
-        .context("write handshake")?;
-    peer.read_exact(handshake_msg_bytes)
+) -> Result<(HandshakeMsg, TcpStream), HandshakeError> {
+    connect_peer(
+        peer_address.into(),
+        info_hash,
+        my_peer_id,
+        &ConnectionConfig::default(),
+    )
+    .await
+}
+
+/// Connect to a peer and exchange handshakes, giving up after the timeouts in `config`.
+pub async fn connect_peer(
+    peer_address: SocketAddr,
+    info_hash: &[u8; 20],
+    my_peer_id: &[u8; 20],
+    config: &ConnectionConfig,
+) -> Result<(HandshakeMsg, TcpStream), HandshakeError> {
+    let mut peer = tokio::time::timeout(config.connect_timeout, TcpStream::connect(peer_address))
         .await
-        .context("read handshake")?;
+        .map_err(|_| HandshakeError::ConnectTimeout(config.connect_timeout))?
+        .map_err(HandshakeError::Connect)?;
+    let handshake_msg = tokio::time::timeout(
+        config.handshake_timeout,
+        handshake(&mut peer, info_hash, my_peer_id, config.capabilities),
+    )
+    .await
+    .map_err(|_| HandshakeError::Timeout(config.handshake_timeout))??;
+    Ok((handshake_msg, peer))
+}
+
+/// Exchange handshakes over an established connection to a peer, announcing
+/// `capabilities` and checking that it serves `info_hash` and isn't ourselves.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    peer: &mut S,
+    info_hash: &[u8; 20],
+    my_peer_id: &[u8; 20],
+    capabilities: Capabilities,
+) -> Result<HandshakeMsg, HandshakeError> {
+    HandshakeMsg::with_capabilities(*info_hash, *my_peer_id, capabilities)
+        .write(peer)
+        .await?;
+    let remote = HandshakeMsg::read(peer).await?;
 
-    // TODO: remove check if it is possilbly unccessory
-    if handshake_msg.len != 19u8 || handshake_msg.string != *b"BitTorrent protocol" {
-        return Err(Error::msg("Handshake Response didn't matched"));
+    if remote.info_hash != *info_hash {
+        return Err(HandshakeError::InfoHashMismatch {
+            expected: *info_hash,
+            got: remote.info_hash,
+        });
+    }
+    if remote.peer_id == *my_peer_id {
+        return Err(HandshakeError::SelfConnection);
     }
+    Ok(remote)
+}
 
-    Ok((handshake_msg, peer))
+/// Read messages until an extension message with extended id `id` arrives and
+/// return its payload after the id. Other messages are dropped.
+pub async fn read_extended<R: AsyncRead + Unpin>(stream: &mut R, id: u8) -> anyhow::Result<Bytes> {
+    loop {
+        let msg = PeerMessage::read(stream).await.context("read message")?;
+        if let PeerMessage::Extended {
+            id: extended_id,
+            payload,
+        } = msg
+        {
+            if extended_id == id {
+                return Ok(payload);
+            }
+        }
+    }
+}
+
+/// Answer the handshake of an inbound connection.
+///
+/// `accept` is called with the info hash the remote peer asked for and decides
+/// whether we serve that torrent; if it does we reply with our own handshake
+/// announcing `capabilities`.
+pub async fn accept_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    peer: &mut S,
+    my_peer_id: &[u8; 20],
+    capabilities: Capabilities,
+    accept: impl FnOnce(&[u8; 20]) -> bool,
+) -> Result<HandshakeMsg, HandshakeError> {
+    let remote = HandshakeMsg::read(peer).await?;
+    if !accept(&remote.info_hash) {
+        return Err(HandshakeError::UnknownInfoHash(remote.info_hash));
+    }
+    if remote.peer_id == *my_peer_id {
+        return Err(HandshakeError::SelfConnection);
+    }
+
+    HandshakeMsg::with_capabilities(remote.info_hash, *my_peer_id, capabilities)
+        .write(peer)
+        .await?;
+    Ok(remote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn messages_round_trip() {
+        let messages = [
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have { index: 7 },
+            PeerMessage::Bitfield(Bytes::from_static(&[0xa5, 0x80])),
+            PeerMessage::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::Piece {
+                index: 2,
+                begin: 32768,
+                data: Bytes::from_static(b"block data"),
+            },
+            PeerMessage::Cancel {
+                index: 3,
+                begin: 0,
+                length: 100,
+            },
+            PeerMessage::Port(6881),
+            PeerMessage::SuggestPiece { index: 4 },
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::RejectRequest {
+                index: 5,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::AllowedFast { index: 6 },
+            PeerMessage::Extended {
+                id: 1,
+                payload: Bytes::from_static(b"d1:ai1ee"),
+            },
+        ];
+        let mut wire = Vec::new();
+        for msg in &messages {
+            msg.write(&mut wire).await.unwrap();
+        }
+
+        let mut stream = &wire[..];
+        for msg in &messages {
+            assert_eq!(&PeerMessage::read(&mut stream).await.unwrap(), msg);
+        }
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn encodes_frames() {
+        assert_eq!(PeerMessage::KeepAlive.encode(), [0, 0, 0, 0]);
+        assert_eq!(PeerMessage::Interested.encode(), [0, 0, 0, 1, 2]);
+        assert_eq!(
+            PeerMessage::Have { index: 0x0102_0304 }.encode(),
+            [0, 0, 0, 5, 4, 1, 2, 3, 4]
+        );
+        assert_eq!(
+            PeerMessage::Port(0x1ae1).encode(),
+            [0, 0, 0, 3, 9, 0x1a, 0xe1]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        let decode =
+            |id: u8, payload: &'static [u8]| PeerMessage::decode(id, Bytes::from_static(payload));
+        assert!(decode(0, &[0]).is_err());
+        assert!(decode(14, &[1]).is_err());
+        assert!(decode(4, &[0, 0, 1]).is_err());
+        assert!(decode(17, &[0, 0, 0, 0, 0]).is_err());
+        assert!(decode(6, &[0; 11]).is_err());
+        assert!(decode(16, &[0; 13]).is_err());
+        assert!(decode(7, &[0; 7]).is_err());
+        assert!(decode(9, &[0]).is_err());
+        assert!(decode(20, &[]).is_err());
+
+        // an empty block is still a piece message
+        assert_eq!(
+            decode(7, &[0, 0, 0, 1, 0, 0, 0, 2]).unwrap(),
+            Some(PeerMessage::Piece {
+                index: 1,
+                begin: 2,
+                data: Bytes::new(),
+            })
+        );
+        assert_eq!(decode(42, &[1, 2, 3]).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn skips_unknown_messages() {
+        let mut wire = vec![0, 0, 0, 3, 42, 1, 2];
+        wire.extend(PeerMessage::Unchoke.encode());
+        let mut stream = &wire[..];
+        assert_eq!(
+            PeerMessage::read(&mut stream).await.unwrap(),
+            PeerMessage::Unchoke
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_and_truncated_frames() {
+        let frame = PeerMessage::Bitfield(Bytes::from(vec![0xff; 100])).encode();
+        let err = PeerMessage::read_limited(&mut &frame[..], 100)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"), "{}", err);
+        assert!(PeerMessage::read_limited(&mut &frame[..], 101)
+            .await
+            .is_ok());
+
+        assert!(PeerMessage::read(&mut &frame[..50]).await.is_err());
+        assert!(PeerMessage::read(&mut &[0u8, 0][..]).await.is_err());
+    }
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    #[tokio::test]
+    async fn handshakes_over_duplex() {
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        let caps = Capabilities::none()
+            .with(Capability::Extended)
+            .with(Capability::Fast);
+        let (remote, inbound) = tokio::join!(
+            handshake(&mut ours, &INFO_HASH, &[2; 20], caps),
+            accept_handshake(&mut theirs, &[3; 20], Capabilities::none(), |hash| {
+                *hash == INFO_HASH
+            }),
+        );
+        let remote = remote.unwrap();
+        assert_eq!(remote.peer_id, [3; 20]);
+        assert_eq!(remote.capabilities(), Capabilities::none());
+        let inbound = inbound.unwrap();
+        assert_eq!(inbound.peer_id, [2; 20]);
+        assert_eq!(inbound.capabilities(), caps);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_info_hash() {
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        let (result, _) = tokio::join!(
+            handshake(&mut ours, &INFO_HASH, &[2; 20], Capabilities::none()),
+            async {
+                HandshakeMsg::read(&mut theirs).await.unwrap();
+                HandshakeMsg::new([9; 20], [3; 20])
+                    .write(&mut theirs)
+                    .await
+                    .unwrap();
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(HandshakeError::InfoHashMismatch { got, .. }) if got == [9; 20]
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_to_ourselves() {
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        let (outbound, inbound) = tokio::join!(
+            handshake(&mut ours, &INFO_HASH, &[2; 20], Capabilities::none()),
+            // hangs up without answering
+            async move { accept_handshake(&mut theirs, &[2; 20], Capabilities::none(), |_| true).await },
+        );
+        assert!(matches!(inbound, Err(HandshakeError::SelfConnection)));
+        assert!(matches!(outbound, Err(HandshakeError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_info_hash() {
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        HandshakeMsg::new([9; 20], [2; 20])
+            .write(&mut ours)
+            .await
+            .unwrap();
+        let result = accept_handshake(&mut theirs, &[3; 20], Capabilities::none(), |h| {
+            *h == INFO_HASH
+        })
+        .await;
+        assert!(matches!(result, Err(HandshakeError::UnknownInfoHash(h)) if h == [9; 20]));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_protocols() {
+        let mut bytes = HandshakeMsg::new(INFO_HASH, [2; 20]).to_bytes();
+        assert_eq!(
+            HandshakeMsg::from_bytes(&bytes).unwrap(),
+            HandshakeMsg::new(INFO_HASH, [2; 20])
+        );
+
+        bytes[5] = b'X';
+        assert!(matches!(
+            HandshakeMsg::read(&mut &bytes[..]).await,
+            Err(HandshakeError::Protocol)
+        ));
+        bytes = HandshakeMsg::new(INFO_HASH, [2; 20]).to_bytes();
+        bytes[0] = 18;
+        assert!(matches!(
+            HandshakeMsg::from_bytes(&bytes),
+            Err(HandshakeError::Protocol)
+        ));
+        assert!(matches!(
+            HandshakeMsg::read(&mut &bytes[..60]).await,
+            Err(HandshakeError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn capabilities_use_the_reserved_bits() {
+        let caps = Capabilities::none()
+            .with(Capability::Extended)
+            .with(Capability::Dht)
+            .with(Capability::Fast)
+            .with(Capability::V2);
+        assert_eq!(caps.reserved(), [0, 0, 0, 0, 0, 0x10, 0, 0x15]);
+        assert_eq!(caps.iter().collect::<Vec<_>>(), Capability::ALL);
+
+        let mut caps = caps;
+        caps.remove(Capability::Dht);
+        assert!(!caps.supports(Capability::Dht));
+        assert_eq!(caps.reserved(), [0, 0, 0, 0, 0, 0x10, 0, 0x14]);
+    }
+
+    #[test]
+    fn capabilities_keep_unknown_bits() {
+        let reserved = [0x80, 0, 0, 0, 0, 0x10, 0x02, 0x05];
+        let caps = Capabilities::from_reserved(reserved);
+        assert_eq!(caps.reserved(), reserved);
+        assert_eq!(
+            caps.iter().collect::<Vec<_>>(),
+            [Capability::Extended, Capability::Dht, Capability::Fast]
+        );
+        assert_eq!(
+            HandshakeMsg::with_capabilities(INFO_HASH, [2; 20], caps).to_bytes()[20..28],
+            reserved
+        );
+    }
+
+    #[test]
+    fn negotiates_common_capabilities() {
+        let ours = Capabilities::none()
+            .with(Capability::Extended)
+            .with(Capability::Fast);
+        let theirs = Capabilities::from_reserved([0xff, 0, 0, 0, 0, 0, 0, 0x05]);
+        let common = ours.intersection(&theirs);
+        assert_eq!(common, Capabilities::none().with(Capability::Fast));
+        assert_eq!(theirs.intersection(&ours), common);
+
+        let mut state = PeerState::new(Instant::now());
+        state.capabilities = common;
+        assert!(state.supports(Capability::Fast));
+        assert!(!state.supports(Capability::Extended));
+        assert!(!state.supports(Capability::Dht));
+    }
+
+    #[test]
+    fn allowed_fast_set_matches_bep_6() {
+        let ip = IpAddr::from([80, 4, 4, 200]);
+        let info_hash = [0xaa; 20];
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            [1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        // the whole /24 gets the same set
+        assert_eq!(
+            allowed_fast_set(IpAddr::from([80, 4, 4, 1]), &info_hash, 1313, 7),
+            [1059, 431, 808, 1217, 287, 376, 1188]
+        );
+    }
+
+    #[test]
+    fn allowed_fast_set_of_small_torrents() {
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let mut set = allowed_fast_set(ip, &[1; 20], 3, ALLOWED_FAST_COUNT);
+        set.sort();
+        assert_eq!(set, [0, 1, 2]);
+        assert!(allowed_fast_set("::1".parse().unwrap(), &[1; 20], 100, 10).is_empty());
+    }
+
+    #[test]
+    fn fast_messages() {
+        assert!(PeerMessage::HaveNone.is_fast());
+        assert!(PeerMessage::AllowedFast { index: 0 }.is_fast());
+        assert!(!PeerMessage::Port(1).is_fast());
+        assert!(!PeerMessage::KeepAlive.is_fast());
+    }
+
+    #[test]
+    fn sends_keep_alives_when_idle() {
+        let config = ConnectionConfig::default();
+        let start = Instant::now();
+        let mut liveness = Liveness::new(start);
+        assert_eq!(liveness.deadline(&config), start + KEEP_ALIVE_INTERVAL);
+        assert!(!liveness.check(start, &config).unwrap());
+        let secs = |s| start + Duration::from_secs(s);
+        assert!(!liveness.check(secs(119), &config).unwrap());
+        assert!(liveness.check(secs(120), &config).unwrap());
+
+        // sending anything postpones the keep-alive
+        liveness.received(secs(100));
+        liveness.sent(secs(110));
+        assert!(!liveness.check(secs(120), &config).unwrap());
+        assert_eq!(liveness.deadline(&config), secs(230));
+        assert!(liveness.check(secs(230), &config).unwrap());
+    }
+
+    #[test]
+    fn drops_silent_peers() {
+        let config = ConnectionConfig::default();
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        let mut liveness = Liveness::new(start);
+        liveness.sent(secs(150));
+        assert_eq!(liveness.deadline(&config), start + IDLE_TIMEOUT);
+        assert!(liveness.check(secs(179), &config).is_ok());
+        // our own keep-alives don't keep the peer alive
+        let err = liveness.check(secs(180), &config).unwrap_err();
+        assert!(err.to_string().contains("180s"), "{}", err);
+
+        liveness.received(secs(170));
+        assert!(liveness.check(secs(349), &config).is_ok());
+        assert!(liveness.check(secs(350), &config).is_err());
+    }
 }
@@ -0,0 +1,461 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error};
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::extension::*;
+use crate::value::Value;
+
+/// the peer prefers encrypted connections
+pub const PEX_ENCRYPTION: u8 = 0x01;
+/// the peer is a seed
+pub const PEX_SEED: u8 = 0x02;
+/// the peer supports uTP
+pub const PEX_UTP: u8 = 0x04;
+/// the peer supports holepunching
+pub const PEX_HOLEPUNCH: u8 = 0x08;
+/// the sender connected to the peer, so it is reachable
+pub const PEX_OUTGOING: u8 = 0x10;
+
+/// how often we send a PEX message to each peer
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// PEX messages from a peer arriving sooner than this after its last one are dropped
+pub const PEX_MIN_INTERVAL: Duration = Duration::from_secs(45);
+/// peers added or dropped in one message at most, as BEP 11 asks
+pub const MAX_PEX_PEERS: usize = 50;
+/// peers waiting to be connected to, further ones are forgotten
+pub const MAX_QUEUED_PEERS: usize = 1000;
+
+/// A `ut_pex` message, the peers that connected and disconnected since the last one
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    /// new peers with their PEX_* flags
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Bytes {
+        let mut added = Vec::new();
+        let mut added_f = Vec::new();
+        let mut added6 = Vec::new();
+        let mut added6_f = Vec::new();
+        for (addr, flags) in &self.added {
+            match addr {
+                SocketAddr::V4(_) => {
+                    added.extend_from_slice(&compact_addr(addr));
+                    added_f.push(*flags);
+                }
+                SocketAddr::V6(_) => {
+                    added6.extend_from_slice(&compact_addr(addr));
+                    added6_f.push(*flags);
+                }
+            }
+        }
+        let mut dropped = Vec::new();
+        let mut dropped6 = Vec::new();
+        for addr in &self.dropped {
+            match addr {
+                SocketAddr::V4(_) => dropped.extend_from_slice(&compact_addr(addr)),
+                SocketAddr::V6(_) => dropped6.extend_from_slice(&compact_addr(addr)),
+            }
+        }
+
+        let mut dict = BTreeMap::new();
+        dict.insert(b"added"[..].to_vec(), Value::String(added));
+        dict.insert(b"added.f"[..].to_vec(), Value::String(added_f));
+        dict.insert(b"added6"[..].to_vec(), Value::String(added6));
+        dict.insert(b"added6.f"[..].to_vec(), Value::String(added6_f));
+        dict.insert(b"dropped"[..].to_vec(), Value::String(dropped));
+        dict.insert(b"dropped6"[..].to_vec(), Value::String(dropped6));
+        Value::Dict(dict).encode().into()
+    }
+
+    /// Read a message. Missing lists count as empty and missing flags as 0.
+    pub fn decode(payload: &[u8]) -> anyhow::Result<Self> {
+        let (value, _) = Value::decode(payload).context("decode pex message")?;
+        let Value::Dict(dict) = value else {
+            return Err(Error::msg("pex message must be a dictionary"));
+        };
+        let bytes = |key: &[u8]| match dict.get(key) {
+            Some(Value::String(s)) => Ok(s.as_slice()),
+            None => Ok(&[][..]),
+            Some(_) => Err(Error::msg(format!(
+                "pex field {} must be a string",
+                String::from_utf8_lossy(key)
+            ))),
+        };
+
+        let mut msg = PexMessage::default();
+        for (key, flags_key, len) in [
+            (&b"added"[..], &b"added.f"[..], 6),
+            (b"added6", b"added6.f", 18),
+        ] {
+            let flags = bytes(flags_key)?;
+            for (i, addr) in parse_compact(bytes(key)?, len)?.into_iter().enumerate() {
+                msg.added.push((addr, flags.get(i).copied().unwrap_or(0)));
+            }
+        }
+        msg.dropped = parse_compact(bytes(b"dropped")?, 6)?;
+        msg.dropped.extend(parse_compact(bytes(b"dropped6")?, 18)?);
+        Ok(msg)
+    }
+}
+
+/// address and port in the compact form of trackers and PEX, 6 bytes for IPv4 and 18 for IPv6
+pub fn compact_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
+/// split a compact list of `len` byte addresses
+fn parse_compact(bytes: &[u8], len: usize) -> anyhow::Result<Vec<SocketAddr>> {
+    if !bytes.chunks_exact(len).remainder().is_empty() {
+        return Err(Error::msg(format!(
+            "compact peer list of {} bytes isn't a multiple of {}",
+            bytes.len(),
+            len
+        )));
+    }
+    Ok(bytes
+        .chunks_exact(len)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(len - 2);
+            let ip = match ip.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(ip).unwrap()),
+                _ => IpAddr::from(<[u8; 16]>::try_from(ip).unwrap()),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect())
+}
+
+#[derive(Debug, Default)]
+struct SwarmState {
+    /// every peer we heard of, so none is queued twice
+    known: HashSet<SocketAddr>,
+    /// peers not tried yet, in the order we heard of them
+    queue: VecDeque<SocketAddr>,
+    /// peers we are connected to with their PEX_* flags
+    connected: HashMap<SocketAddr, u8>,
+}
+
+/// The peers of a torrent, shared by its connections.
+///
+/// Trackers and PEX add peers to try; the download connects to them and
+/// records its connections, which PEX in turn tells other peers about.
+#[derive(Debug, Default)]
+pub struct PeerSwarm {
+    state: Mutex<SwarmState>,
+    /// signalled when peers are queued
+    pub added: Notify,
+}
+
+impl PeerSwarm {
+    /// Queue the peers we haven't heard of before, returning how many.
+    pub fn add(&self, peers: impl IntoIterator<Item = SocketAddr>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut added = 0;
+        for addr in peers {
+            if state.queue.len() >= MAX_QUEUED_PEERS {
+                break;
+            }
+            if addr.port() != 0 && state.known.insert(addr) {
+                state.queue.push_back(addr);
+                added += 1;
+            }
+        }
+        drop(state);
+        if added > 0 {
+            self.added.notify_one();
+        }
+        added
+    }
+
+    /// next peer to connect to
+    pub fn next(&self) -> Option<SocketAddr> {
+        self.state.lock().unwrap().queue.pop_front()
+    }
+
+    pub fn connected(&self, addr: SocketAddr, flags: u8) {
+        self.state.lock().unwrap().connected.insert(addr, flags);
+    }
+
+    /// add `flags` to a connected peer, e.g. PEX_SEED once it has every piece
+    pub fn set_flags(&self, addr: SocketAddr, flags: u8) {
+        if let Some(f) = self.state.lock().unwrap().connected.get_mut(&addr) {
+            *f |= flags;
+        }
+    }
+
+    pub fn disconnected(&self, addr: SocketAddr) {
+        self.state.lock().unwrap().connected.remove(&addr);
+    }
+
+    pub fn connected_peers(&self) -> HashMap<SocketAddr, u8> {
+        self.state.lock().unwrap().connected.clone()
+    }
+}
+
+/// The `ut_pex` extension: tells the peer about our connections every
+/// PEX_INTERVAL and adds the peers it tells us about to the swarm.
+pub struct UtPex {
+    swarm: Arc<PeerSwarm>,
+    /// address of the peer on the other end, not sent back to it
+    remote: SocketAddr,
+    /// the peer's id for `ut_pex`, set once its handshake arrived
+    remote_id: Option<u8>,
+    /// connections we told the peer about
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl UtPex {
+    pub fn new(swarm: Arc<PeerSwarm>, remote: SocketAddr) -> Self {
+        Self {
+            swarm,
+            remote,
+            remote_id: None,
+            sent: HashSet::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake, id: Option<u8>) {
+        self.remote_id = id;
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Bytes>> {
+        let now = Instant::now();
+        // a peer flooding us with peers is ignored rather than trusted
+        if let Some(last) = self.last_received {
+            if now.saturating_duration_since(last) < PEX_MIN_INTERVAL {
+                return Ok(Vec::new());
+            }
+        }
+        self.last_received = Some(now);
+
+        let msg = PexMessage::decode(payload)?;
+        self.swarm.add(
+            msg.added
+                .into_iter()
+                .take(MAX_PEX_PEERS)
+                .map(|(addr, _)| addr),
+        );
+        Ok(Vec::new())
+    }
+
+    /// Send the connections made and lost since the last message, at most once
+    /// per PEX_INTERVAL. The first message lists every connection.
+    fn poll(&mut self, now: Instant) -> Vec<Bytes> {
+        if self.remote_id.is_none() {
+            return Vec::new();
+        }
+        if let Some(last) = self.last_sent {
+            if now.saturating_duration_since(last) < PEX_INTERVAL {
+                return Vec::new();
+            }
+        }
+
+        let mut connected = self.swarm.connected_peers();
+        connected.remove(&self.remote);
+        let mut msg = PexMessage::default();
+        for (addr, flags) in &connected {
+            if msg.added.len() < MAX_PEX_PEERS && !self.sent.contains(addr) {
+                msg.added.push((*addr, *flags));
+            }
+        }
+        for addr in &self.sent {
+            if msg.dropped.len() < MAX_PEX_PEERS && !connected.contains_key(addr) {
+                msg.dropped.push(*addr);
+            }
+        }
+        for (addr, _) in &msg.added {
+            self.sent.insert(*addr);
+        }
+        for addr in &msg.dropped {
+            self.sent.remove(addr);
+        }
+
+        if msg.added.is_empty() && msg.dropped.is_empty() {
+            return Vec::new();
+        }
+        self.last_sent = Some(now);
+        vec![msg.encode()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(i: u32) -> SocketAddr {
+        SocketAddr::from((u32::to_be_bytes(0x0a00_0000 + i), 6881))
+    }
+
+    fn pex_with_remote() -> (Arc<PeerSwarm>, UtPex) {
+        let swarm = Arc::new(PeerSwarm::default());
+        let mut pex = UtPex::new(swarm.clone(), v4(0));
+        pex.on_handshake(&ExtensionHandshake::default(), Some(3));
+        (swarm, pex)
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let msg = PexMessage {
+            added: vec![
+                ("1.2.3.4:6881".parse().unwrap(), PEX_SEED | PEX_OUTGOING),
+                ("[2001:db8::1]:51413".parse().unwrap(), PEX_UTP),
+                ("5.6.7.8:1".parse().unwrap(), 0),
+            ],
+            dropped: vec!["9.9.9.9:9999".parse().unwrap(), "[::1]:80".parse().unwrap()],
+        };
+        let encoded = msg.encode();
+        let Value::Dict(dict) = Value::decode(&encoded).unwrap().0 else {
+            panic!("pex message must be a dictionary");
+        };
+        assert_eq!(
+            dict[&b"added"[..]],
+            Value::String(vec![1, 2, 3, 4, 0x1a, 0xe1, 5, 6, 7, 8, 0, 1])
+        );
+        assert_eq!(dict[&b"added.f"[..]], Value::String(vec![0x12, 0]));
+        assert_eq!(dict[&b"added6.f"[..]], Value::String(vec![PEX_UTP]));
+        assert_eq!(
+            dict[&b"dropped"[..]],
+            Value::String(vec![9, 9, 9, 9, 0x27, 0x0f])
+        );
+
+        let decoded = PexMessage::decode(&encoded).unwrap();
+        // IPv4 peers come first, as the lists are read in order
+        assert_eq!(decoded.added, [msg.added[0], msg.added[2], msg.added[1]]);
+        assert_eq!(decoded.dropped, msg.dropped);
+    }
+
+    #[test]
+    fn decodes_missing_fields_as_empty() {
+        let decoded = PexMessage::decode(b"d5:added6:\x01\x02\x03\x04\x00\x50e").unwrap();
+        assert_eq!(decoded.added, [("1.2.3.4:80".parse().unwrap(), 0)]);
+        assert!(decoded.dropped.is_empty());
+        assert!(PexMessage::decode(b"de").unwrap().added.is_empty());
+        assert!(PexMessage::decode(b"le").is_err());
+        assert!(PexMessage::decode(b"d5:addedi3ee").is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_compact_lists() {
+        assert_eq!(parse_compact(&[], 6).unwrap(), []);
+        assert_eq!(parse_compact(&[0; 12], 6).unwrap().len(), 2);
+        assert_eq!(parse_compact(&[0; 36], 18).unwrap().len(), 2);
+        for len in [1, 5, 7, 13] {
+            assert!(parse_compact(&vec![0; len], 6).is_err(), "{}", len);
+        }
+        for len in [6, 17, 19, 24] {
+            assert!(parse_compact(&vec![0; len], 18).is_err(), "{}", len);
+        }
+        assert!(PexMessage::decode(b"d5:added5:\x01\x02\x03\x04\x00e").is_err());
+        assert!(PexMessage::decode(b"d8:dropped612:000000000000e").is_err());
+    }
+
+    #[test]
+    fn queues_each_peer_once() {
+        let swarm = PeerSwarm::default();
+        assert_eq!(swarm.add([v4(1), v4(2), v4(1)]), 2);
+        assert_eq!(swarm.add([v4(2), v4(3)]), 1);
+        // port 0 can't be connected to
+        assert_eq!(swarm.add(["1.1.1.1:0".parse().unwrap()]), 0);
+        assert_eq!(swarm.next(), Some(v4(1)));
+        // peers already tried aren't queued again
+        assert_eq!(swarm.add([v4(1)]), 0);
+        assert_eq!(swarm.next(), Some(v4(2)));
+        assert_eq!(swarm.next(), Some(v4(3)));
+        assert_eq!(swarm.next(), None);
+    }
+
+    #[test]
+    fn caps_queued_peers() {
+        let swarm = PeerSwarm::default();
+        let added = swarm.add((0..MAX_QUEUED_PEERS as u32 + 10).map(v4));
+        assert_eq!(added, MAX_QUEUED_PEERS);
+        assert_eq!(swarm.add([v4(5000)]), 0);
+        swarm.next();
+        assert_eq!(swarm.add([v4(5000)]), 1);
+    }
+
+    #[test]
+    fn rate_limits_incoming_messages() {
+        let (swarm, mut pex) = pex_with_remote();
+        let message = |i| {
+            PexMessage {
+                added: vec![(v4(i), 0)],
+                dropped: Vec::new(),
+            }
+            .encode()
+        };
+        pex.on_message(&message(1)).unwrap();
+        // sooner than PEX_MIN_INTERVAL later, ignored
+        pex.on_message(&message(2)).unwrap();
+        assert_eq!(swarm.next(), Some(v4(1)));
+        assert_eq!(swarm.next(), None);
+
+        pex.last_received = Some(Instant::now() - PEX_MIN_INTERVAL);
+        pex.on_message(&message(3)).unwrap();
+        assert_eq!(swarm.next(), Some(v4(3)));
+    }
+
+    #[test]
+    fn adds_at_most_max_pex_peers_from_a_message() {
+        let (swarm, mut pex) = pex_with_remote();
+        let msg = PexMessage {
+            added: (1..=80).map(|i| (v4(i), 0)).collect(),
+            dropped: Vec::new(),
+        };
+        pex.on_message(&msg.encode()).unwrap();
+        let queued = std::iter::from_fn(|| swarm.next()).count();
+        assert_eq!(queued, MAX_PEX_PEERS);
+    }
+
+    #[test]
+    fn sends_connection_changes_every_interval() {
+        let swarm = Arc::new(PeerSwarm::default());
+        let mut pex = UtPex::new(swarm.clone(), v4(0));
+        let start = Instant::now();
+        for i in 0..80 {
+            swarm.connected(v4(i), PEX_OUTGOING);
+        }
+        // nothing is sent before the peer's handshake told us its id
+        assert!(pex.poll(start).is_empty());
+        pex.on_handshake(&ExtensionHandshake::default(), Some(3));
+
+        let sent = pex.poll(start);
+        assert_eq!(sent.len(), 1);
+        let msg = PexMessage::decode(&sent[0]).unwrap();
+        assert_eq!(msg.added.len(), MAX_PEX_PEERS);
+        // the peer isn't told about itself
+        assert!(msg.added.iter().all(|(addr, _)| *addr != v4(0)));
+
+        assert!(pex.poll(start + PEX_INTERVAL / 2).is_empty());
+        swarm.disconnected(msg.added[0].0);
+        let sent = pex.poll(start + PEX_INTERVAL);
+        let next = PexMessage::decode(&sent[0]).unwrap();
+        assert_eq!(next.dropped, [msg.added[0].0]);
+        assert_eq!(next.added.len(), 79 - MAX_PEX_PEERS);
+        // nothing changed since, nothing to send
+        assert!(pex.poll(start + 2 * PEX_INTERVAL).is_empty());
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::HashSet;
+
+use crate::bitfield::Bitfield;
+use crate::priority::FilePriority;
+use crate::rng::Rng;
+
+/// pieces picked at random before switching to rarest-first, so we quickly
+/// have something to trade
+pub const RANDOM_FIRST_PIECES: usize = 4;
+
+/// Decides which piece to download next from a peer.
+///
+/// Keeps how many connected peers have each piece, picks the rarest piece the
+/// peer has (random among equally rare ones) of the highest priority and
+/// prefers finishing pieces that were partially downloaded. Skipped pieces
+/// are never picked. Once every missing piece is fully requested the download
+/// is in endgame and pieces may be requested twice.
+#[derive(Debug)]
+pub struct PiecePicker {
+    /// number of connected peers having each piece
+    availability: Vec<u32>,
+    /// pieces downloaded and verified
+    have: Bitfield,
+    /// started pieces with blocks nobody has requested yet
+    partial: HashSet<u32>,
+    /// started pieces with every block requested or received
+    in_flight: HashSet<u32>,
+    /// priority of each piece, the highest of the files it overlaps
+    priorities: Vec<FilePriority>,
+    /// number of pieces not skipped
+    wanted: usize,
+    rng: Rng,
+}
+
+impl PiecePicker {
+    pub fn new(npieces: usize) -> Self {
+        Self::with_rng(npieces, Rng::new())
+    }
+
+    pub fn with_rng(npieces: usize, rng: Rng) -> Self {
+        Self {
+            availability: vec![0; npieces],
+            have: Bitfield::new(npieces),
+            partial: HashSet::new(),
+            in_flight: HashSet::new(),
+            priorities: vec![FilePriority::Normal; npieces],
+            wanted: npieces,
+            rng,
+        }
+    }
+
+    /// Set the priority of every piece. Pieces already started keep being
+    /// downloaded.
+    pub fn set_priorities(&mut self, priorities: Vec<FilePriority>) {
+        debug_assert_eq!(priorities.len(), self.availability.len());
+        self.wanted = priorities
+            .iter()
+            .filter(|p| **p != FilePriority::Skip)
+            .count();
+        self.priorities = priorities;
+    }
+
+    /// we still need the piece and it isn't skipped
+    pub fn wants(&self, index: u32) -> bool {
+        !self.have.has(index) && self.priorities[index as usize] != FilePriority::Skip
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    /// every piece that isn't skipped has been downloaded
+    pub fn is_complete(&self) -> bool {
+        self.have.count() >= self.wanted
+    }
+
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability[index as usize]
+    }
+
+    /// a peer announced its pieces with a `bitfield` message
+    pub fn add_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter() {
+            self.availability[index as usize] += 1;
+        }
+    }
+
+    /// a peer with `bitfield` disconnected
+    pub fn remove_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter() {
+            let count = &mut self.availability[index as usize];
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// a peer announced a new piece with a `have` message
+    pub fn add_have(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    /// Pick a piece `peer_has` that we still need and that has blocks nobody
+    /// requested yet, preferring pieces already started. None if the peer has
+    /// nothing for us right now.
+    pub fn pick(&mut self, peer_has: &Bitfield) -> Option<u32> {
+        let partial: Vec<u32> = self
+            .partial
+            .iter()
+            .copied()
+            .filter(|index| peer_has.has(*index))
+            .collect();
+        if !partial.is_empty() {
+            return self.rarest(partial);
+        }
+
+        let mut candidates: Vec<u32> = (0..self.availability.len() as u32)
+            .filter(|index| {
+                peer_has.has(*index)
+                    && self.wants(*index)
+                    && !self.in_flight.contains(index)
+                    && !self.partial.contains(index)
+            })
+            .collect();
+        let top = candidates
+            .iter()
+            .map(|index| self.priorities[*index as usize])
+            .max();
+        candidates.retain(|index| Some(self.priorities[*index as usize]) == top);
+        let index = if self.have.count() < RANDOM_FIRST_PIECES {
+            if candidates.is_empty() {
+                return None;
+            }
+            candidates[self.rng.below(candidates.len())]
+        } else {
+            self.rarest(candidates)?
+        };
+
+        self.partial.insert(index);
+        Some(index)
+    }
+
+    /// Pick the first of the pieces the peer suggested that it has and that
+    /// still has blocks nobody requested.
+    pub fn pick_suggested(&mut self, peer_has: &Bitfield, suggested: &[u32]) -> Option<u32> {
+        let index = suggested.iter().copied().find(|index| {
+            peer_has.has(*index) && self.wants(*index) && !self.in_flight.contains(index)
+        })?;
+        self.partial.insert(index);
+        Some(index)
+    }
+
+    /// every piece we want and lack has been started and all their blocks requested
+    pub fn is_endgame(&self) -> bool {
+        self.partial.is_empty()
+            && !self.in_flight.is_empty()
+            && self.have.count() + self.in_flight.len() >= self.wanted
+    }
+
+    /// In endgame, pick a fully requested piece `peer_has` so its remaining
+    /// blocks can be requested a second time.
+    pub fn pick_endgame(&mut self, peer_has: &Bitfield) -> Option<u32> {
+        if !self.is_endgame() {
+            return None;
+        }
+        let candidates: Vec<u32> = self
+            .in_flight
+            .iter()
+            .copied()
+            .filter(|index| peer_has.has(*index))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(candidates[self.rng.below(candidates.len())])
+    }
+
+    /// Record whether every block of the started piece has been requested.
+    pub fn set_requested(&mut self, index: u32, fully: bool) {
+        if fully {
+            self.partial.remove(&index);
+            self.in_flight.insert(index);
+        } else {
+            self.in_flight.remove(&index);
+            self.partial.insert(index);
+        }
+    }
+
+    /// the piece was downloaded and verified
+    pub fn complete(&mut self, index: u32) {
+        self.have.set(index);
+        self.partial.remove(&index);
+        self.in_flight.remove(&index);
+    }
+
+    /// The piece was dropped, e.g. after failing its hash check, and has to be
+    /// started over.
+    pub fn abort(&mut self, index: u32) {
+        self.in_flight.remove(&index);
+        self.partial.remove(&index);
+    }
+
+    /// rarest of `candidates`, ties broken at random
+    fn rarest(&mut self, candidates: Vec<u32>) -> Option<u32> {
+        let min = candidates
+            .iter()
+            .map(|i| self.availability[*i as usize])
+            .min()?;
+        let rarest: Vec<u32> = candidates
+            .into_iter()
+            .filter(|i| self.availability[*i as usize] == min)
+            .collect();
+        Some(rarest[self.rng.below(rarest.len())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitfield(len: usize, pieces: impl IntoIterator<Item = u32>) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for index in pieces {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    /// a picker past the random first pieces, with `0..RANDOM_FIRST_PIECES` done
+    fn started_picker(npieces: usize) -> PiecePicker {
+        let mut picker = PiecePicker::with_rng(npieces, Rng::with_seed(1));
+        for index in 0..RANDOM_FIRST_PIECES as u32 {
+            picker.complete(index);
+        }
+        picker
+    }
+
+    #[test]
+    fn picks_rarest_piece_the_peer_has() {
+        let mut picker = started_picker(10);
+        let seed = Bitfield::full(10);
+        picker.add_bitfield(&seed);
+        picker.add_bitfield(&bitfield(10, [4, 5, 6, 8, 9]));
+        picker.add_have(9);
+        assert_eq!(picker.availability(7), 1);
+        assert_eq!(picker.availability(9), 3);
+
+        assert_eq!(picker.pick(&seed), Some(7));
+        picker.set_requested(7, true);
+        // equally rare pieces are picked in any order, the most common last
+        let mut picked = Vec::new();
+        while let Some(index) = picker.pick(&seed) {
+            picker.set_requested(index, true);
+            picked.push(index);
+        }
+        assert_eq!(picked.len(), 5);
+        assert_eq!(picked[4], 9);
+    }
+
+    #[test]
+    fn availability_drops_with_disconnected_peers() {
+        let mut picker = started_picker(6);
+        let peer = bitfield(6, [4]);
+        picker.add_bitfield(&Bitfield::full(6));
+        picker.add_bitfield(&peer);
+        picker.add_bitfield(&peer);
+        assert_eq!(picker.pick(&Bitfield::full(6)), Some(5));
+
+        picker.abort(5);
+        picker.remove_bitfield(&peer);
+        picker.remove_bitfield(&peer);
+        picker.add_bitfield(&bitfield(6, [5]));
+        assert_eq!(picker.pick(&Bitfield::full(6)), Some(4));
+    }
+
+    #[test]
+    fn prefers_started_pieces() {
+        let mut picker = started_picker(8);
+        picker.add_bitfield(&Bitfield::full(8));
+        picker.add_bitfield(&bitfield(8, [4, 5, 6]));
+        let started = picker.pick(&Bitfield::full(8)).unwrap();
+        assert_eq!(started, 7);
+
+        // blocks of the piece are still unrequested, finish it before the rarer pieces
+        picker.set_requested(started, false);
+        assert_eq!(picker.pick(&Bitfield::full(8)), Some(7));
+        // a peer without it starts another piece
+        assert_eq!(picker.pick(&bitfield(8, [5])), Some(5));
+    }
+
+    #[test]
+    fn never_picks_pieces_we_have_or_the_peer_lacks() {
+        let mut picker = started_picker(6);
+        picker.add_bitfield(&Bitfield::full(6));
+        assert_eq!(picker.pick(&bitfield(6, [0, 1, 2, 3])), None);
+        assert_eq!(picker.pick(&bitfield(6, [1, 5])), Some(5));
+        picker.set_requested(5, true);
+        assert_eq!(picker.pick(&bitfield(6, [1, 5])), None);
+    }
+
+    #[test]
+    fn endgame_once_every_missing_piece_is_requested() {
+        let mut picker = started_picker(6);
+        let seed = Bitfield::full(6);
+        picker.add_bitfield(&seed);
+        let first = picker.pick(&seed).unwrap();
+        picker.set_requested(first, true);
+        assert!(!picker.is_endgame());
+        assert_eq!(picker.pick_endgame(&seed), None);
+
+        let second = picker.pick(&seed).unwrap();
+        picker.set_requested(second, true);
+        assert!(picker.is_endgame());
+        assert_eq!(picker.pick(&seed), None);
+        // in-flight pieces are handed out again, only to peers having them
+        assert_eq!(picker.pick_endgame(&bitfield(6, [second])), Some(second));
+        assert_eq!(picker.pick_endgame(&bitfield(6, [0])), None);
+
+        picker.complete(second);
+        assert!(picker.is_endgame());
+        assert_eq!(picker.pick_endgame(&seed), Some(first));
+        picker.complete(first);
+        assert!(picker.is_complete());
+        assert!(!picker.is_endgame());
+    }
+
+    #[test]
+    fn released_blocks_end_the_endgame() {
+        let mut picker = started_picker(5);
+        let seed = Bitfield::full(5);
+        picker.add_bitfield(&seed);
+        let index = picker.pick(&seed).unwrap();
+        picker.set_requested(index, true);
+        assert!(picker.is_endgame());
+
+        // a peer gave blocks back, they are picked normally again
+        picker.set_requested(index, false);
+        assert!(!picker.is_endgame());
+        assert_eq!(picker.pick(&seed), Some(index));
+    }
+
+    #[test]
+    fn skipped_pieces_dont_hold_off_the_endgame() {
+        let mut picker = started_picker(6);
+        let mut priorities = vec![FilePriority::Normal; 6];
+        priorities[5] = FilePriority::Skip;
+        picker.set_priorities(priorities);
+        let seed = Bitfield::full(6);
+        picker.add_bitfield(&seed);
+        assert_eq!(picker.pick(&seed), Some(4));
+        picker.set_requested(4, true);
+        assert!(picker.is_endgame());
+        assert_eq!(picker.pick(&seed), None);
+    }
+}
@@ -0,0 +1,200 @@
+use std::time::{Duration, Instant};
+
+use crate::download::BLOCK_SIZE;
+
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    /// requests kept outstanding however slow the peer is
+    pub min_requests: usize,
+    /// upper bound on outstanding requests, whatever the rate and RTT
+    pub max_requests: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            min_requests: 4,
+            max_requests: 500,
+        }
+    }
+}
+
+/// Sizes the queue of requests outstanding at a peer.
+///
+/// The queue should hold a bandwidth-delay product worth of blocks so the
+/// peer never waits for our next request. The rate is measured once per
+/// round trip and the queue kept half again as deep as rate × RTT, which lets
+/// it grow until the link, not the queue, limits the transfer.
+#[derive(Debug)]
+pub struct Pipeline {
+    pub config: PipelineConfig,
+    /// outstanding requests the peer accepts, its `reqq`
+    peer_limit: Option<usize>,
+    /// lowest latency seen between a request and its block
+    min_rtt: Option<Duration>,
+    /// smoothed bytes per second
+    rate: f64,
+    sample_start: Option<Instant>,
+    sample_bytes: u64,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Self {
+        Self {
+            config,
+            peer_limit: None,
+            min_rtt: None,
+            rate: 0.0,
+            sample_start: None,
+            sample_bytes: 0,
+        }
+    }
+
+    /// the peer told us how many requests it queues at most
+    pub fn set_peer_limit(&mut self, reqq: usize) {
+        self.peer_limit = Some(reqq.max(1));
+    }
+
+    /// a block of `bytes` requested at `requested_at` arrived at `now`
+    pub fn on_block(&mut self, requested_at: Instant, now: Instant, bytes: u64) {
+        let rtt = now.saturating_duration_since(requested_at);
+        self.min_rtt = Some(match self.min_rtt {
+            Some(min_rtt) => min_rtt.min(rtt),
+            None => rtt,
+        });
+
+        let start = *self.sample_start.get_or_insert(requested_at);
+        self.sample_bytes += bytes;
+        let elapsed = now.saturating_duration_since(start);
+        // measure over at least a round trip so a burst of queued blocks doesn't look like bandwidth
+        if elapsed >= self.min_rtt.unwrap().max(Duration::from_millis(50)) {
+            let sample = self.sample_bytes as f64 / elapsed.as_secs_f64();
+            self.rate = if self.rate == 0.0 {
+                sample
+            } else {
+                0.75 * self.rate + 0.25 * sample
+            };
+            self.sample_start = Some(now);
+            self.sample_bytes = 0;
+        }
+    }
+
+    /// the peer stopped sending, e.g. it choked us, restart the rate measurement
+    pub fn pause(&mut self) {
+        self.sample_start = None;
+        self.sample_bytes = 0;
+    }
+
+    /// estimated bytes per second
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// number of requests to keep outstanding
+    pub fn depth(&self) -> usize {
+        let rtt = self.min_rtt.unwrap_or_default().as_secs_f64();
+        let bdp = (1.5 * self.rate * rtt / BLOCK_SIZE as f64).ceil() as usize + 2;
+        let max = match self.peer_limit {
+            Some(limit) => self.config.max_requests.min(limit),
+            None => self.config.max_requests,
+        };
+        bdp.max(self.config.min_requests).min(max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: u64 = BLOCK_SIZE as u64;
+
+    /// a pipeline that saw `blocks_per_sec` blocks a second arrive `rtt` after their request
+    fn measured(blocks_per_sec: u64, rtt: Duration) -> Pipeline {
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        let start = Instant::now();
+        for i in 0..=blocks_per_sec {
+            let arrived = start + rtt + Duration::from_secs(1) * i as u32 / blocks_per_sec as u32;
+            pipeline.on_block(arrived - rtt, arrived, BLOCK);
+        }
+        pipeline
+    }
+
+    #[test]
+    fn starts_at_the_minimum() {
+        let pipeline = Pipeline::new(PipelineConfig::default());
+        assert_eq!(pipeline.rate(), 0.0);
+        assert_eq!(pipeline.depth(), 4);
+    }
+
+    #[test]
+    fn measures_rate_and_rtt() {
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        let start = Instant::now();
+        pipeline.on_block(start, start + Duration::from_millis(100), BLOCK);
+        // one block over a single round trip isn't a sample yet
+        assert_eq!(pipeline.min_rtt, Some(Duration::from_millis(100)));
+        assert_eq!(pipeline.rate(), BLOCK as f64 / 0.1);
+        pipeline.on_block(start, start + Duration::from_millis(300), 3 * BLOCK);
+        // the lowest RTT is kept, the rate smoothed towards the new sample
+        assert_eq!(pipeline.min_rtt, Some(Duration::from_millis(100)));
+        let sample = 3.0 * BLOCK as f64 / 0.2;
+        assert!((pipeline.rate() - (0.75 * BLOCK as f64 / 0.1 + 0.25 * sample)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn waits_a_round_trip_before_sampling() {
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        let start = Instant::now();
+        // a burst of queued blocks arriving together isn't bandwidth
+        for _ in 0..10 {
+            pipeline.on_block(start, start + Duration::from_millis(10), BLOCK);
+        }
+        assert_eq!(pipeline.rate(), 0.0);
+        pipeline.on_block(start, start + Duration::from_millis(50), BLOCK);
+        assert_eq!(pipeline.rate(), 11.0 * BLOCK as f64 / 0.05);
+    }
+
+    #[test]
+    fn keeps_half_again_the_bandwidth_delay_product() {
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        // 100 blocks a second over 200ms: 1.5 × 20 blocks in flight, plus 2
+        pipeline.rate = 100.0 * BLOCK as f64;
+        pipeline.min_rtt = Some(Duration::from_millis(200));
+        assert_eq!(pipeline.depth(), 32);
+        pipeline.min_rtt = Some(Duration::from_millis(210));
+        assert_eq!(pipeline.depth(), 34);
+
+        // measured from blocks arriving at that pace the depth comes out close
+        let depth = measured(100, Duration::from_millis(200)).depth();
+        assert!((20..=34).contains(&depth), "{}", depth);
+    }
+
+    #[test]
+    fn clamps_the_depth() {
+        // a slow peer still gets min_requests
+        assert_eq!(measured(2, Duration::from_millis(100)).depth(), 4);
+
+        let mut pipeline = measured(10_000, Duration::from_secs(1));
+        assert_eq!(pipeline.depth(), 500);
+        pipeline.config.max_requests = 100;
+        assert_eq!(pipeline.depth(), 100);
+        // the peer's reqq wins when it's lower
+        pipeline.set_peer_limit(64);
+        assert_eq!(pipeline.depth(), 64);
+        pipeline.set_peer_limit(1000);
+        assert_eq!(pipeline.depth(), 100);
+        pipeline.set_peer_limit(0);
+        assert_eq!(pipeline.depth(), 1);
+    }
+
+    #[test]
+    fn restarts_the_measurement_after_a_pause() {
+        let mut pipeline = measured(100, Duration::from_millis(100));
+        let rate = pipeline.rate();
+        pipeline.pause();
+        // the time the peer spent choking us doesn't count
+        let later = Instant::now() + Duration::from_secs(60);
+        pipeline.on_block(later, later + Duration::from_millis(100), BLOCK);
+        assert!(pipeline.rate() > 0.75 * rate);
+    }
+}
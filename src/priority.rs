@@ -0,0 +1,96 @@
+use anyhow::Error;
+
+use crate::magnet::parse_index_range;
+
+/// How much we want a file of the torrent. Pieces take the highest priority
+/// of the files they overlap, higher priority pieces are picked first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    /// not downloaded at all
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "skip" => Ok(FilePriority::Skip),
+            "low" => Ok(FilePriority::Low),
+            "normal" => Ok(FilePriority::Normal),
+            "high" => Ok(FilePriority::High),
+            _ => Err(Error::msg(format!(
+                "invalid priority {}, expected skip, low, normal or high",
+                s
+            ))),
+        }
+    }
+}
+
+/// Priorities of the `nfiles` files of a torrent from a selection like
+/// `0,2,4-6:high,7:low`. Listed files are normal unless a priority follows
+/// them, files left out are skipped.
+pub fn parse_file_priorities(selection: &str, nfiles: usize) -> anyhow::Result<Vec<FilePriority>> {
+    let mut priorities = vec![FilePriority::Skip; nfiles];
+    for item in selection.split(',') {
+        let (range, priority) = match item.split_once(':') {
+            Some((range, priority)) => (range, FilePriority::parse(priority)?),
+            None => (item, FilePriority::Normal),
+        };
+        let range = parse_index_range(range)?;
+        if *range.end() >= nfiles {
+            return Err(Error::msg(format!(
+                "file index {} is out of range, the torrent has {} files",
+                range.end(),
+                nfiles
+            )));
+        }
+        for index in range {
+            priorities[index] = priority;
+        }
+    }
+    Ok(priorities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use FilePriority::*;
+
+    #[test]
+    fn parses_selections() {
+        assert_eq!(
+            parse_file_priorities("0,2,4-6:high,7:low", 9).unwrap(),
+            [Normal, Skip, Normal, Skip, High, High, High, Low, Skip]
+        );
+        // later items override earlier ones
+        assert_eq!(
+            parse_file_priorities("0-2,1:skip", 3).unwrap(),
+            [Normal, Skip, Normal]
+        );
+        assert_eq!(parse_file_priorities("0:normal", 1).unwrap(), [Normal]);
+    }
+
+    #[test]
+    fn rejects_bad_selections() {
+        for selection in [
+            "9", "3-9", "", "a", "1-", "-1", "2-1", "0,", "0:", "0:urgent", "0:HIGH", "0;1",
+        ] {
+            assert!(
+                parse_file_priorities(selection, 9).is_err(),
+                "{:?}",
+                selection
+            );
+        }
+        let err = parse_file_priorities("2-9", 9).unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{}", err);
+    }
+
+    #[test]
+    fn orders_priorities() {
+        assert!(Skip < Low && Low < Normal && Normal < High);
+        assert_eq!(FilePriority::default(), Normal);
+    }
+}
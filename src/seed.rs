@@ -0,0 +1,612 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error};
+use bytes::Bytes;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Notify};
+
+use crate::bitfield::Bitfield;
+use crate::choker::*;
+use crate::extension::*;
+use crate::metadata::UtMetadata;
+use crate::peer::*;
+use crate::storage::Storage;
+use crate::torrent::*;
+use crate::tracker::*;
+
+/// largest block a peer may request, requests above this are treated as abusive
+pub const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+/// requests queued per peer, announced as `reqq`; further requests are dropped
+pub const MAX_QUEUED_REQUESTS: usize = 250;
+
+/// A torrent whose data we hold on disk and serve to other peers
+pub struct SeedTorrent {
+    pub torrent: Torrent,
+    pub info_hash: [u8; 20],
+    pub storage: Storage,
+    /// pieces present on disk with a matching hash
+    pub have: Bitfield,
+    /// the bencoded info dictionary, served to peers that joined by magnet link
+    pub metadata: Bytes,
+    /// bytes of piece data sent to peers
+    pub uploaded: AtomicU64,
+    /// connections currently serving this torrent
+    pub peers: Mutex<HashMap<SocketAddr, ConnectedPeer>>,
+    pub choker: Mutex<Choker<SocketAddr>>,
+}
+
+impl SeedTorrent {
+    /// Open the torrent data at `path` and hash-check every piece.
+    pub fn new(torrent: Torrent, path: impl Into<PathBuf>, choker_config: ChokerConfig) -> Self {
+        let storage = Storage::new(path, &torrent.info);
+        let npieces = torrent.info.piece_count();
+        let mut have = Bitfield::new(npieces);
+        for index in 0..npieces as u32 {
+            // missing or short files just mean we don't have the piece
+            if let Ok(piece) = storage.read_piece(index) {
+                if torrent.verify_piece(index, &piece) {
+                    have.set(index);
+                }
+            }
+        }
+
+        Self {
+            info_hash: torrent.info.info_hash(),
+            metadata: torrent.info.encoded().into(),
+            torrent,
+            storage,
+            have,
+            uploaded: AtomicU64::new(0),
+            peers: Mutex::new(HashMap::new()),
+            choker: Mutex::new(Choker::new(choker_config)),
+        }
+    }
+
+    /// Let the choker pick the unchoked peers and tell connections whose state changed.
+    pub fn rechoke(&self, now: Instant) {
+        let peers = self.peers.lock().unwrap();
+        let states: Vec<(SocketAddr, PeerState)> = peers
+            .iter()
+            .map(|(addr, peer)| (*addr, peer.state.lock().unwrap().clone()))
+            .collect();
+        let unchoked = self.choker.lock().unwrap().rechoke(
+            now,
+            self.have.is_full(),
+            states.iter().map(|(addr, state)| (*addr, state)),
+        );
+
+        for (addr, peer) in peers.iter() {
+            let mut state = peer.state.lock().unwrap();
+            let choke = !unchoked.contains(addr);
+            if state.am_choking != choke {
+                state.am_choking = choke;
+                let command = if choke {
+                    PeerCommand::Choke
+                } else {
+                    PeerCommand::Unchoke
+                };
+                // a closed channel means the connection is going away anyway
+                _ = peer.commands.send(command);
+            }
+        }
+    }
+
+    /// bytes of the torrent we don't have
+    pub fn left(&self) -> u64 {
+        let info = &self.torrent.info;
+        (0..info.piece_count() as u32)
+            .filter(|i| !self.have.has(*i))
+            .map(|i| info.piece_len(i) as u64)
+            .sum()
+    }
+
+    /// Announce to the tracker until `stop` resolves, then send a `stopped` event.
+    pub async fn announce_periodically(
+        &self,
+        peer_id: [u8; 20],
+        port: u16,
+        stop: impl std::future::Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        tokio::pin!(stop);
+        let mut event = Some("started");
+        loop {
+            let interval = if self.torrent.announce.is_empty() {
+                // nothing to announce to, only serve incoming connections
+                Duration::MAX
+            } else {
+                match announce(
+                    &self.torrent.announce(),
+                    &self.tracker_request(peer_id, port, event),
+                )
+                .await
+                {
+                    Ok(res) => {
+                        event = None;
+                        Duration::from_secs(res.interval.max(60))
+                    }
+                    Err(e) => {
+                        eprintln!("announce failed: {:#}", e);
+                        Duration::from_secs(60)
+                    }
+                }
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = &mut stop => break,
+            }
+        }
+
+        if !self.torrent.announce.is_empty() {
+            let request = self.tracker_request(peer_id, port, Some("stopped"));
+            announce(&self.torrent.announce(), &request).await?;
+        }
+        Ok(())
+    }
+
+    fn tracker_request(
+        &self,
+        peer_id: [u8; 20],
+        port: u16,
+        event: Option<&'static str>,
+    ) -> TrackerRequest {
+        TrackerRequest {
+            info_hash: self.info_hash,
+            port,
+            peer_id,
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: 0,
+            left: self.left(),
+            compact: 1,
+            event,
+        }
+    }
+}
+
+/// instructions from the choker to a connection task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerCommand {
+    Choke,
+    Unchoke,
+}
+
+/// a connection being served, as seen by the choker
+pub struct ConnectedPeer {
+    pub state: Arc<Mutex<PeerState>>,
+    pub commands: mpsc::UnboundedSender<PeerCommand>,
+}
+
+/// Accepts inbound peer connections and serves pieces of the active torrents
+pub struct Seeder {
+    peer_id: [u8; 20],
+    choker_config: ChokerConfig,
+    pub connection_config: ConnectionConfig,
+    torrents: RwLock<HashMap<[u8; 20], Arc<SeedTorrent>>>,
+    /// wakes the choker early, e.g. when a peer becomes interested
+    rechoke: Notify,
+}
+
+impl Seeder {
+    pub fn new(peer_id: [u8; 20], choker_config: ChokerConfig) -> Self {
+        Self {
+            peer_id,
+            choker_config,
+            connection_config: ConnectionConfig::default(),
+            torrents: RwLock::new(HashMap::new()),
+            rechoke: Notify::new(),
+        }
+    }
+
+    /// start serving `torrent`, with its data at `path`, to peers asking for its info hash
+    pub fn add(&self, torrent: Torrent, path: impl Into<PathBuf>) -> Arc<SeedTorrent> {
+        let torrent = Arc::new(SeedTorrent::new(torrent, path, self.choker_config.clone()));
+        self.torrents
+            .write()
+            .unwrap()
+            .insert(torrent.info_hash, torrent.clone());
+        torrent
+    }
+
+    pub fn remove(&self, info_hash: &[u8; 20]) -> Option<Arc<SeedTorrent>> {
+        self.torrents.write().unwrap().remove(info_hash)
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> Option<Arc<SeedTorrent>> {
+        self.torrents.read().unwrap().get(info_hash).cloned()
+    }
+
+    /// accept peers on `listener` forever, serving each on its own task
+    pub async fn listen(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        tokio::spawn(self.clone().run_choker());
+        loop {
+            let (stream, addr) = listener.accept().await.context("accept peer")?;
+            let seeder = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = seeder.serve_peer(stream, addr).await {
+                    eprintln!("peer {}: {:#}", addr, e);
+                }
+            });
+        }
+    }
+
+    /// rechoke every torrent each rechoke interval, or sooner when woken
+    async fn run_choker(self: Arc<Self>) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.choker_config.rechoke_interval) => {}
+                _ = self.rechoke.notified() => {}
+            }
+            let torrents: Vec<Arc<SeedTorrent>> =
+                self.torrents.read().unwrap().values().cloned().collect();
+            for torrent in torrents {
+                torrent.rechoke(Instant::now());
+            }
+        }
+    }
+
+    /// Serve the peer at `addr` on a freshly accepted connection, starting with
+    /// its handshake. Any transport works, not just TCP.
+    pub async fn serve_peer<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+        addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let timeout = self.connection_config.handshake_timeout;
+        let remote = tokio::time::timeout(
+            timeout,
+            accept_handshake(
+                &mut stream,
+                &self.peer_id,
+                self.connection_config.capabilities,
+                |info_hash| self.get(info_hash).is_some(),
+            ),
+        )
+        .await
+        .map_err(|_| HandshakeError::Timeout(timeout))??;
+        let torrent = self
+            .get(&remote.info_hash)
+            .context("torrent removed during handshake")?;
+
+        let mut state = PeerState::new(Instant::now());
+        state.capabilities = remote
+            .capabilities()
+            .intersection(&self.connection_config.capabilities);
+        let state = Arc::new(Mutex::new(state));
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        torrent.peers.lock().unwrap().insert(
+            addr,
+            ConnectedPeer {
+                state: state.clone(),
+                commands: commands_tx,
+            },
+        );
+        let _registration = PeerRegistration {
+            seeder: self,
+            torrent: &torrent,
+            addr,
+        };
+
+        let mut stream = BufReader::new(stream);
+        self.peer_loop(&torrent, addr, &state, commands, &mut stream)
+            .await
+    }
+
+    async fn peer_loop<S: AsyncBufRead + AsyncWrite + Unpin>(
+        &self,
+        torrent: &SeedTorrent,
+        addr: SocketAddr,
+        state: &Mutex<PeerState>,
+        mut commands: mpsc::UnboundedReceiver<PeerCommand>,
+        stream: &mut S,
+    ) -> anyhow::Result<()> {
+        let fast = state.lock().unwrap().supports(Capability::Fast);
+        let pieces = if fast && torrent.have.is_full() {
+            PeerMessage::HaveAll
+        } else if fast && torrent.have.count() == 0 {
+            PeerMessage::HaveNone
+        } else {
+            PeerMessage::Bitfield(Bytes::copy_from_slice(torrent.have.as_bytes()))
+        };
+        pieces.write(stream).await?;
+
+        // pieces the peer may request while we choke it
+        let mut allowed_fast = Vec::new();
+        if fast {
+            allowed_fast = allowed_fast_set(
+                addr.ip(),
+                &torrent.info_hash,
+                torrent.have.len(),
+                ALLOWED_FAST_COUNT,
+            );
+            allowed_fast.retain(|index| torrent.have.has(*index));
+            for &index in &allowed_fast {
+                PeerMessage::AllowedFast { index }.write(stream).await?;
+            }
+        }
+        let mut extensions = Extensions::new();
+        extensions.register(Box::new(UtMetadata::serving(torrent.metadata.clone())));
+        extensions.handshake.reqq = Some(MAX_QUEUED_REQUESTS);
+        extensions.handshake.yourip = Some(addr.ip());
+        if state.lock().unwrap().supports(Capability::Extended) {
+            extensions.handshake_message().write(stream).await?;
+        }
+        let config = &self.connection_config;
+        let mut liveness = Liveness::new(Instant::now());
+
+        let mut requests = VecDeque::<BlockRequest>::new();
+        loop {
+            // serve queued requests only while the peer is quiet, so a Cancel sent
+            // right after a Request still reaches us before the block goes out
+            tokio::select! {
+                biased;
+                _ = stream.fill_buf() => {}
+                Some(command) = commands.recv() => {
+                    if command == PeerCommand::Choke {
+                        // with the fast extension dropped requests are rejected explicitly
+                        let (kept, dropped) = requests
+                            .drain(..)
+                            .partition(|r| fast && allowed_fast.contains(&r.index));
+                        requests = kept;
+                        if fast {
+                            for request in dropped {
+                                request.reject().write(stream).await?;
+                            }
+                        }
+                    }
+                    let msg = match command {
+                        PeerCommand::Choke => PeerMessage::Choke,
+                        PeerCommand::Unchoke => PeerMessage::Unchoke,
+                    };
+                    msg.write(stream).await?;
+                    liveness.sent(Instant::now());
+                    continue;
+                }
+                _ = std::future::ready(()), if !requests.is_empty() => {
+                    let request = requests.pop_front().unwrap();
+                    send_block(torrent, &request, stream).await?;
+                    let now = Instant::now();
+                    liveness.sent(now);
+                    state
+                        .lock()
+                        .unwrap()
+                        .record_upload(now, request.length as u64);
+                    continue;
+                }
+                _ = tokio::time::sleep_until(liveness.deadline(config).into()) => {
+                    let now = Instant::now();
+                    if liveness.check(now, config)? {
+                        PeerMessage::KeepAlive.write(stream).await?;
+                        liveness.sent(now);
+                    }
+                    continue;
+                }
+            }
+
+            let msg = match PeerMessage::read_limited(stream, config.max_frame_len).await {
+                Ok(msg) => msg,
+                // the peer hanging up is the normal end of a connection
+                Err(e) if is_eof(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            liveness.received(Instant::now());
+            match msg {
+                PeerMessage::Interested => {
+                    state.lock().unwrap().peer_interested = true;
+                    self.rechoke.notify_one();
+                }
+                PeerMessage::NotInterested => {
+                    state.lock().unwrap().peer_interested = false;
+                    self.rechoke.notify_one();
+                }
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let request = BlockRequest {
+                        index,
+                        begin,
+                        length,
+                    };
+                    request.validate(torrent)?;
+                    // requests while choked are dropped, the peer re-requests after
+                    // unchoke, unless the piece is allowed fast. So are requests
+                    // beyond the reqq we announced.
+                    let choked = state.lock().unwrap().am_choking && !allowed_fast.contains(&index);
+                    if choked || requests.len() >= MAX_QUEUED_REQUESTS {
+                        if fast {
+                            request.reject().write(stream).await?;
+                            liveness.sent(Instant::now());
+                        }
+                        continue;
+                    }
+                    requests.push_back(request);
+                }
+                PeerMessage::Cancel {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let cancel = BlockRequest {
+                        index,
+                        begin,
+                        length,
+                    };
+                    let queued = requests.len();
+                    requests.retain(|r| *r != cancel);
+                    // the fast extension answers every request with a block or a reject
+                    if fast && requests.len() < queued {
+                        cancel.reject().write(stream).await?;
+                        liveness.sent(Instant::now());
+                    }
+                }
+                PeerMessage::Extended { id, payload } => {
+                    for reply in extensions.dispatch(id, &payload)? {
+                        reply.write(stream).await?;
+                        liveness.sent(Instant::now());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Removes a connected peer from its torrent when dropped, also when serving
+/// it panicked.
+struct PeerRegistration<'a> {
+    seeder: &'a Seeder,
+    torrent: &'a SeedTorrent,
+    addr: SocketAddr,
+}
+
+impl Drop for PeerRegistration<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut peers) = self.torrent.peers.lock() {
+            peers.remove(&self.addr);
+        }
+        // the peer may have held an unchoke slot
+        self.seeder.rechoke.notify_one();
+    }
+}
+
+/// a block asked for by a `request` message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockRequest {
+    index: u32,
+    begin: u32,
+    length: u32,
+}
+
+impl BlockRequest {
+    fn validate(&self, torrent: &SeedTorrent) -> anyhow::Result<()> {
+        if !torrent.have.has(self.index) {
+            return Err(Error::msg(format!(
+                "requested missing piece {}",
+                self.index
+            )));
+        }
+        let piece_len = torrent.torrent.info.piece_len(self.index);
+        if self.length == 0
+            || self.length > MAX_REQUEST_LENGTH
+            || self.begin as u64 + self.length as u64 > piece_len as u64
+        {
+            return Err(Error::msg(format!("invalid request {:?}", self)));
+        }
+        Ok(())
+    }
+
+    fn reject(&self) -> PeerMessage {
+        PeerMessage::RejectRequest {
+            index: self.index,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+}
+
+async fn send_block<W: AsyncWrite + Unpin>(
+    torrent: &SeedTorrent,
+    request: &BlockRequest,
+    stream: &mut W,
+) -> anyhow::Result<()> {
+    let offset =
+        request.index as u64 * torrent.torrent.info.piece_length as u64 + request.begin as u64;
+    let mut block = vec![0u8; request.length as usize];
+    torrent.storage.read(offset, &mut block)?;
+
+    PeerMessage::Piece {
+        index: request.index,
+        begin: request.begin,
+        data: block.into(),
+    }
+    .write(stream)
+    .await?;
+
+    torrent
+        .uploaded
+        .fetch_add(request.length as u64, Ordering::Relaxed);
+    Ok(())
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+/// address to listen on for inbound peers
+pub fn listen_address(port: u16) -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::*;
+    use crate::download::Download;
+
+    #[tokio::test]
+    async fn serves_a_download_over_duplex() {
+        let dir = tempfile::tempdir().unwrap();
+        let seeded = dir.path().join("seeded");
+        std::fs::create_dir_all(&seeded).unwrap();
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(seeded.join("data.bin"), &content).unwrap();
+        let opts = CreateOptions {
+            piece_length: Some(32768),
+            creation_date: false,
+            ..Default::default()
+        };
+        let torrent = create_torrent(seeded.join("data.bin"), &opts).unwrap();
+
+        let choker_config = ChokerConfig {
+            unchoke_slots: 2,
+            ..ChokerConfig::default()
+        };
+        let seeder = Arc::new(Seeder::new([1; 20], choker_config));
+        let seed = seeder.add(torrent.clone(), seeded.join("data.bin"));
+        assert_eq!(seed.choker.lock().unwrap().config.unchoke_slots, 2);
+        assert!(seed.have.is_full());
+        let choker = tokio::spawn(seeder.clone().run_choker());
+
+        let downloaded = dir.path().join("downloaded.bin");
+        let storage = Storage::new(&downloaded, &torrent.info);
+        storage.create_files().unwrap();
+        let download = Download::new(torrent, storage, [2; 20]);
+        let capabilities = ConnectionConfig::default().capabilities;
+        // nothing is bound, the address only names the peer
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        let (mut ours, theirs) = tokio::io::duplex(65536);
+        let serving = tokio::spawn({
+            let seeder = seeder.clone();
+            async move { seeder.serve_peer(theirs, addr).await }
+        });
+        let remote = handshake(&mut ours, &download.info_hash, &[2; 20], capabilities)
+            .await
+            .unwrap();
+        assert_eq!(remote.peer_id, [1; 20]);
+        let capabilities = remote.capabilities().intersection(&capabilities);
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            download.download_over(ours, addr, capabilities),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(download.is_complete());
+        assert_eq!(std::fs::read(&downloaded).unwrap(), content);
+        assert!(seed.uploaded.load(Ordering::Relaxed) >= content.len() as u64);
+
+        // the seeder notices the download hanging up and forgets the peer
+        serving.await.unwrap().ok();
+        assert!(seed.peers.lock().unwrap().is_empty());
+        choker.abort();
+    }
+}
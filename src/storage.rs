@@ -0,0 +1,549 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+
+use crate::priority::FilePriority;
+use crate::torrent::Info;
+
+/// a file on disk and where it sits in the torrent's concatenated byte stream
+#[derive(Debug, Clone)]
+pub struct StorageFile {
+    pub path: PathBuf,
+    pub length: u64,
+    /// offset of the first byte of the file in the torrent
+    pub offset: u64,
+    /// padding (BEP 47), its bytes are zeros and it isn't written to disk
+    pub padding: bool,
+    pub executable: bool,
+    /// the file is a symlink to this path, relative to the symlink's directory
+    pub symlink: Option<PathBuf>,
+    /// not downloaded, its bytes in pieces shared with wanted files go to the partfile
+    pub skipped: bool,
+}
+
+/// Maps the torrent's contiguous byte stream onto the files on disk.
+///
+/// For single-file torrents `root` is the file itself, for multi-file torrents
+/// it is the directory containing the files listed in the info dict.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    pub files: Vec<StorageFile>,
+    pub piece_length: u64,
+    pub length: u64,
+    /// every file starts at a piece boundary, as in v2-only torrents
+    pub aligned: bool,
+    /// holds the bytes of skipped files in pieces that overlap wanted files
+    pub partfile: PathBuf,
+    /// piece sized slot in the partfile of each such piece
+    pub part_slots: BTreeMap<u32, u64>,
+}
+
+impl Storage {
+    pub fn new(root: impl Into<PathBuf>, info: &Info) -> Self {
+        let root: PathBuf = root.into();
+        let mut partfile = root.clone().into_os_string();
+        partfile.push(".parts");
+        let aligned = info.has_v2() && !info.has_v1();
+        let piece_length = info.piece_length as u64;
+        let mut files = Vec::new();
+        let mut length = info.length;
+        match &info.files {
+            Some(info_files) => {
+                let mut offset = 0u64;
+                for file in info_files {
+                    if aligned {
+                        offset = offset.div_ceil(piece_length) * piece_length;
+                    }
+                    let path = file.path.iter().fold(root.clone(), |acc, p| acc.join(p));
+                    // the target is relative to the torrent directory, climb up to it
+                    let symlink = file
+                        .symlink_path
+                        .as_ref()
+                        .filter(|_| file.is_symlink())
+                        .map(|target| {
+                            let up = file.path.len().saturating_sub(1);
+                            let up: PathBuf = (0..up).map(|_| "..").collect();
+                            target.iter().fold(up, |acc, p| acc.join(p))
+                        });
+                    files.push(StorageFile {
+                        path,
+                        length: file.length,
+                        offset,
+                        padding: file.is_padding(),
+                        executable: file.is_executable(),
+                        symlink,
+                        skipped: false,
+                    });
+                    offset += file.length;
+                }
+                length = offset;
+            }
+            None => files.push(StorageFile {
+                path: root,
+                length: info.length,
+                offset: 0,
+                padding: false,
+                executable: false,
+                symlink: None,
+                skipped: false,
+            }),
+        }
+
+        Self {
+            files,
+            piece_length,
+            length,
+            aligned,
+            partfile: partfile.into(),
+            part_slots: BTreeMap::new(),
+        }
+    }
+
+    /// Skip the files whose priority is Skip, one priority per file of the
+    /// torrent. The pieces overlapping both skipped and wanted files get a slot
+    /// in the partfile, in piece order.
+    pub fn set_priorities(&mut self, priorities: &[FilePriority]) -> anyhow::Result<()> {
+        if priorities.len() != self.files.len() {
+            return Err(Error::msg(format!(
+                "got {} file priorities for a torrent of {} files",
+                priorities.len(),
+                self.files.len()
+            )));
+        }
+        for (file, priority) in self.files.iter_mut().zip(priorities) {
+            file.skipped = *priority == FilePriority::Skip;
+        }
+
+        let piece_priorities = self.piece_priorities(priorities);
+        let boundary: BTreeSet<u32> = self
+            .files
+            .iter()
+            .filter(|f| f.skipped && !f.padding)
+            .filter_map(|f| self.file_pieces(f))
+            .flatten()
+            .filter(|index| piece_priorities[*index as usize] != FilePriority::Skip)
+            .collect();
+        self.part_slots = boundary.into_iter().zip(0..).collect();
+        Ok(())
+    }
+
+    /// Priority of each piece given one per file, the highest of the files it
+    /// overlaps. Pieces of only skipped or padding files are skipped.
+    pub fn piece_priorities(&self, priorities: &[FilePriority]) -> Vec<FilePriority> {
+        let npieces = self.length.div_ceil(self.piece_length) as usize;
+        let mut pieces = vec![FilePriority::Skip; npieces];
+        for (file, priority) in self.files.iter().zip(priorities) {
+            if file.padding {
+                continue;
+            }
+            for index in self.file_pieces(file).into_iter().flatten() {
+                let piece = &mut pieces[index as usize];
+                *piece = (*piece).max(*priority);
+            }
+        }
+        pieces
+    }
+
+    /// the pieces `file` overlaps, None if it is empty
+    fn file_pieces(&self, file: &StorageFile) -> Option<RangeInclusive<u32>> {
+        if file.length == 0 {
+            return None;
+        }
+        let last = file.offset + file.length - 1;
+        Some((file.offset / self.piece_length) as u32..=(last / self.piece_length) as u32)
+    }
+
+    /// Where torrent bytes `start..stop` of a skipped file are kept in the
+    /// partfile, split at piece boundaries: torrent range and partfile offset.
+    fn part_ranges(&self, start: u64, stop: u64) -> anyhow::Result<Vec<(u64, u64, u64)>> {
+        let mut ranges = Vec::new();
+        let mut pos = start;
+        while pos < stop {
+            let index = (pos / self.piece_length) as u32;
+            let piece_start = index as u64 * self.piece_length;
+            let end = stop.min(piece_start + self.piece_length);
+            let slot = self
+                .part_slots
+                .get(&index)
+                .with_context(|| format!("piece {} only has bytes of skipped files", index))?;
+            ranges.push((pos, end, slot * self.piece_length + pos - piece_start));
+            pos = end;
+        }
+        Ok(ranges)
+    }
+
+    /// fill `buf` with the torrent bytes starting at `offset`, reading across file boundaries
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        let end = offset + buf.len() as u64;
+        if end > self.length {
+            return Err(anyhow::Error::msg(format!(
+                "read of {}..{} is past the end of the torrent",
+                offset, end
+            )));
+        }
+
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= offset || file.offset >= end {
+                continue;
+            }
+            let start = offset.max(file.offset);
+            let stop = end.min(file_end);
+            let range = (start - offset) as usize..(stop - offset) as usize;
+            if file.padding {
+                buf[range].fill(0);
+                continue;
+            }
+            if file.skipped {
+                let mut f = File::open(&self.partfile)
+                    .with_context(|| format!("open {}", self.partfile.display()))?;
+                for (start, stop, part_offset) in self.part_ranges(start, stop)? {
+                    f.seek(SeekFrom::Start(part_offset))?;
+                    f.read_exact(&mut buf[(start - offset) as usize..(stop - offset) as usize])
+                        .with_context(|| format!("read {}", self.partfile.display()))?;
+                }
+                continue;
+            }
+
+            let mut f =
+                File::open(&file.path).with_context(|| format!("open {}", file.path.display()))?;
+            f.seek(SeekFrom::Start(start - file.offset))?;
+            f.read_exact(&mut buf[range])
+                .with_context(|| format!("read {}", file.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Create every file of the torrent, so empty files exist even though no
+    /// piece covers them. Padding files are left out, symlinks are created and
+    /// executable files get their executable bits.
+    pub fn create_files(&self) -> anyhow::Result<()> {
+        for file in &self.files {
+            if file.padding || file.skipped {
+                continue;
+            }
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("create {}", parent.display()))?;
+            }
+            if let Some(target) = &file.symlink {
+                create_symlink(target, &file.path)
+                    .with_context(|| format!("create symlink {}", file.path.display()))?;
+                continue;
+            }
+            let f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)
+                .with_context(|| format!("create {}", file.path.display()))?;
+            if file.executable {
+                set_executable(&f)
+                    .with_context(|| format!("make {} executable", file.path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// write `data` at torrent `offset`, creating files and directories as needed
+    pub fn write(&self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        let end = offset + data.len() as u64;
+        if end > self.length {
+            return Err(anyhow::Error::msg(format!(
+                "write of {}..{} is past the end of the torrent",
+                offset, end
+            )));
+        }
+
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= offset || file.offset >= end {
+                continue;
+            }
+            if file.padding || file.symlink.is_some() {
+                continue;
+            }
+            let start = offset.max(file.offset);
+            let stop = end.min(file_end);
+            if file.skipped {
+                let mut f = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&self.partfile)
+                    .with_context(|| format!("open {}", self.partfile.display()))?;
+                for (start, stop, part_offset) in self.part_ranges(start, stop)? {
+                    f.seek(SeekFrom::Start(part_offset))?;
+                    f.write_all(&data[(start - offset) as usize..(stop - offset) as usize])
+                        .with_context(|| format!("write {}", self.partfile.display()))?;
+                }
+                continue;
+            }
+
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("create {}", parent.display()))?;
+            }
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)
+                .with_context(|| format!("open {}", file.path.display()))?;
+            f.seek(SeekFrom::Start(start - file.offset))?;
+            f.write_all(&data[(start - offset) as usize..(stop - offset) as usize])
+                .with_context(|| format!("write {}", file.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// read the whole piece at `index`
+    pub fn read_piece(&self, index: u32) -> anyhow::Result<Vec<u8>> {
+        let offset = index as u64 * self.piece_length;
+        if offset >= self.length {
+            return Err(anyhow::Error::msg(format!("piece {} out of range", index)));
+        }
+        let mut end = (offset + self.piece_length).min(self.length);
+        if self.aligned {
+            // pieces end with their file
+            if let Some(file) = self
+                .files
+                .iter()
+                .find(|f| f.length > 0 && f.offset <= offset && offset < f.offset + f.length)
+            {
+                end = end.min(file.offset + file.length);
+            }
+        }
+        let len = end - offset;
+        let mut piece = vec![0u8; len as usize];
+        self.read(offset, &mut piece)?;
+        Ok(piece)
+    }
+}
+
+/// point a symlink at `link` to `target`, replacing an earlier symlink
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    if link
+        .symlink_metadata()
+        .is_ok_and(|m| m.file_type().is_symlink())
+    {
+        fs::remove_file(link)?;
+    }
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// symlinks need privileges elsewhere, the link is left out
+#[cfg(not(unix))]
+fn create_symlink(_target: &Path, _link: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// add the executable bits wherever the file is readable
+#[cfg(unix)]
+fn set_executable(file: &File) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = file.metadata()?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(mode | (mode & 0o444) >> 2);
+    file.set_permissions(permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_file: &File) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::FileInfo;
+    use FilePriority::*;
+
+    const PIECE: u64 = 16;
+
+    /// a multi-file torrent of `files` (path, length, attr) with 16 byte pieces
+    fn info(files: &[(&str, u64, Option<&str>)]) -> Info {
+        let files: Vec<FileInfo> = files
+            .iter()
+            .map(|(path, length, attr)| FileInfo {
+                length: *length,
+                path: path.split('/').map(str::to_string).collect(),
+                attr: attr.map(str::to_string),
+                symlink_path: None,
+            })
+            .collect();
+        let length = files.iter().map(|f| f.length).sum();
+        Info {
+            length,
+            name: "test".to_string(),
+            piece_length: PIECE as u32,
+            pieces: vec![[0; 20]; length.div_ceil(PIECE) as usize],
+            files: Some(files),
+            private: false,
+            source: None,
+            meta_version: None,
+            file_tree: Vec::new(),
+            raw: None,
+        }
+    }
+
+    fn bytes(length: usize) -> Vec<u8> {
+        (1..=length).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn maps_offsets_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("test");
+        let storage = Storage::new(&root, &info(&[("a", 10, None), ("d/b", 20, None)]));
+        assert_eq!(storage.length, 30);
+        assert_eq!(storage.files[1].offset, 10);
+        assert_eq!(storage.files[1].path, root.join("d").join("b"));
+
+        let data = bytes(30);
+        storage.write(0, &data[..16]).unwrap();
+        storage.write(16, &data[16..]).unwrap();
+        assert_eq!(fs::read(root.join("a")).unwrap(), &data[..10]);
+        assert_eq!(fs::read(root.join("d/b")).unwrap(), &data[10..]);
+        assert_eq!(storage.read_piece(1).unwrap(), &data[16..]);
+        assert!(storage.read_piece(2).is_err());
+        assert!(storage.write(25, &[0; 6]).is_err());
+    }
+
+    #[test]
+    fn prioritizes_pieces_by_their_files() {
+        // pieces: 0 = a, 1 = a + b, 2 = b + c, 3 = c
+        let mut storage = Storage::new(
+            "unused",
+            &info(&[("a", 20, None), ("b", 20, None), ("c", 20, None)]),
+        );
+        assert_eq!(
+            storage.piece_priorities(&[Low, Skip, High]),
+            [Low, Low, High, High]
+        );
+        assert_eq!(
+            storage.piece_priorities(&[Skip, Normal, Skip]),
+            [Skip, Normal, Normal, Skip]
+        );
+
+        assert!(storage.set_priorities(&[Normal, Skip]).is_err());
+        storage.set_priorities(&[Skip, High, Skip]).unwrap();
+        assert!(storage.files[0].skipped && !storage.files[1].skipped);
+        // skipped bytes of pieces 1 and 2 go to the partfile, in piece order
+        assert_eq!(storage.part_slots, BTreeMap::from([(1, 0), (2, 1)]));
+        assert_eq!(storage.part_ranges(16, 20).unwrap(), [(16, 20, 0)]);
+        assert_eq!(storage.part_ranges(32, 36).unwrap(), [(32, 36, 16)]);
+        // piece 0 only has bytes of a skipped file, it is never downloaded
+        assert!(storage.part_ranges(0, 20).is_err());
+
+        storage.set_priorities(&[Normal; 3]).unwrap();
+        assert!(storage.part_slots.is_empty());
+    }
+
+    #[test]
+    fn keeps_skipped_bytes_in_the_partfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("test");
+        let mut storage = Storage::new(
+            &root,
+            &info(&[("a", 20, None), ("b", 20, None), ("c", 20, None)]),
+        );
+        assert_eq!(storage.partfile, dir.path().join("test.parts"));
+        storage.set_priorities(&[Skip, Normal, Skip]).unwrap();
+        storage.create_files().unwrap();
+        assert!(!root.join("a").exists());
+        assert!(!root.join("c").exists());
+
+        let data = bytes(60);
+        // the pieces shared with b, as the download writes them
+        storage.write(16, &data[16..32]).unwrap();
+        storage.write(32, &data[32..48]).unwrap();
+        assert!(!root.join("a").exists());
+        assert!(!root.join("c").exists());
+        assert_eq!(fs::read(root.join("b")).unwrap(), &data[20..40]);
+        let part = fs::read(&storage.partfile).unwrap();
+        assert_eq!(&part[..4], &data[16..20]);
+        assert_eq!(&part[24..32], &data[40..48]);
+
+        // both pieces read back whole, so they verify
+        assert_eq!(storage.read_piece(1).unwrap(), &data[16..32]);
+        assert_eq!(storage.read_piece(2).unwrap(), &data[32..48]);
+        assert!(storage.read_piece(0).is_err());
+    }
+
+    #[test]
+    fn skips_padding_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("test");
+        let storage = Storage::new(
+            &root,
+            &info(&[("a", 10, None), (".pad/6", 6, Some("p")), ("b", 16, None)]),
+        );
+        assert!(storage.files[1].padding);
+        storage.create_files().unwrap();
+        assert!(!root.join(".pad").exists());
+
+        let mut data = bytes(32);
+        storage.write(0, &data).unwrap();
+        assert!(!root.join(".pad").exists());
+        assert_eq!(fs::read(root.join("a")).unwrap(), &data[..10]);
+        assert_eq!(fs::read(root.join("b")).unwrap(), &data[16..]);
+        // padding reads as zeros, whatever was written over it
+        data[10..16].fill(0);
+        assert_eq!(storage.read_piece(0).unwrap(), &data[..16]);
+        // pieces of nothing but padding aren't wanted
+        assert_eq!(
+            storage.piece_priorities(&[Skip, High, Normal]),
+            [Skip, Normal]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn makes_executable_files_executable() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("test");
+        let storage = Storage::new(&root, &info(&[("run.sh", 4, Some("x")), ("data", 4, None)]));
+        assert!(storage.files[0].executable && !storage.files[1].executable);
+        storage.create_files().unwrap();
+        let mode = |name| fs::metadata(root.join(name)).unwrap().permissions().mode();
+        assert_ne!(mode("run.sh") & 0o111, 0);
+        assert_eq!(mode("run.sh") & 0o111, (mode("run.sh") & 0o444) >> 2);
+        assert_eq!(mode("data") & 0o111, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn creates_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("test");
+        let mut info = info(&[
+            ("d/a", 8, None),
+            ("d/link", 0, Some("l")),
+            ("top", 0, Some("l")),
+        ]);
+        let files = info.files.as_mut().unwrap();
+        files[1].symlink_path = Some(vec!["d".to_string(), "a".to_string()]);
+        files[2].symlink_path = Some(vec!["d".to_string(), "a".to_string()]);
+        let storage = Storage::new(&root, &info);
+        // targets are relative to the torrent directory, links to their own
+        assert_eq!(storage.files[1].symlink, Some(PathBuf::from("../d/a")));
+        assert_eq!(storage.files[2].symlink, Some(PathBuf::from("d/a")));
+
+        storage.create_files().unwrap();
+        // creating the files again replaces the links
+        storage.create_files().unwrap();
+        storage.write(0, &bytes(8)).unwrap();
+        for link in ["d/link", "top"] {
+            let path = root.join(link);
+            assert!(path.symlink_metadata().unwrap().file_type().is_symlink());
+            assert_eq!(fs::read(&path).unwrap(), bytes(8));
+        }
+    }
+}
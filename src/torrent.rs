@@ -1,20 +1,34 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 
+use crate::merkle::*;
+use crate::sha256::sha256;
 use crate::value::*;
 use anyhow::{Context, Error};
 use bytes::BufMut;
 use sha1::{Digest, Sha1};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Torrent {
     pub announce: Vec<u8>,
+    /// tiers of tracker urls (BEP 12), empty if the torrent only has `announce`
+    pub announce_list: Vec<Vec<String>>,
+    /// free-form textual comment of the author
+    pub comment: Option<String>,
+    /// name and version of the program used to create the torrent
+    pub created_by: Option<String>,
+    /// creation time of the torrent in UNIX epoch seconds
+    pub creation_date: Option<i64>,
+    /// web seed urls (BEP 19)
+    pub url_list: Vec<String>,
     pub info: Info,
+    /// piece hashes of the v2 files larger than a piece, by their pieces root (BEP 52)
+    pub piece_layers: BTreeMap<[u8; 32], Vec<[u8; 32]>>,
 }
 
 pub trait TorrentInfo {
     fn announce(&self) -> String;
     fn info_hash(&self) -> [u8; 20];
-    fn length(&self) -> u32;
+    fn length(&self) -> u64;
 }
 
 impl TorrentInfo for Torrent {
@@ -23,10 +37,10 @@ impl TorrentInfo for Torrent {
     }
 
     fn info_hash(&self) -> [u8; 20] {
-        self.info.hash()
+        self.info.info_hash()
     }
 
-    fn length(&self) -> u32 {
+    fn length(&self) -> u64 {
         self.info.length
     }
 }
@@ -34,18 +48,196 @@ impl TorrentInfo for Torrent {
 impl Torrent {
     pub fn from_value(value: &Value) -> anyhow::Result<Self> {
         if let Value::Dict(meta_info) = value {
-            let announce = if let Value::String(a) = meta_info[&b"announce"[..]].clone() {
-                a
-            } else {
-                return Err(Error::msg("cannot parse announce from bencode value"));
+            let announce = match meta_info.get(&b"announce"[..]) {
+                Some(Value::String(a)) => a.clone(),
+                None => Vec::new(),
+                _ => return Err(Error::msg("cannot parse announce from bencode value")),
+            };
+
+            let announce_list = match meta_info.get(&b"announce-list"[..]) {
+                Some(Value::Array(tiers)) => tiers
+                    .iter()
+                    .map(|tier| match tier {
+                        Value::Array(urls) => urls.iter().map(utf8_string).collect(),
+                        _ => Err(Error::msg("announce-list tier must be a list")),
+                    })
+                    .collect::<anyhow::Result<Vec<Vec<String>>>>()?,
+                _ => Vec::new(),
+            };
+
+            let url_list = match meta_info.get(&b"url-list"[..]) {
+                Some(Value::Array(urls)) => urls
+                    .iter()
+                    .map(utf8_string)
+                    .collect::<anyhow::Result<Vec<String>>>()?,
+                Some(url @ Value::String(_)) => vec![utf8_string(url)?],
+                _ => Vec::new(),
+            };
+
+            let comment = meta_info
+                .get(&b"comment"[..])
+                .map(utf8_string)
+                .transpose()?;
+            let created_by = meta_info
+                .get(&b"created by"[..])
+                .map(utf8_string)
+                .transpose()?;
+            let creation_date = match meta_info.get(&b"creation date"[..]) {
+                Some(Value::Integer(d)) => Some(*d as i64),
+                _ => None,
             };
 
-            let info = "info".as_bytes().to_vec();
-            let info = Info::from_value(&meta_info[&info])?;
-            Ok(Self { announce, info })
+            let info = meta_info
+                .get(&b"info"[..])
+                .context("torrent has no info dict")?;
+            let info = Info::from_value(info)?;
+
+            let mut piece_layers = BTreeMap::new();
+            if let Some(Value::Dict(layers)) = meta_info.get(&b"piece layers"[..]) {
+                for (root, layer) in layers {
+                    let root = <[u8; 32]>::try_from(root.as_slice())
+                        .map_err(|_| Error::msg("piece layer key must be a 32 byte pieces root"))?;
+                    let Value::String(layer) = layer else {
+                        return Err(Error::msg("piece layer must be a string"));
+                    };
+                    if !layer.chunks_exact(32).remainder().is_empty() {
+                        return Err(Error::msg("piece layer length isn't a multiple of 32"));
+                    }
+                    let layer = layer
+                        .chunks_exact(32)
+                        .map(|h| std::array::from_fn(|i| h[i]))
+                        .collect();
+                    piece_layers.insert(root, layer);
+                }
+            }
+            info.check_piece_layers(&piece_layers)?;
+
+            Ok(Self {
+                announce,
+                announce_list,
+                comment,
+                created_by,
+                creation_date,
+                url_list,
+                info,
+                piece_layers,
+            })
         } else {
-            return Err(Error::msg("Provided value is not dictionary"));
+            Err(Error::msg("Provided value is not dictionary"))
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut map = BTreeMap::new();
+        if !self.announce.is_empty() {
+            map.insert(
+                b"announce"[..].to_vec(),
+                Value::String(self.announce.clone()),
+            );
+        }
+        if !self.announce_list.is_empty() {
+            let tiers = self
+                .announce_list
+                .iter()
+                .map(|tier| Value::Array(tier.iter().map(|url| string_value(url)).collect()))
+                .collect();
+            map.insert(b"announce-list"[..].to_vec(), Value::Array(tiers));
+        }
+        if let Some(comment) = &self.comment {
+            map.insert(b"comment"[..].to_vec(), string_value(comment));
+        }
+        if let Some(created_by) = &self.created_by {
+            map.insert(b"created by"[..].to_vec(), string_value(created_by));
+        }
+        if let Some(creation_date) = self.creation_date {
+            map.insert(
+                b"creation date"[..].to_vec(),
+                Value::Integer(creation_date as isize),
+            );
+        }
+        if !self.url_list.is_empty() {
+            let urls = self.url_list.iter().map(|url| string_value(url)).collect();
+            map.insert(b"url-list"[..].to_vec(), Value::Array(urls));
+        }
+        map.insert(b"info"[..].to_vec(), self.info.to_value());
+        if !self.piece_layers.is_empty() {
+            let layers = self
+                .piece_layers
+                .iter()
+                .map(|(root, layer)| (root.to_vec(), Value::String(layer.concat())))
+                .collect();
+            map.insert(b"piece layers"[..].to_vec(), Value::Dict(layers));
+        }
+        Value::Dict(map)
+    }
+
+    /// Check piece `index` against its SHA-1 hash and, for v2 torrents, the
+    /// merkle tree of its file. Hybrid torrents pass with either when the
+    /// piece layer is unknown, e.g. for torrents from magnet links.
+    pub fn verify_piece(&self, index: u32, data: &[u8]) -> bool {
+        let info = &self.info;
+        if info.has_v1() {
+            let Some(hash) = info.pieces.get(index as usize) else {
+                return false;
+            };
+            if Sha1::digest(data)[..] != hash[..] {
+                return false;
+            }
         }
+        if !info.has_v2() {
+            return true;
+        }
+
+        let Some((file, piece)) = info.tree_piece(index) else {
+            return info.has_v1();
+        };
+        let Some(root) = file.pieces_root else {
+            return false;
+        };
+        // hybrid pieces end with the padding up to the next file, v2 hashes the file only
+        let start = piece as u64 * info.piece_length as u64;
+        let len = (file.length - start).min(info.piece_length as u64) as usize;
+        if data.len() < len {
+            return false;
+        }
+        let data = &data[..len];
+        if file.length <= info.piece_length as u64 {
+            return small_file_root(data) == root;
+        }
+        match self.piece_layers.get(&root) {
+            Some(layer) => layer.get(piece as usize) == Some(&piece_hash(data, info.piece_length)),
+            None => info.has_v1(),
+        }
+    }
+
+    /// Encode the torrent with `info` as its already bencoded info dictionary,
+    /// written as is so the info hash stays that of those exact bytes.
+    pub fn encode_with_info(&self, info: &[u8]) -> Vec<u8> {
+        let Value::Dict(map) = self.to_value() else {
+            unreachable!("to_value returns a dictionary")
+        };
+        let mut encoded = vec![b'd'];
+        let mut info = Some(info);
+        for (key, value) in &map {
+            match key.as_slice().cmp(b"info") {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => continue,
+                std::cmp::Ordering::Greater => {
+                    if let Some(info) = info.take() {
+                        encoded.extend(Value::String(b"info".to_vec()).encode());
+                        encoded.extend_from_slice(info);
+                    }
+                }
+            }
+            encoded.extend(Value::String(key.clone()).encode());
+            encoded.extend(value.encode());
+        }
+        if let Some(info) = info {
+            encoded.extend(Value::String(b"info".to_vec()).encode());
+            encoded.extend_from_slice(info);
+        }
+        encoded.push(b'e');
+        encoded
     }
 
     pub fn piece_hashes(&self) -> &Vec<[u8; 20]> {
@@ -53,62 +245,206 @@ impl Torrent {
     }
 }
 
+/// a file of a multi-file torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    // size of the file in bytes
+    pub length: u64,
+    // path components of the file relative to the torrent directory
+    pub path: Vec<String>,
+    // file attributes (BEP 47): `p` padding, `x` executable, `h` hidden, `l` symlink
+    pub attr: Option<String>,
+    // target of a symlink, path components relative to the torrent directory
+    pub symlink_path: Option<Vec<String>>,
+}
+
+/// a file of a v2 torrent's `file tree`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeFile {
+    pub length: u64,
+    /// path components of the file, the torrent name not included
+    pub path: Vec<String>,
+    /// root of the file's merkle tree, None for empty files
+    pub pieces_root: Option<[u8; 32]>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Info {
-    // size of the file in bytes, for single-file torrents
-    pub length: u32,
-    // suggested name to save a file UTF-8 encoded
+    // size of the file in bytes for single-file torrents, sum of all files otherwise
+    pub length: u64,
+    // suggested name to save a file (or directory for multi-file torrents) UTF-8 encoded
     pub name: String,
     // number of bytes in each piece
     pub piece_length: u32,
     // concatenated SHA-1 hashes of each piece
     pub pieces: Vec<[u8; 20]>,
+    // files of a multi-file torrent, None for single-file torrents
+    pub files: Option<Vec<FileInfo>>,
+    // peers must only be obtained from the torrent's trackers (BEP 27)
+    pub private: bool,
+    // source tag, used by private trackers to give torrents unique info hashes
+    pub source: Option<String>,
+    // 2 for v2 and hybrid torrents (BEP 52), None for v1 torrents
+    pub meta_version: Option<u32>,
+    // files of the v2 `file tree` in order, empty for v1 torrents
+    pub file_tree: Vec<TreeFile>,
+    // the info dictionary as read, hashed and served as is so keys we don't
+    // know about keep the info hash intact, None for torrents we create
+    pub raw: Option<Vec<u8>>,
 }
 
 impl Info {
     pub fn from_value(value: &Value) -> anyhow::Result<Self> {
         if let Value::Dict(info) = value {
-            let length = if let Value::Integer(a) = info[&b"length"[..]] {
-                a as u32
+            let name: String = if let Some(Value::String(a)) = info.get(&b"name"[..]) {
+                String::from_utf8(a.clone()).context("info name must be UTF-8 encoded")?
             } else {
-                return Err(Error::msg("cannot get Length from Info dict."));
+                return Err(Error::msg("cannot parse Info Name"));
             };
+            // the name is the file or directory everything is stored in
+            check_path_component(&name).context("info name")?;
 
-            let name: String = if let Value::String(a) = info[&b"name"[..]].clone() {
-                String::from_utf8(a).context("info name must be UTF-8 encoded")?
-            } else {
-                return Err(Error::msg("cannot parse Info Name"));
+            let piece_length = match info.get(&b"piece length"[..]) {
+                Some(Value::Integer(a)) if *a > 0 => {
+                    u32::try_from(*a).context("piece length is too large")?
+                }
+                Some(Value::Integer(a)) => {
+                    return Err(Error::msg(format!("invalid piece length {}", a)))
+                }
+                _ => return Err(Error::msg("cannot parse Info piece length")),
             };
 
-            let piece_length = if let Value::Integer(a) = info[&b"piece length"[..]] {
-                a as u32
-            } else {
-                return Err(Error::msg("cannot parse Info piece length"));
+            let meta_version = match info.get(&b"meta version"[..]) {
+                Some(Value::Integer(2)) => Some(2),
+                Some(Value::Integer(v)) => {
+                    return Err(Error::msg(format!("unsupported meta version {}", v)))
+                }
+                _ => None,
             };
+            let mut file_tree = Vec::new();
+            if meta_version.is_some() {
+                if piece_length < MERKLE_BLOCK_SIZE as u32 || !piece_length.is_power_of_two() {
+                    return Err(Error::msg(
+                        "v2 piece length must be a power of two of at least 16 KiB",
+                    ));
+                }
+                let tree = info
+                    .get(&b"file tree"[..])
+                    .context("v2 torrent has no file tree")?;
+                parse_file_tree(tree, &mut Vec::new(), &mut file_tree)?;
+                if file_tree.is_empty() {
+                    return Err(Error::msg("v2 file tree has no files"));
+                }
+            }
 
-            let pieces: Vec<[u8; 20]> = if let Value::String(a) = info[&b"pieces"[..]].clone() {
-                // TODO: validate if value len is exact multiple of 20 and non-zero and return error
-                a.chunks_exact(20)
+            let pieces: Vec<[u8; 20]> = match info.get(&b"pieces"[..]) {
+                Some(Value::String(a)) if !a.chunks_exact(20).remainder().is_empty() => {
+                    return Err(Error::msg(format!(
+                        "pieces of {} bytes isn't a multiple of 20",
+                        a.len()
+                    )))
+                }
+                Some(Value::String(a)) => a
+                    .chunks_exact(20)
                     .map(|c| std::array::from_fn(|i| c[i]))
-                    .collect()
-            } else {
-                return Err(Error::msg("cannot parse Info peices"));
+                    .collect(),
+                // v2-only torrents hash pieces per file
+                None if meta_version.is_some() => Vec::new(),
+                _ => return Err(Error::msg("cannot parse Info peices")),
             };
 
-            Ok(Self {
+            let files = match info.get(&b"files"[..]) {
+                Some(Value::Array(files)) => Some(
+                    files
+                        .iter()
+                        .map(FileInfo::from_value)
+                        .collect::<anyhow::Result<Vec<FileInfo>>>()?,
+                ),
+                // a v2-only torrent of one file named like the torrent is laid out
+                // like a v1 single-file torrent
+                _ if pieces.is_empty() && !file_tree.is_empty() => {
+                    if file_tree.len() == 1 && file_tree[0].path == [name.clone()] {
+                        None
+                    } else {
+                        Some(
+                            file_tree
+                                .iter()
+                                .map(|f| FileInfo {
+                                    length: f.length,
+                                    path: f.path.clone(),
+                                    attr: None,
+                                    symlink_path: None,
+                                })
+                                .collect(),
+                        )
+                    }
+                }
+                _ => None,
+            };
+
+            let length = match (info.get(&b"length"[..]), &files) {
+                (Some(Value::Integer(a)), _) => {
+                    u64::try_from(*a).context("length must not be negative")?
+                }
+                (None, Some(files)) => files.iter().map(|f| f.length).sum(),
+                (None, None) if !file_tree.is_empty() => file_tree.iter().map(|f| f.length).sum(),
+                _ => return Err(Error::msg("cannot get Length from Info dict.")),
+            };
+
+            if let Some(files) = &files {
+                check_symlinks(files)?;
+            }
+
+            // every piece needs a hash, or it could never be verified
+            let expected = length.div_ceil(piece_length as u64);
+            if info.contains_key(&b"pieces"[..]) && pieces.len() as u64 != expected {
+                return Err(Error::msg(format!(
+                    "torrent of {} bytes in pieces of {} has {} piece hashes, expected {}",
+                    length,
+                    piece_length,
+                    pieces.len(),
+                    expected
+                )));
+            }
+
+            let private = matches!(info.get(&b"private"[..]), Some(Value::Integer(1)));
+            let source = info.get(&b"source"[..]).map(utf8_string).transpose()?;
+
+            let info = Self {
                 length,
                 name,
                 piece_length,
                 pieces,
-            })
+                files,
+                private,
+                source,
+                meta_version,
+                file_tree,
+                // keeps every key, the exact bytes are set by whoever has them
+                raw: Some(value.encode()),
+            };
+            if info.has_v1() && info.has_v2() {
+                info.check_hybrid()?;
+            }
+            Ok(info)
         } else {
-            return Err(Error::msg("Provided value is not dictionary"));
+            Err(Error::msg("Provided value is not dictionary"))
         }
     }
 
     pub fn to_value(&self) -> Value {
         let mut map = BTreeMap::new();
-        map.insert(b"length"[..].to_vec(), Value::Integer(self.length as isize));
+        match &self.files {
+            Some(files) => {
+                map.insert(
+                    b"files"[..].to_vec(),
+                    Value::Array(files.iter().map(FileInfo::to_value).collect()),
+                );
+            }
+            None => {
+                map.insert(b"length"[..].to_vec(), Value::Integer(self.length as isize));
+            }
+        }
         map.insert(
             b"name"[..].to_vec(),
             Value::String(self.name.as_bytes().to_vec()),
@@ -124,13 +460,754 @@ impl Info {
                 acc
             })),
         );
+        if self.private {
+            map.insert(b"private"[..].to_vec(), Value::Integer(1));
+        }
+        if let Some(source) = &self.source {
+            map.insert(b"source"[..].to_vec(), string_value(source));
+        }
+        if let Some(meta_version) = self.meta_version {
+            map.insert(
+                b"meta version"[..].to_vec(),
+                Value::Integer(meta_version as isize),
+            );
+            map.insert(b"file tree"[..].to_vec(), file_tree_value(&self.file_tree));
+        }
+        if self.has_v2() && !self.has_v1() {
+            map.remove(&b"pieces"[..]);
+            map.remove(&b"files"[..]);
+            map.remove(&b"length"[..]);
+        }
         Value::Dict(map)
     }
 
+    /// The v1 files of a hybrid torrent, padding files aside, must be those of
+    /// the file tree and both must have the same pieces.
+    fn check_hybrid(&self) -> anyhow::Result<()> {
+        let v1_files: Vec<(u64, &[String])> = match &self.files {
+            Some(files) => files
+                .iter()
+                .filter(|f| !f.is_padding())
+                .map(|f| (f.length, f.path.as_slice()))
+                .collect(),
+            None => vec![(self.length, std::slice::from_ref(&self.name))],
+        };
+        let v2_files: Vec<(u64, &[String])> = self
+            .file_tree
+            .iter()
+            .map(|f| (f.length, f.path.as_slice()))
+            .collect();
+        if v1_files != v2_files {
+            return Err(Error::msg("hybrid torrent has different v1 and v2 files"));
+        }
+        if self.pieces.len() != self.tree_piece_count() {
+            return Err(Error::msg(
+                "hybrid torrent has a different number of v1 and v2 pieces",
+            ));
+        }
+        Ok(())
+    }
+
+    /// has the SHA-1 `pieces` of v1 and hybrid torrents
+    pub fn has_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    /// has the `file tree` of v2 and hybrid torrents
+    pub fn has_v2(&self) -> bool {
+        self.meta_version == Some(2)
+    }
+
+    /// number of pieces of the torrent
+    pub fn piece_count(&self) -> usize {
+        if self.has_v1() {
+            self.pieces.len()
+        } else {
+            self.tree_piece_count()
+        }
+    }
+
+    /// Number of v2 pieces. Every file starts at a piece boundary, so its last
+    /// piece may be short.
+    fn tree_piece_count(&self) -> usize {
+        self.file_tree
+            .iter()
+            .map(|f| f.length.div_ceil(self.piece_length as u64) as usize)
+            .sum()
+    }
+
+    /// the file of the file tree piece `index` belongs to, and the piece's index within it
+    pub fn tree_piece(&self, index: u32) -> Option<(&TreeFile, u32)> {
+        let mut first = 0;
+        for file in &self.file_tree {
+            let npieces = file.length.div_ceil(self.piece_length as u64) as u32;
+            if index < first + npieces {
+                return Some((file, index - first));
+            }
+            first += npieces;
+        }
+        None
+    }
+
+    /// Check that `piece_layers` holds a layer matching the pieces root of every
+    /// file larger than a piece. v2-only torrents can't be verified without them.
+    pub fn check_piece_layers(
+        &self,
+        piece_layers: &BTreeMap<[u8; 32], Vec<[u8; 32]>>,
+    ) -> anyhow::Result<()> {
+        for file in &self.file_tree {
+            let Some(root) = file.pieces_root else {
+                continue;
+            };
+            if file.length <= self.piece_length as u64 {
+                continue;
+            }
+            let Some(layer) = piece_layers.get(&root) else {
+                if self.has_v1() {
+                    continue;
+                }
+                return Err(Error::msg(format!(
+                    "no piece layer for {}",
+                    file.path.join("/")
+                )));
+            };
+            let npieces = file.length.div_ceil(self.piece_length as u64) as usize;
+            if layer.len() != npieces || layer_root(layer, self.piece_length) != root {
+                return Err(Error::msg(format!(
+                    "piece layer of {} doesn't match its pieces root",
+                    file.path.join("/")
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// length in bytes of the piece at `index`, the last piece may be shorter
+    /// and so may the last piece of every file of a v2-only torrent
+    pub fn piece_len(&self, index: u32) -> u32 {
+        if !self.has_v1() {
+            return match self.tree_piece(index) {
+                Some((file, piece)) => (file.length - piece as u64 * self.piece_length as u64)
+                    .min(self.piece_length as u64) as u32,
+                None => 0,
+            };
+        }
+        let npieces = self.pieces.len() as u32;
+        let rem = (self.length % self.piece_length as u64) as u32;
+        if index == npieces - 1 && rem != 0 {
+            rem
+        } else {
+            self.piece_length
+        }
+    }
+
+    /// the encoded info dictionary, as read if it was
+    pub fn encoded(&self) -> Vec<u8> {
+        match &self.raw {
+            Some(raw) => raw.clone(),
+            None => self.to_value().encode(),
+        }
+    }
+
+    /// the v1 info hash, SHA-1 of the info dictionary
     pub fn hash(&self) -> [u8; 20] {
-        let bencode = self.to_value().encode();
         let mut hasher = Sha1::new();
-        hasher.update(&bencode);
+        hasher.update(self.encoded());
         hasher.finalize().into()
     }
+
+    /// the v2 info hash, SHA-256 of the info dictionary
+    pub fn hash_v2(&self) -> Option<[u8; 32]> {
+        self.has_v2().then(|| sha256(&self.encoded()))
+    }
+
+    /// The info hash used with trackers and peers: the v1 hash, or for
+    /// v2-only torrents the v2 hash truncated to 20 bytes.
+    pub fn info_hash(&self) -> [u8; 20] {
+        match self.hash_v2() {
+            Some(hash) if !self.has_v1() => std::array::from_fn(|i| hash[i]),
+            _ => self.hash(),
+        }
+    }
+}
+
+impl FileInfo {
+    /// a padding file, filling up the previous file to a piece boundary
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p')
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_attr('x')
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.has_attr('h')
+    }
+
+    /// a symlink to `symlink_path`, it has no data of its own
+    pub fn is_symlink(&self) -> bool {
+        self.has_attr('l')
+    }
+
+    fn has_attr(&self, flag: char) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains(flag))
+    }
+
+    pub fn from_value(value: &Value) -> anyhow::Result<Self> {
+        if let Value::Dict(file) = value {
+            let length = match file.get(&b"length"[..]) {
+                Some(Value::Integer(a)) if *a >= 0 => *a as u64,
+                _ => return Err(Error::msg("cannot get Length of file")),
+            };
+
+            let path = if let Some(Value::Array(path)) = file.get(&b"path"[..]) {
+                path.iter()
+                    .map(utf8_string)
+                    .collect::<anyhow::Result<Vec<String>>>()?
+            } else {
+                return Err(Error::msg("cannot get Path of file"));
+            };
+            check_path(&path)?;
+
+            let attr = file.get(&b"attr"[..]).map(utf8_string).transpose()?;
+            let symlink_path = match file.get(&b"symlink path"[..]) {
+                Some(Value::Array(target)) => Some(
+                    target
+                        .iter()
+                        .map(utf8_string)
+                        .collect::<anyhow::Result<Vec<String>>>()?,
+                ),
+                _ => None,
+            };
+            let file = Self {
+                length,
+                path,
+                attr,
+                symlink_path,
+            };
+            match &file.symlink_path {
+                None if file.is_symlink() => {
+                    return Err(Error::msg(format!(
+                        "symlink {} has no symlink path",
+                        file.path.join("/")
+                    )))
+                }
+                // the target is relative to the torrent directory and must stay in it
+                Some(target) if file.is_symlink() => check_path(target)
+                    .with_context(|| format!("symlink {}", file.path.join("/")))?,
+                _ => {}
+            }
+            Ok(file)
+        } else {
+            Err(Error::msg("Provided value is not dictionary"))
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut map = BTreeMap::new();
+        map.insert(b"length"[..].to_vec(), Value::Integer(self.length as isize));
+        if let Some(attr) = &self.attr {
+            map.insert(b"attr"[..].to_vec(), string_value(attr));
+        }
+        if let Some(target) = &self.symlink_path {
+            map.insert(
+                b"symlink path"[..].to_vec(),
+                Value::Array(target.iter().map(|p| string_value(p)).collect()),
+            );
+        }
+        map.insert(
+            b"path"[..].to_vec(),
+            Value::Array(self.path.iter().map(|p| string_value(p)).collect()),
+        );
+        Value::Dict(map)
+    }
+}
+
+/// Collect the files of a `file tree` node in key order. `path` leads to the node.
+fn parse_file_tree(
+    node: &Value,
+    path: &mut Vec<String>,
+    files: &mut Vec<TreeFile>,
+) -> anyhow::Result<()> {
+    let Value::Dict(entries) = node else {
+        return Err(Error::msg("file tree node must be a dictionary"));
+    };
+    for (name, child) in entries {
+        if name.is_empty() {
+            // the node is a file, its properties under the empty key
+            let Value::Dict(props) = child else {
+                return Err(Error::msg("file tree entry must be a dictionary"));
+            };
+            let length = match props.get(&b"length"[..]) {
+                Some(Value::Integer(len)) if *len >= 0 => *len as u64,
+                _ => return Err(Error::msg("cannot get length of file tree entry")),
+            };
+            let pieces_root = match props.get(&b"pieces root"[..]) {
+                Some(Value::String(root)) => Some(
+                    <[u8; 32]>::try_from(root.as_slice())
+                        .map_err(|_| Error::msg("pieces root must be 32 bytes"))?,
+                ),
+                None if length == 0 => None,
+                _ => return Err(Error::msg("file tree entry has no pieces root")),
+            };
+            if path.is_empty() {
+                return Err(Error::msg("file tree entry has no name"));
+            }
+            files.push(TreeFile {
+                length,
+                path: path.clone(),
+                pieces_root,
+            });
+            continue;
+        }
+        let name = String::from_utf8(name.clone()).context("file name must be UTF-8 encoded")?;
+        check_path_component(&name)?;
+        path.push(name);
+        parse_file_tree(child, path, files)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+/// the `file tree` dictionary of `files`
+fn file_tree_value(files: &[TreeFile]) -> Value {
+    let mut root = BTreeMap::new();
+    for file in files {
+        let mut node = &mut root;
+        for name in &file.path {
+            let child = node
+                .entry(name.as_bytes().to_vec())
+                .or_insert_with(|| Value::Dict(BTreeMap::new()));
+            let Value::Dict(child) = child else {
+                unreachable!("file tree nodes are dictionaries")
+            };
+            node = child;
+        }
+        let mut props = BTreeMap::new();
+        props.insert(b"length"[..].to_vec(), Value::Integer(file.length as isize));
+        if let Some(root) = file.pieces_root {
+            props.insert(b"pieces root"[..].to_vec(), Value::String(root.to_vec()));
+        }
+        node.insert(Vec::new(), Value::Dict(props));
+    }
+    Value::Dict(root)
+}
+
+/// Check that a file path from a torrent stays inside the torrent directory
+/// once joined onto it.
+pub fn check_path(path: &[String]) -> anyhow::Result<()> {
+    if path.is_empty() {
+        return Err(Error::msg("file path is empty"));
+    }
+    path.iter().try_for_each(|c| check_path_component(c))
+}
+
+/// Reject files inside, or in place of, a symlink of the torrent, so nothing is
+/// written through a link the torrent created.
+fn check_symlinks(files: &[FileInfo]) -> anyhow::Result<()> {
+    let links: HashSet<&[String]> = files
+        .iter()
+        .filter(|f| f.is_symlink())
+        .map(|f| f.path.as_slice())
+        .collect();
+    if links.is_empty() {
+        return Ok(());
+    }
+    for file in files {
+        let through = (1..=file.path.len())
+            .map(|n| &file.path[..n])
+            .filter(|prefix| prefix.len() < file.path.len() || !file.is_symlink())
+            .find(|prefix| links.contains(prefix));
+        if let Some(link) = through {
+            return Err(Error::msg(format!(
+                "file {} would be written through symlink {}",
+                file.path.join("/"),
+                link.join("/")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// A single normal component: not empty, `.` or `..`, not absolute and
+/// without separators of any platform.
+fn check_path_component(component: &str) -> anyhow::Result<()> {
+    let mut components = std::path::Path::new(component).components();
+    let normal = matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(c)), None) if c == component
+    );
+    if !normal || component.contains(['/', '\\', '\0']) {
+        return Err(Error::msg(format!(
+            "invalid file path component {:?}",
+            component
+        )));
+    }
+    Ok(())
+}
+
+fn utf8_string(value: &Value) -> anyhow::Result<String> {
+    if let Value::String(s) = value {
+        String::from_utf8(s.clone()).context("string must be UTF-8 encoded")
+    } else {
+        Err(Error::msg("expected bencode string"))
+    }
+}
+
+fn string_value(s: &str) -> Value {
+    Value::String(s.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict<const N: usize>(entries: [(&str, Value); N]) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    fn hex32(s: &str) -> [u8; 32] {
+        hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    const BIG_ROOT: &str = "33e2d18ace9db35babb7c73b9d83d7c5b5d7dc18663225ca17ac3d3e9cb6a306";
+    const SMALL_ROOT: &str = "7f76d7d4d2be22143bfcedbe16b6e06f91c20ba17178edeb5c3a02d31734dcf3";
+
+    fn big_file() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn small_file() -> Vec<u8> {
+        b"hello v2\n".repeat(100)
+    }
+
+    /// A v2-only torrent of `big.bin`, spanning four 32 KiB pieces, and
+    /// `small.txt`. Roots and info hash computed independently with Python's
+    /// hashlib.
+    fn v2_torrent() -> Torrent {
+        let entry = |length: isize, root: &str| {
+            dict([(
+                "",
+                dict([
+                    ("length", Value::Integer(length)),
+                    ("pieces root", Value::String(hex32(root).to_vec())),
+                ]),
+            )])
+        };
+        let info = dict([
+            (
+                "file tree",
+                dict([
+                    ("big.bin", entry(100_000, BIG_ROOT)),
+                    ("small.txt", entry(900, SMALL_ROOT)),
+                ]),
+            ),
+            ("meta version", Value::Integer(2)),
+            ("name", Value::String(b"v2test".to_vec())),
+            ("piece length", Value::Integer(32768)),
+        ]);
+        let layer = big_file()
+            .chunks(32768)
+            .map(|piece| piece_hash(piece, 32768))
+            .collect();
+        Torrent {
+            announce: Vec::new(),
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: Vec::new(),
+            info: Info::from_value(&info).unwrap(),
+            piece_layers: BTreeMap::from([(hex32(BIG_ROOT), layer)]),
+        }
+    }
+
+    #[test]
+    fn parses_v2_torrents() {
+        let torrent = v2_torrent();
+        let info = &torrent.info;
+        assert!(info.has_v2());
+        assert!(!info.has_v1());
+        assert_eq!(info.length, 100_900);
+        assert_eq!(
+            info.hash_v2().map(hex::encode).as_deref(),
+            Some("8db7fb628ab43c91d13001f953930284763a3596568a709556f9f71e15dcf99f")
+        );
+        assert_eq!(info.info_hash()[..], info.hash_v2().unwrap()[..20]);
+        assert_eq!(
+            info.file_tree
+                .iter()
+                .map(|f| f.path.join("/"))
+                .collect::<Vec<_>>(),
+            ["big.bin", "small.txt"]
+        );
+        let files = info.files.as_ref().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].path, ["small.txt"]);
+
+        // every file starts a new piece
+        assert_eq!(info.piece_count(), 5);
+        let lengths: Vec<u32> = (0..5).map(|i| info.piece_len(i)).collect();
+        assert_eq!(lengths, [32768, 32768, 32768, 1696, 900]);
+        assert_eq!(
+            info.tree_piece(4).map(|(f, i)| (f.length, i)),
+            Some((900, 0))
+        );
+        assert!(info.tree_piece(5).is_none());
+    }
+
+    #[test]
+    fn checks_piece_layers_against_roots() {
+        let torrent = v2_torrent();
+        let info = &torrent.info;
+        info.check_piece_layers(&torrent.piece_layers).unwrap();
+        assert!(info.check_piece_layers(&BTreeMap::new()).is_err());
+
+        let mut layers = torrent.piece_layers.clone();
+        layers.get_mut(&hex32(BIG_ROOT)).unwrap()[2][0] ^= 1;
+        assert!(info.check_piece_layers(&layers).is_err());
+        let mut layers = torrent.piece_layers.clone();
+        layers.get_mut(&hex32(BIG_ROOT)).unwrap().pop();
+        assert!(info.check_piece_layers(&layers).is_err());
+    }
+
+    #[test]
+    fn verifies_v2_pieces() {
+        let torrent = v2_torrent();
+        let big = big_file();
+        for (index, piece) in big.chunks(32768).enumerate() {
+            assert!(torrent.verify_piece(index as u32, piece), "piece {}", index);
+        }
+        assert!(torrent.verify_piece(4, &small_file()));
+
+        let mut corrupt = big[..32768].to_vec();
+        corrupt[100] ^= 1;
+        assert!(!torrent.verify_piece(0, &corrupt));
+        assert!(!torrent.verify_piece(1, &big[..32768]));
+        assert!(!torrent.verify_piece(4, &small_file()[..899]));
+        assert!(!torrent.verify_piece(5, &small_file()));
+    }
+
+    /// info dictionary of a single-file v1 torrent, `entries` added or replaced
+    fn v1_info<const N: usize>(entries: [(&str, Value); N]) -> Value {
+        let Value::Dict(mut info) = dict([
+            ("length", Value::Integer(40_000)),
+            ("name", Value::String(b"file.bin".to_vec())),
+            ("piece length", Value::Integer(16384)),
+            ("pieces", Value::String(vec![0; 60])),
+        ]) else {
+            unreachable!("dict returns a dictionary")
+        };
+        for (key, value) in entries {
+            info.insert(key.as_bytes().to_vec(), value);
+        }
+        Value::Dict(info)
+    }
+
+    #[test]
+    fn checks_piece_length_and_count() {
+        let info = Info::from_value(&v1_info([])).unwrap();
+        assert_eq!(info.pieces.len(), 3);
+        assert_eq!(info.piece_len(2), 40_000 - 2 * 16384);
+
+        for (key, value) in [
+            ("piece length", Value::Integer(0)),
+            ("piece length", Value::Integer(-16384)),
+            ("piece length", Value::Integer(1 << 32)),
+            ("pieces", Value::String(vec![0; 59])),
+            ("pieces", Value::String(vec![0; 40])),
+            ("pieces", Value::String(vec![0; 80])),
+            ("length", Value::Integer(-1)),
+        ] {
+            let err = Info::from_value(&v1_info([(key, value.clone())]));
+            assert!(err.is_err(), "{} {:?}", key, value);
+        }
+    }
+
+    fn file_entry(path: &[&str], attr: Option<&str>, target: Option<&[&str]>) -> Value {
+        let strings = |parts: &[&str]| {
+            Value::Array(
+                parts
+                    .iter()
+                    .map(|p| Value::String(p.as_bytes().to_vec()))
+                    .collect(),
+            )
+        };
+        let Value::Dict(mut file) = dict([
+            (
+                "length",
+                Value::Integer(if target.is_some() { 0 } else { 100 }),
+            ),
+            ("path", strings(path)),
+        ]) else {
+            unreachable!("dict returns a dictionary")
+        };
+        if let Some(attr) = attr {
+            file.insert(b"attr".to_vec(), Value::String(attr.as_bytes().to_vec()));
+        }
+        if let Some(target) = target {
+            file.insert(b"symlink path".to_vec(), strings(target));
+        }
+        Value::Dict(file)
+    }
+
+    /// a multi-file torrent of `files`, with a piece hash for every 16 KiB
+    fn multi_file_info(files: Vec<Value>) -> anyhow::Result<Info> {
+        let length: isize = files
+            .iter()
+            .map(|f| match f {
+                Value::Dict(f) => match f[&b"length"[..]] {
+                    Value::Integer(len) => len,
+                    _ => 0,
+                },
+                _ => 0,
+            })
+            .sum();
+        let npieces = (length as usize).div_ceil(16384);
+        let Value::Dict(mut info) = v1_info([
+            ("files", Value::Array(files)),
+            ("pieces", Value::String(vec![0; 20 * npieces])),
+        ]) else {
+            unreachable!("v1_info returns a dictionary")
+        };
+        info.remove(&b"length"[..]);
+        Info::from_value(&Value::Dict(info))
+    }
+
+    #[test]
+    fn rejects_paths_leaving_the_download_directory() {
+        multi_file_info(vec![
+            file_entry(&["dir", "a.txt"], None, None),
+            file_entry(&["..a", "b..", ".hidden"], None, None),
+        ])
+        .unwrap();
+
+        for path in [
+            &[][..],
+            &[".."],
+            &["dir", "..", "..", "etc"],
+            &["."],
+            &[""],
+            &["/etc/passwd"],
+            &["dir/../.."],
+            &["dir\\..\\x"],
+            &["a\0b"],
+        ] {
+            let result = multi_file_info(vec![file_entry(path, None, None)]);
+            assert!(result.is_err(), "{:?}", path);
+        }
+        for name in ["..", ".", "", "a/b", "/etc", "a\\b"] {
+            let info = v1_info([("name", Value::String(name.as_bytes().to_vec()))]);
+            assert!(Info::from_value(&info).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_file_tree_names_leaving_the_download_directory() {
+        let info = |name: &str| {
+            let tree = dict([(
+                name,
+                dict([(
+                    "",
+                    dict([
+                        ("length", Value::Integer(900)),
+                        ("pieces root", Value::String(hex32(SMALL_ROOT).to_vec())),
+                    ]),
+                )]),
+            )]);
+            Info::from_value(&dict([
+                ("file tree", tree),
+                ("meta version", Value::Integer(2)),
+                ("name", Value::String(b"v2test".to_vec())),
+                ("piece length", Value::Integer(32768)),
+            ]))
+        };
+        info("small.txt").unwrap();
+        for name in ["..", ".", "a/b", "/abs", "a\\b"] {
+            assert!(info(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn checks_symlinks() {
+        let info = multi_file_info(vec![
+            file_entry(&["data", "a.txt"], None, None),
+            file_entry(&["latest"], Some("l"), Some(&["data", "a.txt"])),
+        ])
+        .unwrap();
+        let files = info.files.unwrap();
+        assert!(files[1].is_symlink());
+        assert_eq!(
+            files[1].symlink_path.as_deref(),
+            Some(&["data".to_string(), "a.txt".to_string()][..])
+        );
+
+        // the target must stay inside the torrent directory
+        assert!(multi_file_info(vec![
+            file_entry(&["a.txt"], None, None),
+            file_entry(&["link"], Some("l"), Some(&["..", "etc"])),
+        ])
+        .is_err());
+        assert!(multi_file_info(vec![
+            file_entry(&["a.txt"], None, None),
+            file_entry(&["link"], Some("l"), None),
+        ])
+        .is_err());
+        // nothing may be written through, or in place of, a symlink
+        assert!(multi_file_info(vec![
+            file_entry(&["link"], Some("l"), Some(&["data"])),
+            file_entry(&["link", "a.txt"], None, None),
+        ])
+        .is_err());
+        assert!(multi_file_info(vec![
+            file_entry(&["link"], Some("l"), Some(&["data"])),
+            file_entry(&["link"], None, None),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn embeds_info_bytes_unchanged() {
+        // keys this crate doesn't model, in an order it wouldn't write them in
+        let info: &[u8] = b"d6:lengthi40000e4:name8:file.bin12:piece lengthi16384e\
+            6:pieces60:000000000000000000000000000000000000000000000000000000000000\
+            1:xli1ei2ee1:yd1:zi3eee";
+        let mut parsed = Info::from_value(&Value::decode(info).unwrap().0).unwrap();
+        parsed.raw = Some(info.to_vec());
+        let torrent = Torrent {
+            announce: b"http://t/announce".to_vec(),
+            announce_list: Vec::new(),
+            comment: Some("c".to_string()),
+            created_by: None,
+            creation_date: Some(1),
+            url_list: vec!["http://seed/".to_string()],
+            info: parsed,
+            piece_layers: BTreeMap::new(),
+        };
+
+        let encoded = torrent.encode_with_info(info);
+        assert_eq!(raw_dict_value(&encoded, b"info").unwrap(), Some(info));
+        let (value, rest) = Value::decode(&encoded).unwrap();
+        assert!(rest.is_empty());
+        // the keys around the info dictionary stay sorted
+        assert_eq!(value.encode(), encoded);
+
+        let mut reparsed = Torrent::from_value(&value).unwrap();
+        reparsed.info.raw = raw_dict_value(&encoded, b"info")
+            .unwrap()
+            .map(<[u8]>::to_vec);
+        assert_eq!(reparsed.info.info_hash(), torrent.info.info_hash());
+        assert_eq!(
+            reparsed.info.info_hash(),
+            <[u8; 20]>::from(Sha1::digest(info))
+        );
+        assert_eq!(reparsed.url_list, torrent.url_list);
+        assert_eq!(reparsed.comment.as_deref(), Some("c"));
+    }
 }
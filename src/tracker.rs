@@ -5,10 +5,12 @@ use std::net::SocketAddrV4;
 use crate::torrent::TorrentInfo;
 use crate::value::Value;
 
+/// port announced to trackers when the caller doesn't listen on a specific one
+pub const DEFAULT_PORT: u16 = 6881;
+
 pub struct TrackerResponse {
     /// An integer, indicating how often your client should make a request to the tracker.
-    /// You can ignore this value for the purposes of this challenge.
-    // interval: usize,
+    pub interval: u64,
     /// A string, contains list of peers that your client can connect to.
     /// Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number
     pub peers: Vec<u8>,
@@ -17,12 +19,24 @@ pub struct TrackerResponse {
 impl TrackerResponse {
     pub fn from_value(value: Value) -> anyhow::Result<Self> {
         if let Value::Dict(res) = value {
+            if let Some(Value::String(reason)) = res.get(&b"failure reason"[..]) {
+                return Err(anyhow::Error::msg(format!(
+                    "tracker failure: {}",
+                    String::from_utf8_lossy(reason)
+                )));
+            }
             let peers = if let Some(Value::String(v)) = res.get(&b"peers"[..]) {
                 v
             } else {
                 return Err(anyhow::Error::msg("no peers in tracker response"));
             };
+            let interval = if let Some(Value::Integer(i)) = res.get(&b"interval"[..]) {
+                *i as u64
+            } else {
+                1800
+            };
             return Ok(Self {
+                interval,
                 peers: peers.clone(),
             });
         }
@@ -31,25 +45,71 @@ impl TrackerResponse {
             "failed to parse tracker response from value",
         ))
     }
+
+    /// decode the compact peer list
+    pub fn peer_addresses(&self) -> Vec<SocketAddrV4> {
+        self.peers
+            .chunks_exact(6)
+            .map(|peer| {
+                SocketAddrV4::new(
+                    Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]),
+                    u16::from_be_bytes([peer[4], peer[5]]),
+                )
+            })
+            .collect()
+    }
 }
 
 /// Query Params for making Get requet to Tracker
 pub struct TrackerRequest {
     /// 20 bytes long info hash of the torrent need to be URL encoded
     pub info_hash: [u8; 20],
-    /// port your client is listening on set 6881 for this challenge
-    pub port: u32,
+    /// port your client is listening on
+    pub port: u16,
     /// a unique identifier for your client of length 20 that you get to pick.
     pub peer_id: [u8; 20],
     /// the total amount uploaded so far, 0 as default
-    pub uploaded: u32,
+    pub uploaded: u64,
     /// the total amount downloaded so far, 0 as default
-    pub downloaded: u32,
+    pub downloaded: u64,
     /// number of bytes left to download, total length of file as default
-    pub left: u32,
+    pub left: u64,
     // whether the peer list should use the compact representation
     // set true as default. used mostly for backward compatibily
     pub compact: u32,
+    /// one of `started`, `completed` or `stopped`, None for regular announces
+    pub event: Option<&'static str>,
+}
+
+impl TrackerRequest {
+    /// the url announcing this request to the tracker at `announce_url`
+    pub fn url(&self, announce_url: &str) -> anyhow::Result<String> {
+        let info_hash_url = self.info_hash.iter().fold(String::new(), |mut acc, c| {
+            acc.push('%');
+            acc.push_str(&format!("{:02x}", c));
+            acc
+        });
+
+        // private trackers often carry a passkey in the query already
+        let separator = if announce_url.contains('?') { '&' } else { '?' };
+        let mut request_url = format!(
+            "{}{}port={}&peer_id={}&uploaded={}&downloaded={}&left={}&compact={}&info_hash={}",
+            announce_url,
+            separator,
+            self.port,
+            String::from_utf8(self.peer_id.to_vec())?,
+            self.uploaded,
+            self.downloaded,
+            self.left,
+            self.compact,
+            info_hash_url
+        );
+        if let Some(event) = self.event {
+            request_url.push_str("&event=");
+            request_url.push_str(event);
+        }
+        Ok(request_url)
+    }
 }
 
 pub async fn get_peers(
@@ -58,46 +118,67 @@ pub async fn get_peers(
 ) -> anyhow::Result<Vec<SocketAddrV4>> {
     let tracker = TrackerRequest {
         info_hash: torrent.info_hash(),
-        port: 6881,
-        peer_id: my_peer_id.clone(),
+        port: DEFAULT_PORT,
+        peer_id: *my_peer_id,
         uploaded: 0,
         downloaded: 0,
         left: torrent.length(),
         compact: 1,
+        event: None,
     };
 
-    let info_hash_url = tracker.info_hash.iter().fold(String::new(), |mut acc, c| {
-        acc.push('%');
-        acc.push_str(&format!("{:02x}", c));
-        acc
-    });
-
-    let request_url = format!(
-        "{}?port={}&peer_id={}&uploaded={}&downloaded={}&left={}&compact={}&info_hash={}",
-        torrent.announce(),
-        tracker.port,
-        String::from_utf8(tracker.peer_id.to_vec())?,
-        tracker.uploaded,
-        tracker.downloaded,
-        tracker.left,
-        tracker.compact,
-        info_hash_url
-    );
+    let tracker_res = announce(&torrent.announce(), &tracker).await?;
+    Ok(tracker_res.peer_addresses())
+}
 
+/// send `request` to the tracker at `announce_url`
+pub async fn announce(
+    announce_url: &str,
+    tracker: &TrackerRequest,
+) -> anyhow::Result<TrackerResponse> {
+    let request_url = tracker.url(announce_url)?;
     let response = reqwest::get(&request_url).await.context("query tracker")?;
 
     let (value, _rest) = Value::decode(&response.bytes().await?)?;
-    let tracker_res = TrackerResponse::from_value(value)?;
+    TrackerResponse::from_value(value)
+}
 
-    let mut peers = Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for peer in tracker_res.peers.chunks(6) {
-        let peer = SocketAddrV4::new(
-            Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]),
-            u16::from_be_bytes([peer[4], peer[5]]),
+    fn request() -> TrackerRequest {
+        TrackerRequest {
+            info_hash: [0xab; 20],
+            port: 6881,
+            peer_id: *b"-RS0001-123456789012",
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            compact: 1,
+            event: Some("started"),
+        }
+    }
+
+    #[test]
+    fn builds_announce_urls() {
+        let url = request().url("http://tracker/announce").unwrap();
+        assert_eq!(
+            url,
+            format!(
+                "http://tracker/announce?port=6881&peer_id=-RS0001-123456789012\
+                 &uploaded=1&downloaded=2&left=3&compact=1&info_hash={}&event=started",
+                "%ab".repeat(20)
+            )
         );
-        peers.push(peer);
     }
 
-    Ok(peers)
+    #[test]
+    fn appends_to_an_existing_query() {
+        let url = request()
+            .url("http://tracker/announce?passkey=abc")
+            .unwrap();
+        assert!(url.starts_with("http://tracker/announce?passkey=abc&port=6881&peer_id="));
+        assert_eq!(url.matches('?').count(), 1);
+    }
 }
@@ -136,93 +136,221 @@ impl Value {
     }
 }
 
-pub fn decode_string(mut encoded_value: &[u8]) -> anyhow::Result<(Value, &[u8])> {
-    let mut len: usize = 0;
-    let mut index = 0;
-
-    while encoded_value[index].is_ascii_digit() && encoded_value[index] != b':' {
-        let value = encoded_value[index] - b'0';
-        len *= 10;
-        len += value as usize;
-        index += 1;
-    }
-    // len /= 10;
-    index += 1; // skip b':'
-    encoded_value = &encoded_value[index..];
-
-    let string = encoded_value[..len].to_vec();
-    Ok((Value::String(string), &encoded_value[len..]))
+/// lists and dictionaries nested deeper than this are rejected, so hostile
+/// input can't exhaust the stack
+pub const MAX_DEPTH: usize = 64;
+
+pub fn decode_string(encoded_value: &[u8]) -> anyhow::Result<(Value, &[u8])> {
+    let digits = encoded_value
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    if digits == 0 || encoded_value.get(digits) != Some(&b':') {
+        return Err(Error::msg("string must start with its length and a colon"));
+    }
+    let len: usize = std::str::from_utf8(&encoded_value[..digits])?
+        .parse()
+        .map_err(|_| Error::msg("string length is too large"))?;
+    let rest = &encoded_value[digits + 1..];
+    if rest.len() < len {
+        return Err(Error::msg(format!(
+            "string of {} bytes runs past the end of the input",
+            len
+        )));
+    }
+    Ok((Value::String(rest[..len].to_vec()), &rest[len..]))
 }
 
 pub fn decode_integer(encoded_value: &[u8]) -> anyhow::Result<(Value, &[u8])> {
-    if encoded_value[0] == b'i' {
-        // b'i'
-        let mut len = 1;
-        let integer = encoded_value[1..]
-            .iter()
-            .take_while(|c| {
-                len += 1;
-                **c != b'e'
-            })
-            .map(|c| *c as char)
-            .collect::<String>()
-            .parse::<isize>()
-            .unwrap();
-
-        let encoded_value = &encoded_value[len..];
-
-        return Ok((Value::Integer(integer), encoded_value));
-    }
-
-    Err(Error::msg("Failed decoding Integer"))
+    let Some(rest) = encoded_value.strip_prefix(b"i") else {
+        return Err(Error::msg("Failed decoding Integer"));
+    };
+    let end = rest
+        .iter()
+        .position(|c| *c == b'e')
+        .ok_or_else(|| Error::msg("integer is not terminated"))?;
+    let integer = std::str::from_utf8(&rest[..end])
+        .ok()
+        .and_then(|i| i.parse::<isize>().ok())
+        .ok_or_else(|| Error::msg("invalid integer"))?;
+    Ok((Value::Integer(integer), &rest[end + 1..]))
 }
 
-fn decode_list(encoded_value: &[u8]) -> anyhow::Result<(Value, &[u8])> {
-    if encoded_value[0] == b'l' {
-        let mut values = vec![];
-        let mut rest = &encoded_value[1..];
-        loop {
-            if rest[0] == b'e' {
-                break;
+fn decode_list(encoded_value: &[u8], depth: usize) -> anyhow::Result<(Value, &[u8])> {
+    let Some(mut rest) = encoded_value.strip_prefix(b"l") else {
+        return Err(Error::msg("Failed decoding List"));
+    };
+    let mut values = vec![];
+    loop {
+        match rest.first() {
+            Some(b'e') => return Ok((Value::Array(values), &rest[1..])),
+            Some(_) => {
+                let (value, rest_inner) = decode_nested(rest, depth + 1)?;
+                values.push(value);
+                rest = rest_inner;
             }
-            let (value, rest_inner) = decode_bencoded_value(rest)?;
-            values.push(value);
-            rest = rest_inner;
+            None => return Err(Error::msg("list is not terminated")),
         }
-
-        return Ok((Value::Array(values), &rest[1..]));
     }
-    Err(Error::msg("Failed decoding List"))
 }
 
 pub fn decode_dict(encoded_value: &[u8]) -> anyhow::Result<(Value, &[u8])> {
-    if encoded_value[0] == b'd' {
-        let mut values = BTreeMap::new();
-        let mut rest = &encoded_value[1..];
-        loop {
-            if rest[0] == b'e' {
-                break;
-            }
-            let (key, rest_inner) = decode_string(rest)?;
-            let (value, rest_inner) = decode_bencoded_value(rest_inner)?;
-            // NOTE: key must be string in bencoded value
-            if let Value::String(s) = key {
-                values.insert(s, value);
+    decode_dict_at(encoded_value, 0)
+}
+
+fn decode_dict_at(encoded_value: &[u8], depth: usize) -> anyhow::Result<(Value, &[u8])> {
+    let Some(mut rest) = encoded_value.strip_prefix(b"d") else {
+        return Err(Error::msg("Failed decoding Dict"));
+    };
+    let mut values = BTreeMap::new();
+    loop {
+        match rest.first() {
+            Some(b'e') => return Ok((Value::Dict(values), &rest[1..])),
+            Some(_) => {
+                // keys are always strings
+                let (Value::String(key), rest_inner) = decode_string(rest)? else {
+                    unreachable!("decode_string returns a string")
+                };
+                let (value, rest_inner) = decode_nested(rest_inner, depth + 1)?;
+                values.insert(key, value);
+                rest = rest_inner;
             }
-            rest = rest_inner;
+            None => return Err(Error::msg("dictionary is not terminated")),
         }
-
-        return Ok((Value::Dict(values), &rest[1..]));
     }
-    Err(Error::msg("Failed decoding Dict"))
 }
 
+/// Decode the value at the start of `encoded_value`, returning it and the
+/// bytes after it. Malformed or truncated input is an error, never a panic.
 pub fn decode_bencoded_value(encoded_value: &[u8]) -> anyhow::Result<(Value, &[u8])> {
-    match encoded_value[0] {
-        b'i' => decode_integer(encoded_value),
-        b'l' => decode_list(encoded_value),
-        b'd' => decode_dict(encoded_value),
-        c if c.is_ascii_digit() => decode_string(encoded_value),
-        _ => Err(anyhow::Error::msg("cannot parse value")),
+    decode_nested(encoded_value, 0)
+}
+
+/// decode a value inside `depth` lists or dictionaries
+fn decode_nested(encoded_value: &[u8], depth: usize) -> anyhow::Result<(Value, &[u8])> {
+    if depth > MAX_DEPTH {
+        return Err(Error::msg(format!(
+            "values are nested more than {} deep",
+            MAX_DEPTH
+        )));
+    }
+    match encoded_value.first() {
+        Some(b'i') => decode_integer(encoded_value),
+        Some(b'l') => decode_list(encoded_value, depth),
+        Some(b'd') => decode_dict_at(encoded_value, depth),
+        Some(c) if c.is_ascii_digit() => decode_string(encoded_value),
+        Some(_) => Err(anyhow::Error::msg("cannot parse value")),
+        None => Err(anyhow::Error::msg("unexpected end of bencoded value")),
+    }
+}
+
+/// Bytes of the value of `key` in the bencoded dictionary `encoded`, exactly
+/// as they appear, e.g. to hash a torrent's info dictionary as it was written.
+pub fn raw_dict_value<'a>(encoded: &'a [u8], key: &[u8]) -> anyhow::Result<Option<&'a [u8]>> {
+    let Some(mut rest) = encoded.strip_prefix(b"d") else {
+        return Err(Error::msg("expected a dictionary"));
+    };
+    loop {
+        match rest.first() {
+            Some(b'e') => return Ok(None),
+            Some(_) => {}
+            None => return Err(Error::msg("dictionary is not terminated")),
+        }
+        let (k, after_key) = decode_string(rest)?;
+        let (_, after_value) = decode_bencoded_value(after_key)?;
+        if k == Value::String(key.to_vec()) {
+            return Ok(Some(&after_key[..after_key.len() - after_value.len()]));
+        }
+        rest = after_value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_and_encodes() {
+        let encoded = b"d4:listli-3e3:abce6:nestedde3:numi42ee5:tail";
+        let (value, rest) = Value::decode(encoded).unwrap();
+        assert_eq!(rest, b"5:tail");
+        let Value::Dict(dict) = &value else {
+            panic!("expected a dictionary, got {:?}", value);
+        };
+        assert_eq!(dict[&b"num"[..]], Value::Integer(42));
+        assert_eq!(
+            dict[&b"list"[..]],
+            Value::Array(vec![Value::Integer(-3), Value::String(b"abc".to_vec())])
+        );
+        assert_eq!(dict[&b"nested"[..]], Value::Dict(BTreeMap::new()));
+        assert_eq!(value.encode(), &encoded[..encoded.len() - 6]);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        for encoded in [
+            &b""[..],
+            b"5:ab",
+            b"5",
+            b"i42",
+            b"l",
+            b"li1e",
+            b"d",
+            b"d3:key",
+            b"d3:keyi1e",
+            b"d3:ke",
+        ] {
+            assert!(
+                Value::decode(encoded).is_err(),
+                "{}",
+                String::from_utf8_lossy(encoded)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for encoded in [
+            &b"x"[..],
+            b"ie",
+            b"i4x2e",
+            b"-1:a",
+            b"99999999999999999999999:a",
+            b"di1ei2ee",
+            b"l:e",
+        ] {
+            assert!(
+                Value::decode(encoded).is_err(),
+                "{}",
+                String::from_utf8_lossy(encoded)
+            );
+        }
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| {
+            let mut encoded = vec![b'l'; depth];
+            encoded.extend(vec![b'e'; depth]);
+            encoded
+        };
+        assert!(Value::decode(&nested(MAX_DEPTH + 1)).is_ok());
+        let err = Value::decode(&nested(MAX_DEPTH + 2)).unwrap_err();
+        assert!(err.to_string().contains("nested"), "{}", err);
+        // deep enough to overflow the stack without the limit
+        assert!(Value::decode(&nested(1_000_000)).is_err());
+    }
+
+    #[test]
+    fn finds_raw_dict_values() {
+        let encoded = b"d1:ai1e4:infod6:lengthi3ee1:zlee";
+        assert_eq!(
+            raw_dict_value(encoded, b"info").unwrap(),
+            Some(&b"d6:lengthi3ee"[..])
+        );
+        assert_eq!(raw_dict_value(encoded, b"none").unwrap(), None);
+        assert!(raw_dict_value(b"d4:info", b"info").is_err());
+        assert!(raw_dict_value(b"d1:ai1e", b"info").is_err());
+        assert!(raw_dict_value(b"li1ee", b"info").is_err());
     }
 }
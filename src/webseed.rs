@@ -0,0 +1,378 @@
+use std::time::Duration;
+
+use anyhow::{Context, Error};
+use bytes::{Bytes, BytesMut};
+use reqwest::{header, redirect, Client, StatusCode};
+
+use crate::magnet::percent_encode;
+use crate::torrent::Info;
+
+/// redirects followed per request at most
+pub const MAX_REDIRECTS: usize = 5;
+/// time a single range request may take
+pub const WEB_SEED_TIMEOUT: Duration = Duration::from_secs(30);
+/// wait after the first failed request, doubled after each further one
+pub const WEB_SEED_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_WEB_SEED_BACKOFF: Duration = Duration::from_secs(300);
+/// failed requests in a row after which a web seed is given up
+pub const MAX_WEB_SEED_FAILURES: u32 = 5;
+
+/// a file of the torrent on the web seed
+#[derive(Debug, Clone)]
+struct WebSeedFile {
+    url: String,
+    length: u64,
+    /// offset of the first byte of the file in the torrent
+    offset: u64,
+    /// padding (BEP 47), zeros that aren't on the server
+    padding: bool,
+}
+
+/// An HTTP server holding the torrent's files (BEP 19).
+///
+/// The url of a single-file torrent is the file itself, unless it ends with
+/// `/` and the torrent name is appended. Multi-file torrents append the name
+/// and the file's path to the url, like the directory layout on disk.
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    pub url: String,
+    files: Vec<WebSeedFile>,
+    client: Client,
+}
+
+impl WebSeed {
+    pub fn new(url: &str, info: &Info) -> anyhow::Result<Self> {
+        let name = percent_encode(&info.name);
+        let files = match &info.files {
+            None => {
+                let url = if url.ends_with('/') {
+                    format!("{}{}", url, name)
+                } else {
+                    url.to_string()
+                };
+                vec![WebSeedFile {
+                    url,
+                    length: info.length,
+                    offset: 0,
+                    padding: false,
+                }]
+            }
+            Some(files) => {
+                let base = url.trim_end_matches('/');
+                // v2-only files start at piece boundaries, as in Storage
+                let aligned = info.has_v2() && !info.has_v1();
+                let piece_length = info.piece_length as u64;
+                let mut offset = 0u64;
+                let mut seed_files = Vec::new();
+                for file in files {
+                    if aligned {
+                        offset = offset.div_ceil(piece_length) * piece_length;
+                    }
+                    let path: Vec<String> = file.path.iter().map(|p| percent_encode(p)).collect();
+                    seed_files.push(WebSeedFile {
+                        url: format!("{}/{}/{}", base, name, path.join("/")),
+                        length: file.length,
+                        offset,
+                        padding: file.is_padding(),
+                    });
+                    offset += file.length;
+                }
+                seed_files
+            }
+        };
+
+        let client = Client::builder()
+            .redirect(redirect::Policy::limited(MAX_REDIRECTS))
+            .timeout(WEB_SEED_TIMEOUT)
+            .build()
+            .context("build http client")?;
+        Ok(Self {
+            url: url.to_string(),
+            files,
+            client,
+        })
+    }
+
+    /// Fetch `length` torrent bytes starting at `offset`, with a range request
+    /// for each file they span.
+    pub async fn fetch(&self, offset: u64, length: u64) -> anyhow::Result<Bytes> {
+        let end = offset + length;
+        let mut data = BytesMut::with_capacity(length as usize);
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            // empty files have no bytes to fetch
+            if file.length == 0 || file_end <= offset || file.offset >= end {
+                continue;
+            }
+            let start = offset.max(file.offset) - file.offset;
+            let stop = end.min(file_end) - file.offset;
+            if file.padding {
+                data.resize(data.len() + (stop - start) as usize, 0);
+                continue;
+            }
+            data.extend_from_slice(&self.fetch_range(file, start, stop).await?);
+        }
+        if data.len() as u64 != length {
+            return Err(Error::msg(format!(
+                "range {}..{} is past the end of the torrent",
+                offset, end
+            )));
+        }
+        Ok(data.freeze())
+    }
+
+    /// bytes `start..stop` of `file`
+    async fn fetch_range(
+        &self,
+        file: &WebSeedFile,
+        start: u64,
+        stop: u64,
+    ) -> anyhow::Result<Bytes> {
+        let response = self
+            .client
+            .get(&file.url)
+            .header(header::RANGE, format!("bytes={}-{}", start, stop - 1))
+            .send()
+            .await
+            .with_context(|| format!("request {}", file.url))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(Error::msg(format!("{} answered {}", file.url, status)));
+        }
+        let body = response
+            .bytes()
+            .await
+            .with_context(|| format!("read {}", file.url))?;
+
+        let len = (stop - start) as usize;
+        match status {
+            StatusCode::PARTIAL_CONTENT if body.len() == len => Ok(body),
+            // servers without range support send the whole file
+            StatusCode::OK if body.len() as u64 == file.length => {
+                Ok(body.slice(start as usize..stop as usize))
+            }
+            _ => Err(Error::msg(format!(
+                "{} answered {} with {} bytes for a range of {}",
+                file.url,
+                status,
+                body.len(),
+                len
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::torrent::{FileInfo, TreeFile};
+
+    /// A minimal HTTP server for `files` by path, answering range requests
+    /// unless `ranges` is false. Returns its url and the requests it got.
+    async fn serve(
+        files: HashMap<String, Vec<u8>>,
+        ranges: bool,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut reader = BufReader::new(&mut stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).await.unwrap();
+                let path = request_line.split(' ').nth(1).unwrap().to_string();
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).await.unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        range = Some((
+                            start.parse::<usize>().unwrap(),
+                            end.parse::<usize>().unwrap(),
+                        ));
+                    }
+                }
+                log.lock().unwrap().push(format!("{} {:?}", path, range));
+
+                let response = match (files.get(&path), range) {
+                    (Some(data), Some((start, end))) if ranges => {
+                        let mut head = format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            end + 1 - start
+                        )
+                        .into_bytes();
+                        head.extend_from_slice(&data[start..=end]);
+                        head
+                    }
+                    (Some(data), _) => {
+                        let mut head = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            data.len()
+                        )
+                        .into_bytes();
+                        head.extend_from_slice(data);
+                        head
+                    }
+                    (None, _) => {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec()
+                    }
+                };
+                stream.write_all(&response).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn content(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    fn file(path: &[&str], length: u64, attr: Option<&str>) -> FileInfo {
+        FileInfo {
+            length,
+            path: path.iter().map(|p| p.to_string()).collect(),
+            attr: attr.map(str::to_string),
+            symlink_path: None,
+        }
+    }
+
+    /// `my files/a.txt` of 1000 bytes padded to 1024, then `my files/sub/b c.bin`
+    fn multi_file_info() -> Info {
+        let files = vec![
+            file(&["a.txt"], 1000, None),
+            file(&[".pad", "24"], 24, Some("p")),
+            file(&["sub", "b c.bin"], 3000, None),
+        ];
+        Info {
+            length: 4024,
+            name: "my files".to_string(),
+            piece_length: 1024,
+            pieces: vec![[0; 20]; 4],
+            files: Some(files),
+            private: false,
+            source: None,
+            meta_version: None,
+            file_tree: Vec::new(),
+            raw: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_ranges_across_files() {
+        let a = content(1000, 1);
+        let b = content(3000, 2);
+        let (url, requests) = serve(
+            HashMap::from([
+                ("/base/my%20files/a.txt".to_string(), a.clone()),
+                ("/base/my%20files/sub/b%20c.bin".to_string(), b.clone()),
+            ]),
+            true,
+        )
+        .await;
+        let seed = WebSeed::new(&format!("{}/base/", url), &multi_file_info()).unwrap();
+
+        let data = seed.fetch(990, 100).await.unwrap();
+        let mut expected = a[990..].to_vec();
+        expected.extend([0; 24]);
+        expected.extend_from_slice(&b[..66]);
+        assert_eq!(data, expected);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                "/base/my%20files/a.txt Some((990, 999))",
+                "/base/my%20files/sub/b%20c.bin Some((0, 65))"
+            ]
+        );
+
+        assert_eq!(seed.fetch(3024, 1000).await.unwrap(), b[2000..]);
+        assert!(seed.fetch(3024, 1001).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn slices_whole_files_from_servers_without_ranges() {
+        let data = content(5000, 3);
+        let (url, _) = serve(
+            HashMap::from([("/single.bin".to_string(), data.clone())]),
+            false,
+        )
+        .await;
+        let mut info = multi_file_info();
+        info.files = None;
+        info.length = 5000;
+        info.pieces = vec![[0; 20]; 5];
+        let seed = WebSeed::new(&format!("{}/single.bin", url), &info).unwrap();
+        assert_eq!(seed.fetch(1024, 1024).await.unwrap(), data[1024..2048]);
+    }
+
+    #[tokio::test]
+    async fn fails_on_missing_files() {
+        let (url, _) = serve(HashMap::new(), true).await;
+        let seed = WebSeed::new(&format!("{}/", url), &multi_file_info()).unwrap();
+        let err = seed.fetch(0, 100).await.unwrap_err();
+        assert!(err.to_string().contains("404"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn aligns_files_of_v2_torrents_to_pieces() {
+        let big = content(40_000, 4);
+        let small = content(900, 5);
+        let (url, requests) = serve(
+            HashMap::from([
+                ("/v2test/big.bin".to_string(), big.clone()),
+                ("/v2test/small.txt".to_string(), small.clone()),
+            ]),
+            true,
+        )
+        .await;
+        let tree_file = |path: &str, length: u64| TreeFile {
+            length,
+            path: vec![path.to_string()],
+            pieces_root: Some([0; 32]),
+        };
+        let info = Info {
+            length: 40_900,
+            name: "v2test".to_string(),
+            piece_length: 32768,
+            pieces: Vec::new(),
+            files: Some(vec![
+                file(&["big.bin"], 40_000, None),
+                file(&["small.txt"], 900, None),
+            ]),
+            private: false,
+            source: None,
+            meta_version: Some(2),
+            file_tree: vec![tree_file("big.bin", 40_000), tree_file("small.txt", 900)],
+            raw: None,
+        };
+        let seed = WebSeed::new(&url, &info).unwrap();
+
+        // big.bin ends in the middle of the second piece, small.txt starts the third
+        assert_eq!(
+            seed.fetch(32768, 40_000 - 32768).await.unwrap(),
+            big[32768..]
+        );
+        assert_eq!(seed.fetch(65536, 900).await.unwrap(), small);
+        assert_eq!(
+            requests.lock().unwrap()[1],
+            "/v2test/small.txt Some((0, 899))"
+        );
+        assert!(seed.fetch(40_000, 100).await.is_err());
+    }
+}